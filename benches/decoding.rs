@@ -0,0 +1,230 @@
+//! Throughput of `PotlockIndexer::on_transaction` and `on_receipt`. Blocks are
+//! served from memory, so this measures decoding together with inindexer's
+//! transaction tracking, without any network or disk access.
+//!
+//! Run with `cargo bench --features testing`. Throughput is reported in events,
+//! or in blocks for scenarios without Potlock events. Allocations of one run of
+//! each scenario are printed before it's measured.
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::ops::Range;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use async_trait::async_trait;
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use inindexer::message_provider::MessageProvider;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use inindexer::{run_indexer, BlockRange, IndexerOptions, PreprocessTransactionsSettings};
+use potlock_indexer::file_provider::FileProvider;
+use potlock_indexer::synthetic::{self, BlockBuilder, CallResult, MemoryProvider};
+use potlock_indexer::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEventHandler,
+    PotlockIndexer,
+};
+use tokio::runtime::Runtime;
+
+const FIXTURES_DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/blocks");
+const BLOCKS: u64 = 100;
+const CALLS_PER_BLOCK: u64 = 50;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+/// Counts events without keeping them, so that the handler doesn't dominate
+#[derive(Default)]
+struct CountingHandler {
+    events: u64,
+}
+
+#[async_trait]
+impl PotlockEventHandler for CountingHandler {
+    async fn handle_donation(&mut self, _event: DonationEvent, _context: EventContext) {
+        self.events += 1;
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        _event: PotProjectDonationEvent,
+        _context: EventContext,
+    ) {
+        self.events += 1;
+    }
+
+    async fn handle_pot_donation(&mut self, _event: PotDonationEvent, _context: EventContext) {
+        self.events += 1;
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+/// Returns the number of events
+async fn index(provider: MemoryProvider, range: Range<BlockHeight>) -> u64 {
+    let mut indexer = PotlockIndexer::new(CountingHandler::default());
+    run_indexer(
+        &mut indexer,
+        provider,
+        IndexerOptions {
+            preprocess_transactions: Some(PreprocessTransactionsSettings {
+                prefetch_blocks: 0,
+                postfetch_blocks: 0,
+            }),
+            ctrl_c_handler: false,
+            ..IndexerOptions::default_with_range(BlockRange::Range {
+                start_inclusive: range.start,
+                end_exclusive: Some(range.end),
+            })
+        },
+    )
+    .await
+    .unwrap();
+    indexer.0.events
+}
+
+fn synthetic_blocks(call: impl Fn(BlockBuilder, u64) -> BlockBuilder) -> MemoryProvider {
+    MemoryProvider::new((1..=BLOCKS).map(|height| {
+        (0..CALLS_PER_BLOCK)
+            .fold(BlockBuilder::new(height), |block, i| {
+                call(block, height * CALLS_PER_BLOCK + i)
+            })
+            .build()
+    }))
+}
+
+/// Blocks in tests/fixtures/blocks, if any were recorded
+fn recorded_blocks(runtime: &Runtime) -> Option<MemoryProvider> {
+    let provider = FileProvider::new(FIXTURES_DIRECTORY);
+    let mut heights = std::fs::read_dir(FIXTURES_DIRECTORY)
+        .ok()?
+        .filter_map(|entry| {
+            let name = entry.ok()?.file_name().into_string().ok()?;
+            name.split('.').next()?.parse::<BlockHeight>().ok()
+        })
+        .collect::<Vec<_>>();
+    heights.sort_unstable();
+    heights.dedup();
+    // Skipped heights only have a marker
+    let blocks = heights
+        .into_iter()
+        .filter_map(|height| runtime.block_on(provider.get_message(height)).unwrap())
+        .collect::<Vec<_>>();
+    (!blocks.is_empty()).then(|| MemoryProvider::new(blocks))
+}
+
+fn scenarios(runtime: &Runtime) -> Vec<(&'static str, MemoryProvider)> {
+    let mut scenarios = vec![
+        (
+            "donations",
+            synthetic_blocks(|block, id| {
+                let donor_id = format!("donor{id}.near");
+                block.donation(
+                    &donor_id,
+                    &[synthetic::donation(id, &donor_id, "project.near", 1_000)],
+                )
+            }),
+        ),
+        (
+            "donation_batches",
+            synthetic_blocks(|block, id| {
+                if id % CALLS_PER_BLOCK != 0 {
+                    return block;
+                }
+                let donations = (id..id + CALLS_PER_BLOCK)
+                    .map(|id| synthetic::donation(id, "donor.near", "project.near", 1_000))
+                    .collect::<Vec<_>>();
+                block.donation("donor.near", &donations)
+            }),
+        ),
+        (
+            "pot_donations",
+            synthetic_blocks(|block, id| {
+                let donor_id = format!("donor{id}.near");
+                let project_id = (id % 2 == 0).then_some("project.near");
+                block.pot_donation(
+                    &donor_id,
+                    "round",
+                    CallResult::ReceiptChain {
+                        depth: 2,
+                        value: serde_json::to_vec(&synthetic::pot_donation(
+                            id, &donor_id, project_id, 1_000,
+                        ))
+                        .unwrap(),
+                    },
+                )
+            }),
+        ),
+        (
+            "unrelated_calls",
+            synthetic_blocks(|block, id| {
+                block.function_call(
+                    &format!("user{id}.near"),
+                    "app.near",
+                    "ft_transfer",
+                    vec![format!("Transfer {id}")],
+                    CallResult::Value(Vec::new()),
+                )
+            }),
+        ),
+    ];
+    match recorded_blocks(runtime) {
+        Some(blocks) => scenarios.push(("recorded", blocks)),
+        None => eprintln!("No blocks recorded in {FIXTURES_DIRECTORY}, skipping `recorded`"),
+    }
+    scenarios
+}
+
+fn decoding(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let mut group = c.benchmark_group("decoding");
+    for (name, provider) in scenarios(&runtime) {
+        let heights = provider.heights().collect::<Vec<_>>();
+        let range = heights[0]..heights[heights.len() - 1] + 1;
+
+        let allocations_before = ALLOCATIONS.load(Ordering::Relaxed);
+        let events = runtime.block_on(index(provider.clone(), range.clone()));
+        let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations_before;
+        eprintln!(
+            "{name}: {} blocks, {events} events, {allocations} allocations ({} per block)",
+            heights.len(),
+            allocations / heights.len() as u64,
+        );
+
+        group.throughput(Throughput::Elements(if events > 0 {
+            events
+        } else {
+            heights.len() as u64
+        }));
+        group.bench_function(name, |b| {
+            b.to_async(&runtime)
+                .iter(|| index(provider.clone(), range.clone()))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, decoding);
+criterion_main!(benches);
@@ -0,0 +1,466 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_utils::{dec_format, FtBalance};
+use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEventHandler,
+    NEAR_FT_ID,
+};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Amount(#[serde(with = "dec_format")] pub FtBalance);
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Totals {
+    /// Total amount donated, by FT id (e.g. "near")
+    pub amounts: BTreeMap<AccountId, Amount>,
+    pub donation_count: u64,
+    /// Always 0 for donors
+    pub unique_donors: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AggregateKind {
+    Project,
+    Pot,
+    Donor,
+    /// Totals per FT id
+    Token,
+}
+
+impl AggregateKind {
+    fn name(self) -> &'static str {
+        match self {
+            AggregateKind::Project => "project",
+            AggregateKind::Pot => "pot",
+            AggregateKind::Donor => "donor",
+            AggregateKind::Token => "token",
+        }
+    }
+}
+
+/// Donors of every aggregate that counts unique donors, grouped by aggregate
+pub type UniqueDonors = HashMap<(AggregateKind, AccountId), HashSet<AccountId>>;
+
+/// Totals of all donations processed so far. Unique donors are kept by the
+/// [`AggregateSink`], so this only grows with the number of projects, pots, donors,
+/// and tokens.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Aggregates {
+    projects: HashMap<AccountId, Totals>,
+    pots: HashMap<AccountId, Totals>,
+    donors: HashMap<AccountId, Totals>,
+    tokens: HashMap<AccountId, Totals>,
+}
+
+impl Aggregates {
+    pub fn get(&self, kind: AggregateKind, id: &AccountId) -> Option<&Totals> {
+        self.map(kind).get(id)
+    }
+
+    fn map(&self, kind: AggregateKind) -> &HashMap<AccountId, Totals> {
+        match kind {
+            AggregateKind::Project => &self.projects,
+            AggregateKind::Pot => &self.pots,
+            AggregateKind::Donor => &self.donors,
+            AggregateKind::Token => &self.tokens,
+        }
+    }
+
+    fn map_mut(&mut self, kind: AggregateKind) -> &mut HashMap<AccountId, Totals> {
+        match kind {
+            AggregateKind::Project => &mut self.projects,
+            AggregateKind::Pot => &mut self.pots,
+            AggregateKind::Donor => &mut self.donors,
+            AggregateKind::Token => &mut self.tokens,
+        }
+    }
+}
+
+/// Emitted at the end of a block for every project, pot, donor, and token whose
+/// totals have changed in this block
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct AggregateUpdatedEvent {
+    pub kind: AggregateKind,
+    pub id: AccountId,
+    /// Totals after this block
+    pub totals: Totals,
+    pub block_height: BlockHeight,
+}
+
+/// Where [`AggregatingHandler`] publishes updates and persists snapshots
+#[async_trait]
+pub trait AggregateSink: Send + Sync {
+    async fn publish(&mut self, updates: Vec<AggregateUpdatedEvent>) -> Result<(), anyhow::Error>;
+
+    /// Adds donors to the unique donors of their aggregates and returns how many
+    /// unique donors each of these aggregates has now. Adding a donor again has no
+    /// effect, so blocks counted again after a restart don't change the result.
+    async fn add_unique_donors(
+        &mut self,
+        donors: &UniqueDonors,
+    ) -> Result<HashMap<(AggregateKind, AccountId), u64>, anyhow::Error>;
+
+    async fn save_snapshot(
+        &mut self,
+        block_height: BlockHeight,
+        aggregates: &Aggregates,
+    ) -> Result<(), anyhow::Error>;
+}
+
+/// Maintains totals per project, pot, donor, and FT id incrementally.
+///
+/// Can be resumed from a snapshot, in which case events from blocks up to and
+/// including the snapshot's block are ignored, so restarting from an earlier
+/// block doesn't count donations twice. The indexer must not start after the
+/// snapshot's block though, or donations in between are never counted: see
+/// [`AggregatingHandler::snapshot_block`]. Call [`AggregatingHandler::save_snapshot`]
+/// before exiting to avoid indexing blocks since the last snapshot again.
+///
+/// Updates that failed to publish are kept and published with the next block.
+/// Until then, no snapshot is saved, so they're published again after a restart.
+pub struct AggregatingHandler<S: AggregateSink> {
+    aggregates: Aggregates,
+    changed: HashSet<(AggregateKind, AccountId)>,
+    /// Not added to the sink yet
+    new_donors: UniqueDonors,
+    sink: S,
+    snapshot_interval: BlockHeight,
+    last_snapshot_block: Option<BlockHeight>,
+    /// All events up to this block are counted
+    last_complete_block: Option<BlockHeight>,
+    /// Events of a block after `last_complete_block` are counted
+    counting_block: bool,
+    has_unsaved_changes: bool,
+}
+
+impl<S: AggregateSink> AggregatingHandler<S> {
+    /// `snapshot_interval` is the minimum number of blocks between snapshots
+    pub fn new(
+        sink: S,
+        snapshot: Option<(BlockHeight, Aggregates)>,
+        snapshot_interval: BlockHeight,
+    ) -> Self {
+        let (last_snapshot_block, aggregates) = match snapshot {
+            Some((block_height, aggregates)) => (Some(block_height), aggregates),
+            None => (None, Aggregates::default()),
+        };
+        Self {
+            aggregates,
+            changed: HashSet::new(),
+            new_donors: HashMap::new(),
+            sink,
+            snapshot_interval,
+            last_snapshot_block,
+            last_complete_block: None,
+            counting_block: false,
+            has_unsaved_changes: false,
+        }
+    }
+
+    pub fn aggregates(&self) -> &Aggregates {
+        &self.aggregates
+    }
+
+    /// Block of the last saved snapshot. Indexing has to continue right after it.
+    pub fn snapshot_block(&self) -> Option<BlockHeight> {
+        self.last_snapshot_block
+    }
+
+    /// Saves a snapshot at the last flushed block if anything changed since the last
+    /// one, regardless of the snapshot interval. Does nothing while a block is only
+    /// partially counted, since it would be counted again after a restart, or while
+    /// updates are unpublished, since they would never be published.
+    pub async fn save_snapshot(&mut self) -> Result<(), anyhow::Error> {
+        if self.counting_block {
+            log::warn!("Not saving aggregates snapshot in the middle of a block");
+            return Ok(());
+        }
+        if !self.changed.is_empty() {
+            log::warn!("Not saving aggregates snapshot with unpublished updates");
+            return Ok(());
+        }
+        if let Some(block_height) = self
+            .last_complete_block
+            .filter(|_| self.has_unsaved_changes)
+        {
+            self.sink
+                .save_snapshot(block_height, &self.aggregates)
+                .await?;
+            self.last_snapshot_block = Some(block_height);
+            self.has_unsaved_changes = false;
+        }
+        Ok(())
+    }
+
+    fn add(
+        &mut self,
+        kind: AggregateKind,
+        id: &AccountId,
+        ft_id: &AccountId,
+        amount: FtBalance,
+        donor_id: Option<&AccountId>,
+    ) {
+        let totals = self.aggregates.map_mut(kind).entry(id.clone()).or_default();
+        let total = totals.amounts.entry(ft_id.clone()).or_default();
+        total.0 = total.0.saturating_add(amount);
+        totals.donation_count += 1;
+        if let Some(donor_id) = donor_id {
+            self.new_donors
+                .entry((kind, id.clone()))
+                .or_default()
+                .insert(donor_id.clone());
+        }
+        self.changed.insert((kind, id.clone()));
+        self.counting_block = true;
+        self.has_unsaved_changes = true;
+    }
+
+    fn is_already_counted(&self, context: &EventContext) -> bool {
+        self.last_snapshot_block
+            .is_some_and(|snapshot_block| context.block_height <= snapshot_block)
+    }
+}
+
+#[async_trait]
+impl<S: AggregateSink> PotlockEventHandler for AggregatingHandler<S> {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        if self.is_already_counted(&context) {
+            return;
+        }
+        let amount = event.total_amount;
+        let ft_id = &event.ft_id;
+        let donor_id = &event.donor_id;
+        self.add(
+            AggregateKind::Project,
+            &event.project_id,
+            ft_id,
+            amount,
+            Some(donor_id),
+        );
+        self.add(AggregateKind::Donor, donor_id, ft_id, amount, None);
+        self.add(AggregateKind::Token, ft_id, ft_id, amount, Some(donor_id));
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        if self.is_already_counted(&context) {
+            return;
+        }
+        let amount = event.total_amount;
+        let ft_id = &*NEAR_FT_ID;
+        let donor_id = &event.donor_id;
+        self.add(
+            AggregateKind::Project,
+            &event.project_id,
+            ft_id,
+            amount,
+            Some(donor_id),
+        );
+        self.add(
+            AggregateKind::Pot,
+            &event.pot_id,
+            ft_id,
+            amount,
+            Some(donor_id),
+        );
+        self.add(AggregateKind::Donor, donor_id, ft_id, amount, None);
+        self.add(AggregateKind::Token, ft_id, ft_id, amount, Some(donor_id));
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        if self.is_already_counted(&context) {
+            return;
+        }
+        let amount = event.total_amount;
+        let ft_id = &*NEAR_FT_ID;
+        let donor_id = &event.donor_id;
+        self.add(
+            AggregateKind::Pot,
+            &event.pot_id,
+            ft_id,
+            amount,
+            Some(donor_id),
+        );
+        self.add(AggregateKind::Donor, donor_id, ft_id, amount, None);
+        self.add(AggregateKind::Token, ft_id, ft_id, amount, Some(donor_id));
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        self.last_complete_block = Some(block_height);
+        self.counting_block = false;
+        if !self.new_donors.is_empty() {
+            // Kept on failure, to be added with the next block
+            let counts = self.sink.add_unique_donors(&self.new_donors).await?;
+            for ((kind, id), count) in counts {
+                if let Some(totals) = self.aggregates.map_mut(kind).get_mut(&id) {
+                    totals.unique_donors = count;
+                }
+            }
+            self.new_donors.clear();
+        }
+        if !self.changed.is_empty() {
+            let updates = self
+                .changed
+                .iter()
+                .map(|(kind, id)| AggregateUpdatedEvent {
+                    totals: self.aggregates.get(*kind, id).cloned().unwrap_or_default(),
+                    kind: *kind,
+                    id: id.clone(),
+                    block_height,
+                })
+                .collect();
+            // Kept on failure, to be published with the next block
+            self.sink.publish(updates).await?;
+            self.changed.clear();
+        }
+        let snapshot_due = self.last_snapshot_block.is_none_or(|last_snapshot_block| {
+            block_height >= last_snapshot_block + self.snapshot_interval
+        });
+        if snapshot_due {
+            self.save_snapshot().await?;
+        }
+        Ok(())
+    }
+}
+
+/// Publishes updates to `potlock_aggregate_updated` stream, keeps unique donors in
+/// `potlock_aggregate_donors:{kind}:{id}` sets, and keeps the last
+/// `snapshots_to_keep` snapshots in `potlock_aggregates_snapshot:{block_height}` keys,
+/// with their heights in `potlock_aggregates_snapshots` sorted set. All keys are
+/// prefixed with `prefix`.
+pub struct RedisAggregateSink {
+    connection: ConnectionManager,
+    prefix: String,
+    max_stream_size: usize,
+    snapshots_to_keep: usize,
+}
+
+impl RedisAggregateSink {
+    pub fn new(
+        connection: ConnectionManager,
+        prefix: String,
+        max_stream_size: usize,
+        snapshots_to_keep: usize,
+    ) -> Self {
+        Self {
+            connection,
+            prefix,
+            max_stream_size,
+            snapshots_to_keep,
+        }
+    }
+
+    fn updates_stream(&self) -> String {
+        format!("{}potlock_aggregate_updated", self.prefix)
+    }
+
+    fn snapshots_key(&self) -> String {
+        format!("{}potlock_aggregates_snapshots", self.prefix)
+    }
+
+    fn donors_key(&self, kind: AggregateKind, id: &AccountId) -> String {
+        format!(
+            "{}potlock_aggregate_donors:{}:{id}",
+            self.prefix,
+            kind.name()
+        )
+    }
+
+    fn snapshot_key(&self, block_height: BlockHeight) -> String {
+        format!("{}potlock_aggregates_snapshot:{block_height}", self.prefix)
+    }
+
+    /// Loads the most recent snapshot, to be passed to [`AggregatingHandler::new`]
+    pub async fn load_latest_snapshot(
+        &mut self,
+    ) -> Result<Option<(BlockHeight, Aggregates)>, anyhow::Error> {
+        let latest: Vec<BlockHeight> = self
+            .connection
+            .zrevrange(self.snapshots_key(), 0, 0)
+            .await?;
+        let Some(block_height) = latest.first().copied() else {
+            return Ok(None);
+        };
+        let json: String = self.connection.get(self.snapshot_key(block_height)).await?;
+        Ok(Some((block_height, serde_json::from_str(&json)?)))
+    }
+}
+
+#[async_trait]
+impl AggregateSink for RedisAggregateSink {
+    async fn publish(&mut self, updates: Vec<AggregateUpdatedEvent>) -> Result<(), anyhow::Error> {
+        let updates_stream = self.updates_stream();
+        let mut pipe = redis::pipe();
+        for update in updates {
+            pipe.xadd_maxlen(
+                &updates_stream,
+                redis::streams::StreamMaxlen::Approx(self.max_stream_size),
+                "*",
+                &[("event", serde_json::to_string(&update)?)],
+            )
+            .ignore();
+        }
+        pipe.query_async::<_, ()>(&mut self.connection).await?;
+        Ok(())
+    }
+
+    async fn add_unique_donors(
+        &mut self,
+        donors: &UniqueDonors,
+    ) -> Result<HashMap<(AggregateKind, AccountId), u64>, anyhow::Error> {
+        let mut pipe = redis::pipe();
+        for ((kind, id), donors) in donors {
+            let key = self.donors_key(*kind, id);
+            pipe.sadd(
+                &key,
+                donors.iter().map(AccountId::as_str).collect::<Vec<_>>(),
+            )
+            .ignore()
+            .scard(&key);
+        }
+        let counts: Vec<u64> = pipe.query_async(&mut self.connection).await?;
+        Ok(donors.keys().cloned().zip(counts).collect())
+    }
+
+    async fn save_snapshot(
+        &mut self,
+        block_height: BlockHeight,
+        aggregates: &Aggregates,
+    ) -> Result<(), anyhow::Error> {
+        let json = serde_json::to_string(aggregates)?;
+        let _: () = redis::pipe()
+            .atomic()
+            .set(self.snapshot_key(block_height), json)
+            .ignore()
+            .zadd(self.snapshots_key(), block_height, block_height)
+            .ignore()
+            .query_async(&mut self.connection)
+            .await?;
+        let outdated: Vec<BlockHeight> = self
+            .connection
+            .zrevrange(self.snapshots_key(), self.snapshots_to_keep as isize, -1)
+            .await?;
+        if !outdated.is_empty() {
+            let mut pipe = redis::pipe();
+            for outdated_block_height in outdated {
+                pipe.del(self.snapshot_key(outdated_block_height))
+                    .ignore()
+                    .zrem(self.snapshots_key(), outdated_block_height)
+                    .ignore();
+            }
+            pipe.query_async::<_, ()>(&mut self.connection).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+use std::sync::Arc;
+
+use axum::extract::{Path, Query, State};
+use axum::http::StatusCode;
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use inindexer::near_indexer_primitives::types::AccountId;
+use serde::Deserialize;
+
+use crate::aggregating_handler::Totals;
+use crate::storage::{Cursor, DonationFilter, DonationStore, Order, Page, TopDonor};
+use crate::NEAR_FT_ID;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+
+type Store = Arc<dyn DonationStore>;
+
+/// Read-only HTTP API over a [`DonationStore`]:
+///
+/// - `GET /donations` with optional `project_id`, `donor_id`, `pot_id`, `event_type`,
+///   `from_block` filters
+/// - `GET /projects/{project_id}/donations`, `GET /donors/{donor_id}/donations`,
+///   `GET /pots/{pot_id}/donations`
+/// - `GET /projects/{project_id}/totals`, `GET /pots/{pot_id}/totals` (totals per round)
+/// - `GET /top-donors` with `ft_id` (default `near`), `limit`, and the same filters as
+///   `/donations`
+///
+/// Donation lists are paginated with `limit` (default 20, max 100), `order`
+/// (`descending` by default, or `ascending`), and `after`, which takes `next_cursor`
+/// from the previous page.
+pub fn router(store: Store) -> Router {
+    Router::new()
+        .route("/donations", get(donations))
+        .route("/projects/:project_id/donations", get(project_donations))
+        .route("/donors/:donor_id/donations", get(donor_donations))
+        .route("/pots/:pot_id/donations", get(pot_donations))
+        .route("/projects/:project_id/totals", get(project_totals))
+        .route("/pots/:pot_id/totals", get(pot_totals))
+        .route("/top-donors", get(top_donors))
+        .with_state(store)
+}
+
+#[derive(Deserialize)]
+struct PageParams {
+    #[serde(default)]
+    order: Order,
+    after: Option<Cursor>,
+    limit: Option<usize>,
+}
+
+#[derive(Deserialize)]
+struct TopDonorsParams {
+    ft_id: Option<AccountId>,
+    limit: Option<usize>,
+}
+
+struct ApiError(anyhow::Error);
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        Self(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        log::error!("API request failed: {:#}", self.0);
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            Json(serde_json::json!({ "error": format!("{:#}", self.0) })),
+        )
+            .into_response()
+    }
+}
+
+fn limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+}
+
+async fn page(
+    store: &Store,
+    filter: DonationFilter,
+    params: PageParams,
+) -> Result<Json<Page>, ApiError> {
+    Ok(Json(
+        store
+            .donations(&filter, params.order, params.after, limit(params.limit))
+            .await?,
+    ))
+}
+
+async fn donations(
+    State(store): State<Store>,
+    Query(filter): Query<DonationFilter>,
+    Query(params): Query<PageParams>,
+) -> Result<Json<Page>, ApiError> {
+    page(&store, filter, params).await
+}
+
+async fn project_donations(
+    State(store): State<Store>,
+    Path(project_id): Path<AccountId>,
+    Query(params): Query<PageParams>,
+) -> Result<Json<Page>, ApiError> {
+    let filter = DonationFilter {
+        project_id: Some(project_id),
+        ..Default::default()
+    };
+    page(&store, filter, params).await
+}
+
+async fn donor_donations(
+    State(store): State<Store>,
+    Path(donor_id): Path<AccountId>,
+    Query(params): Query<PageParams>,
+) -> Result<Json<Page>, ApiError> {
+    let filter = DonationFilter {
+        donor_id: Some(donor_id),
+        ..Default::default()
+    };
+    page(&store, filter, params).await
+}
+
+async fn pot_donations(
+    State(store): State<Store>,
+    Path(pot_id): Path<AccountId>,
+    Query(params): Query<PageParams>,
+) -> Result<Json<Page>, ApiError> {
+    let filter = DonationFilter {
+        pot_id: Some(pot_id),
+        ..Default::default()
+    };
+    page(&store, filter, params).await
+}
+
+async fn project_totals(
+    State(store): State<Store>,
+    Path(project_id): Path<AccountId>,
+) -> Result<Json<Totals>, ApiError> {
+    let filter = DonationFilter {
+        project_id: Some(project_id),
+        ..Default::default()
+    };
+    Ok(Json(store.totals(&filter).await?))
+}
+
+async fn pot_totals(
+    State(store): State<Store>,
+    Path(pot_id): Path<AccountId>,
+) -> Result<Json<Totals>, ApiError> {
+    let filter = DonationFilter {
+        pot_id: Some(pot_id),
+        ..Default::default()
+    };
+    Ok(Json(store.totals(&filter).await?))
+}
+
+async fn top_donors(
+    State(store): State<Store>,
+    Query(filter): Query<DonationFilter>,
+    Query(params): Query<TopDonorsParams>,
+) -> Result<Json<Vec<TopDonor>>, ApiError> {
+    let ft_id = params.ft_id.unwrap_or_else(|| NEAR_FT_ID.clone());
+    Ok(Json(
+        store
+            .top_donors(&filter, &ft_id, limit(params.limit))
+            .await?,
+    ))
+}
@@ -0,0 +1,230 @@
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    metrics, DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEvent,
+    PotlockEventHandler,
+};
+
+pub struct BackfillOptions {
+    /// End is exclusive
+    pub range: Range<BlockHeight>,
+    pub chunk_size: BlockHeight,
+    /// Number of chunks indexed at the same time
+    pub concurrency: usize,
+    /// Holds indexed chunks that are not merged yet, and the merge progress
+    pub checkpoint_directory: PathBuf,
+}
+
+/// Collects the events of one chunk in memory. Events from blocks outside of the
+/// chunk (transactions completed in prefetched or postfetched blocks) are dropped,
+/// since the neighboring chunk reports them too.
+pub struct BufferingHandler {
+    range: Range<BlockHeight>,
+    blocks: BTreeMap<BlockHeight, Vec<(PotlockEvent, EventContext)>>,
+}
+
+impl BufferingHandler {
+    pub fn new(range: Range<BlockHeight>) -> Self {
+        Self {
+            range,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    /// Events ordered by block height
+    pub fn into_events(self) -> Vec<(PotlockEvent, EventContext)> {
+        self.blocks.into_values().flatten().collect()
+    }
+
+    fn push(&mut self, event: PotlockEvent, context: EventContext) {
+        if self.range.contains(&context.block_height) {
+            self.blocks
+                .entry(context.block_height)
+                .or_default()
+                .push((event, context));
+        }
+    }
+}
+
+#[async_trait]
+impl PotlockEventHandler for BufferingHandler {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        self.push(PotlockEvent::Donation(event), context);
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        self.push(PotlockEvent::PotProjectDonation(event), context);
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        self.push(PotlockEvent::PotDonation(event), context);
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct Progress {
+    /// All blocks before this one were merged into the sink and flushed
+    merged_up_to: BlockHeight,
+}
+
+/// Splits `options.range` into chunks and indexes up to `options.concurrency` of them
+/// at a time with `index_chunk`, which should run a separate [`crate::PotlockIndexer`]
+/// [without metrics](crate::PotlockIndexer::without_metrics) with a
+/// [`BufferingHandler`] over the range it's given. Chunks are merged into `sink` in
+/// block order, flushing every block with events and the last block of each chunk.
+/// Metrics of merged events and flushed blocks are recorded here instead.
+///
+/// Each indexed chunk is saved to the checkpoint directory until it's merged, and
+/// merge progress is saved after every flushed block, so an interrupted backfill
+/// started again with the same range doesn't index saved chunks again, and doesn't
+/// send any block that was already flushed to `sink` again.
+pub async fn backfill<H, F, Fut>(
+    sink: &mut H,
+    options: BackfillOptions,
+    index_chunk: F,
+) -> Result<(), anyhow::Error>
+where
+    H: PotlockEventHandler,
+    F: Fn(Range<BlockHeight>) -> Fut,
+    Fut: Future<Output = Result<Vec<(PotlockEvent, EventContext)>, anyhow::Error>>,
+{
+    anyhow::ensure!(options.chunk_size > 0, "Chunk size must be greater than 0");
+    anyhow::ensure!(
+        options.concurrency > 0,
+        "Concurrency must be greater than 0"
+    );
+    let directory = &options.checkpoint_directory;
+    std::fs::create_dir_all(directory).with_context(|| {
+        format!(
+            "Failed to create checkpoint directory {}",
+            directory.display()
+        )
+    })?;
+    let progress_path = directory.join("progress.json");
+    let start = match std::fs::read(&progress_path) {
+        Ok(progress) => {
+            let progress: Progress = serde_json::from_slice(&progress)
+                .with_context(|| format!("Corrupted {}", progress_path.display()))?;
+            progress.merged_up_to.max(options.range.start)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => options.range.start,
+        Err(err) => return Err(err.into()),
+    };
+    if start > options.range.start {
+        log::info!("Resuming backfill from block {start}");
+    }
+
+    let end = options.range.end;
+    // Chunks keep the same boundaries when resuming from the middle of one, so that
+    // its saved file is found
+    let first_chunk_start = options.range.start
+        + (start - options.range.start) / options.chunk_size * options.chunk_size;
+    let chunks = (first_chunk_start..end)
+        .step_by(options.chunk_size as usize)
+        .map(|chunk_start| chunk_start..(chunk_start + options.chunk_size).min(end))
+        .filter(|chunk| chunk.end > start);
+    let index_chunk = &index_chunk;
+    let mut indexed = futures::stream::iter(chunks)
+        .map(|chunk| async move {
+            let path = chunk_path(directory, &chunk);
+            if let Some(events) = load_chunk(&path)? {
+                return Ok((chunk, events));
+            }
+            let events = index_chunk(chunk.clone())
+                .await
+                .with_context(|| format!("Failed to index blocks {chunk:?}"))?;
+            save_chunk(&path, &events)?;
+            Ok::<_, anyhow::Error>((chunk, events))
+        })
+        .buffered(options.concurrency);
+
+    while let Some((chunk, events)) = indexed.try_next().await? {
+        let mut current_block = None;
+        for (event, context) in events {
+            if context.block_height < start {
+                continue;
+            }
+            if let Some(block) = current_block.filter(|block| *block != context.block_height) {
+                flush(sink, block, &progress_path).await?;
+            }
+            current_block = Some(context.block_height);
+            metrics::record_event(
+                event.event_type(),
+                event.ft_id().as_str(),
+                event.total_amount(),
+            );
+            event.send_to(sink, context).await;
+        }
+        if let Some(block) = current_block.filter(|block| *block != chunk.end - 1) {
+            flush(sink, block, &progress_path).await?;
+        }
+        flush(sink, chunk.end - 1, &progress_path).await?;
+        std::fs::remove_file(chunk_path(directory, &chunk))?;
+        log::info!("Backfilled blocks {chunk:?}");
+    }
+
+    match std::fs::remove_file(progress_path) {
+        Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+        _ => Ok(()),
+    }
+}
+
+/// Flushes `block` and records that everything up to it was merged
+async fn flush<H: PotlockEventHandler>(
+    sink: &mut H,
+    block: BlockHeight,
+    progress_path: &Path,
+) -> Result<(), anyhow::Error> {
+    sink.flush_events(block).await?;
+    metrics::record_block_height(block);
+    write_atomically(
+        progress_path,
+        &serde_json::to_vec(&Progress {
+            merged_up_to: block + 1,
+        })?,
+    )
+}
+
+fn chunk_path(directory: &Path, chunk: &Range<BlockHeight>) -> PathBuf {
+    directory.join(format!("{}-{}.json", chunk.start, chunk.end))
+}
+
+fn load_chunk(path: &Path) -> Result<Option<Vec<(PotlockEvent, EventContext)>>, anyhow::Error> {
+    match std::fs::read(path) {
+        Ok(events) => Ok(Some(
+            serde_json::from_slice(&events)
+                .with_context(|| format!("Corrupted chunk {}", path.display()))?,
+        )),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn save_chunk(path: &Path, events: &[(PotlockEvent, EventContext)]) -> Result<(), anyhow::Error> {
+    write_atomically(path, &serde_json::to_vec(events)?)
+        .with_context(|| format!("Failed to save chunk {}", path.display()))
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), anyhow::Error> {
+    let temp_path = path.with_extension("tmp");
+    std::fs::write(&temp_path, contents)?;
+    std::fs::rename(temp_path, path)?;
+    Ok(())
+}
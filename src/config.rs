@@ -0,0 +1,393 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::Context;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use serde::Deserialize;
+
+use crate::filter::{EventFilter, EVENT_TYPES};
+use crate::redis_handler::{RedisStreamsConfig, Retention};
+use crate::stdout_handler::PrintFormat;
+use crate::PotlockContracts;
+
+/// Contents of the configuration file. Every field is optional, and the binary
+/// overrides them with command line flags and environment variables.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub network: Network,
+    pub provider: Provider,
+    pub contracts: ContractsConfig,
+    pub sinks: Vec<Sink>,
+    pub log_level: log::LevelFilter,
+    pub filter: EventFilter,
+    pub indexer: IndexerConfig,
+    pub redis: RedisConfig,
+    pub export: ExportConfig,
+    pub sqlite: SqliteConfig,
+    pub stdout: StdoutConfig,
+    pub server: ServerConfig,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            network: Network::Mainnet,
+            provider: Provider::Neardata,
+            contracts: ContractsConfig::default(),
+            sinks: vec![Sink::Redis],
+            log_level: log::LevelFilter::Info,
+            filter: EventFilter::default(),
+            indexer: IndexerConfig::default(),
+            redis: RedisConfig::default(),
+            export: ExportConfig::default(),
+            sqlite: SqliteConfig::default(),
+            stdout: StdoutConfig::default(),
+            server: ServerConfig::default(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    Mainnet,
+    Testnet,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Provider {
+    /// neardata.xyz
+    Neardata,
+    /// Local files written by the `download` command, see `indexer.blocks_directory`
+    Files,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Sink {
+    Redis,
+    Export,
+    Sqlite,
+    Aggregates,
+    /// Prints events, for debugging
+    Stdout,
+}
+
+/// Unset contracts default to the ones deployed on the selected network
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ContractsConfig {
+    pub donation: Option<AccountId>,
+    pub pot_factory: Option<AccountId>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IndexerConfig {
+    /// Where `run` starts if there's no checkpoint. Latest block if not set.
+    pub start_block: Option<BlockHeight>,
+    /// Exclusive, `run` continues indefinitely if not set
+    pub end_block: Option<BlockHeight>,
+    pub prefetch_blocks: usize,
+    pub postfetch_blocks: usize,
+    /// Required for the `files` provider
+    pub blocks_directory: Option<PathBuf>,
+    /// How long to wait for the current block to be flushed after SIGINT or SIGTERM,
+    /// before exiting with an error
+    pub shutdown_timeout_secs: u64,
+}
+
+impl Default for IndexerConfig {
+    fn default() -> Self {
+        Self {
+            start_block: None,
+            end_block: None,
+            prefetch_blocks: 20,
+            postfetch_blocks: 20,
+            blocks_directory: None,
+            shutdown_timeout_secs: 30,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RedisConfig {
+    /// Required for the `redis` and `aggregates` sinks
+    pub url: Option<String>,
+    pub prefix: String,
+    /// Approximate number of entries kept in each stream, unless a retention is set
+    pub max_stream_size: usize,
+    /// For all streams
+    pub retention: Option<Retention>,
+    pub retention_donation: Option<Retention>,
+    pub retention_pot_project_donation: Option<Retention>,
+    pub retention_pot_donation: Option<Retention>,
+    pub spill_path: Option<PathBuf>,
+    pub max_spilled_blocks: usize,
+    pub secondary_indexes: bool,
+    pub publish: bool,
+}
+
+impl Default for RedisConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            prefix: String::new(),
+            max_stream_size: 1_000,
+            retention: None,
+            retention_donation: None,
+            retention_pot_project_donation: None,
+            retention_pot_donation: None,
+            spill_path: None,
+            max_spilled_blocks: RedisStreamsConfig::default().max_spilled_blocks,
+            secondary_indexes: false,
+            publish: false,
+        }
+    }
+}
+
+impl RedisConfig {
+    pub fn streams_config(&self) -> RedisStreamsConfig {
+        let default_retention = self
+            .retention
+            .unwrap_or(Retention::MaxLen(self.max_stream_size));
+        RedisStreamsConfig {
+            prefix: self.prefix.clone(),
+            donation_retention: self.retention_donation.unwrap_or(default_retention),
+            pot_project_donation_retention: self
+                .retention_pot_project_donation
+                .unwrap_or(default_retention),
+            pot_donation_retention: self.retention_pot_donation.unwrap_or(default_retention),
+            spill_path: self.spill_path.clone(),
+            max_spilled_blocks: self.max_spilled_blocks,
+            secondary_indexes: self.secondary_indexes,
+            publish: self.publish,
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Parquet,
+    Csv,
+}
+
+impl FromStr for ExportFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "parquet" => Ok(ExportFormat::Parquet),
+            "csv" => Ok(ExportFormat::Csv),
+            _ => anyhow::bail!("Unknown export format {s:?}, expected `parquet` or `csv`"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for ExportFormat {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ExportConfig {
+    /// Required for the `export` sink
+    pub directory: Option<PathBuf>,
+    pub format: ExportFormat,
+    pub max_rows_per_file: usize,
+    /// Rows are written at least this often, bounding what a crash loses
+    pub max_buffered_blocks: BlockHeight,
+}
+
+impl Default for ExportConfig {
+    fn default() -> Self {
+        Self {
+            directory: None,
+            format: ExportFormat::Parquet,
+            max_rows_per_file: 100_000,
+            max_buffered_blocks: 600,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SqliteConfig {
+    /// Required for the `sqlite` sink
+    pub path: Option<PathBuf>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct StdoutConfig {
+    pub format: PrintFormat,
+}
+
+impl Default for StdoutConfig {
+    fn default() -> Self {
+        Self {
+            format: PrintFormat::Pretty,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Metrics, health checks, and with the `sqlite` sink, REST and GraphQL APIs
+    pub http_address: Option<SocketAddr>,
+    pub live_feed_address: Option<SocketAddr>,
+    /// Requires the `sqlite` sink
+    pub grpc_address: Option<SocketAddr>,
+    pub readiness_max_block_interval_secs: u64,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            http_address: None,
+            live_feed_address: None,
+            grpc_address: None,
+            readiness_max_block_interval_secs: 120,
+        }
+    }
+}
+
+impl Config {
+    /// TOML or YAML, depending on the extension
+    pub fn load(path: &Path) -> Result<Self, anyhow::Error> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("toml") => toml::from_str(&contents)
+                .with_context(|| format!("Invalid config file {}", path.display())),
+            Some("yaml" | "yml") => serde_yaml_ng::from_str(&contents)
+                .with_context(|| format!("Invalid config file {}", path.display())),
+            _ => anyhow::bail!(
+                "Unknown config file format {}, expected .toml, .yaml, or .yml",
+                path.display()
+            ),
+        }
+    }
+
+    pub fn contracts(&self) -> PotlockContracts {
+        let defaults = match self.network {
+            Network::Mainnet => PotlockContracts::mainnet(),
+            Network::Testnet => PotlockContracts::testnet(),
+        };
+        PotlockContracts {
+            donation: self.contracts.donation.clone().unwrap_or(defaults.donation),
+            pot_factory: self
+                .contracts
+                .pot_factory
+                .clone()
+                .unwrap_or(defaults.pot_factory),
+        }
+    }
+
+    /// Reports all problems at once
+    pub fn validate(&self) -> Result<(), anyhow::Error> {
+        let mut problems = Vec::new();
+        if self.sinks.is_empty() {
+            problems.push("sinks: at least one sink is required".to_string());
+        }
+        if (self.sinks.contains(&Sink::Redis) || self.sinks.contains(&Sink::Aggregates))
+            && self.redis.url.is_none()
+        {
+            problems.push(
+                "redis.url: required for the redis and aggregates sinks (--redis-url, $REDIS_URL)"
+                    .to_string(),
+            );
+        }
+        if self.sinks.contains(&Sink::Export) && self.export.directory.is_none() {
+            problems.push(
+                "export.directory: required for the export sink (--export-directory, $EXPORT_DIRECTORY)"
+                    .to_string(),
+            );
+        }
+        #[cfg(not(feature = "export"))]
+        if self.sinks.contains(&Sink::Export) {
+            problems.push("sinks: the export sink requires the `export` feature".to_string());
+        }
+        #[cfg(not(feature = "sqlite"))]
+        if self.sinks.contains(&Sink::Sqlite) {
+            problems.push("sinks: the sqlite sink requires the `sqlite` feature".to_string());
+        }
+        if self.sinks.contains(&Sink::Sqlite) && self.sqlite.path.is_none() {
+            problems.push(
+                "sqlite.path: required for the sqlite sink (--sqlite-path, $SQLITE_PATH)"
+                    .to_string(),
+            );
+        }
+        #[cfg(not(feature = "http"))]
+        if self.server.http_address.is_some() {
+            problems.push(
+                "server.http_address: the HTTP server requires the `http` feature".to_string(),
+            );
+        }
+        #[cfg(not(feature = "http"))]
+        if self.server.live_feed_address.is_some() {
+            problems.push(
+                "server.live_feed_address: the live feed server requires the `http` feature"
+                    .to_string(),
+            );
+        }
+        #[cfg(not(feature = "grpc"))]
+        if self.server.grpc_address.is_some() {
+            problems.push(
+                "server.grpc_address: the gRPC server requires the `grpc` feature".to_string(),
+            );
+        }
+        if self.server.grpc_address.is_some() && !self.sinks.contains(&Sink::Sqlite) {
+            problems.push(
+                "server.grpc_address: requires the sqlite sink to replay stored events".to_string(),
+            );
+        }
+        if self.provider == Provider::Files && self.indexer.blocks_directory.is_none() {
+            problems.push(
+                "indexer.blocks_directory: required for the files provider (--blocks-directory, $BLOCKS_DIRECTORY)"
+                    .to_string(),
+            );
+        }
+        if self.export.max_rows_per_file == 0 {
+            problems.push("export.max_rows_per_file: must be greater than 0".to_string());
+        }
+        if self.export.max_buffered_blocks == 0 {
+            problems.push("export.max_buffered_blocks: must be greater than 0".to_string());
+        }
+        if self.redis.max_spilled_blocks == 0 && self.redis.spill_path.is_some() {
+            problems.push("redis.max_spilled_blocks: must be greater than 0".to_string());
+        }
+        if let (Some(start_block), Some(end_block)) =
+            (self.indexer.start_block, self.indexer.end_block)
+        {
+            if start_block >= end_block {
+                problems.push(format!(
+                    "indexer.end_block: must be greater than indexer.start_block ({start_block})"
+                ));
+            }
+        }
+        for event_type in &self.filter.event_types {
+            if !EVENT_TYPES.contains(&event_type.as_str()) {
+                problems.push(format!(
+                    "filter.event_types: unknown event type {event_type:?}, expected one of {}",
+                    EVENT_TYPES.join(", ")
+                ));
+            }
+        }
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            anyhow::bail!("Invalid configuration:\n  - {}", problems.join("\n  - "))
+        }
+    }
+}
@@ -0,0 +1,416 @@
+use std::collections::HashMap;
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use arrow_array::{ArrayRef, RecordBatch, StringArray, UInt64Array};
+use arrow_schema::{DataType, Field, Schema};
+use async_trait::async_trait;
+use chrono::{DateTime, NaiveDate};
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use parquet::arrow::ArrowWriter;
+
+pub use crate::config::ExportFormat;
+use crate::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEventHandler,
+};
+
+/// Writes events to `{directory}/{event_type}/date={YYYY-MM-DD}/{first_block}-{last_block}.{ext}`,
+/// where the date is derived from `block_timestamp_nanosec`. This layout can be read
+/// directly by DuckDB (`read_parquet('dir/*/*/*.parquet', hive_partitioning = true)`)
+/// and pandas / pyarrow.
+///
+/// u128 amounts are stored as decimal strings, since Decimal128 only holds 38 digits
+/// and can't represent every u128.
+///
+/// A file is written when a partition reaches `max_rows_per_file` rows, when
+/// events from a later day arrive, or when its first row is `max_buffered_blocks`
+/// blocks old, so a crash loses at most that many blocks of rows. Call
+/// [`ExportToFiles::finish`] before exiting to write the remaining rows, including
+/// after the indexer failed. Files are written on a blocking thread to a temporary
+/// path and renamed, so readers never see a partial file. Partitions that failed to
+/// write are kept and retried with the next flush.
+///
+/// An event whose block timestamp can't be converted to a date fails the flush of
+/// its block.
+pub struct ExportToFiles {
+    directory: PathBuf,
+    format: ExportFormat,
+    max_rows_per_file: usize,
+    max_buffered_blocks: BlockHeight,
+    partitions: HashMap<(&'static str, NaiveDate), Partition>,
+    latest_day: Option<NaiveDate>,
+    /// Returned by the next flush
+    error: Option<anyhow::Error>,
+}
+
+struct Partition {
+    columns: &'static [(&'static str, ColumnType)],
+    rows: Vec<Vec<Value>>,
+    first_block: BlockHeight,
+    last_block: BlockHeight,
+}
+
+#[derive(Clone, Copy)]
+enum ColumnType {
+    U64,
+    String,
+    NullableString,
+}
+
+enum Value {
+    U64(u64),
+    String(String),
+    NullableString(Option<String>),
+}
+
+impl Value {
+    fn to_csv_field(&self) -> String {
+        match self {
+            Value::U64(value) => value.to_string(),
+            Value::String(value) => value.clone(),
+            Value::NullableString(value) => value.clone().unwrap_or_default(),
+        }
+    }
+}
+
+const CONTEXT_COLUMNS: [(&str, ColumnType); 4] = [
+    ("transaction_id", ColumnType::String),
+    ("receipt_id", ColumnType::String),
+    ("block_height", ColumnType::U64),
+    ("block_timestamp_nanosec", ColumnType::U64),
+];
+
+const DONATION_COLUMNS: &[(&str, ColumnType)] = &[
+    ("donation_id", ColumnType::U64),
+    ("donor_id", ColumnType::String),
+    ("total_amount", ColumnType::String),
+    ("ft_id", ColumnType::String),
+    ("message", ColumnType::NullableString),
+    ("donated_at", ColumnType::U64),
+    ("project_id", ColumnType::String),
+    ("protocol_fee", ColumnType::String),
+    ("referrer_id", ColumnType::NullableString),
+    ("referrer_fee", ColumnType::NullableString),
+    CONTEXT_COLUMNS[0],
+    CONTEXT_COLUMNS[1],
+    CONTEXT_COLUMNS[2],
+    CONTEXT_COLUMNS[3],
+];
+
+const POT_PROJECT_DONATION_COLUMNS: &[(&str, ColumnType)] = &[
+    ("donation_id", ColumnType::U64),
+    ("pot_id", ColumnType::String),
+    ("donor_id", ColumnType::String),
+    ("total_amount", ColumnType::String),
+    ("net_amount", ColumnType::String),
+    ("message", ColumnType::NullableString),
+    ("donated_at", ColumnType::U64),
+    ("project_id", ColumnType::String),
+    ("referrer_id", ColumnType::NullableString),
+    ("referrer_fee", ColumnType::NullableString),
+    ("protocol_fee", ColumnType::String),
+    ("chef_id", ColumnType::NullableString),
+    ("chef_fee", ColumnType::NullableString),
+    CONTEXT_COLUMNS[0],
+    CONTEXT_COLUMNS[1],
+    CONTEXT_COLUMNS[2],
+    CONTEXT_COLUMNS[3],
+];
+
+const POT_DONATION_COLUMNS: &[(&str, ColumnType)] = &[
+    ("donation_id", ColumnType::U64),
+    ("pot_id", ColumnType::String),
+    ("donor_id", ColumnType::String),
+    ("total_amount", ColumnType::String),
+    ("net_amount", ColumnType::String),
+    ("message", ColumnType::NullableString),
+    ("donated_at", ColumnType::U64),
+    ("referrer_id", ColumnType::NullableString),
+    ("referrer_fee", ColumnType::NullableString),
+    ("protocol_fee", ColumnType::String),
+    ("chef_id", ColumnType::NullableString),
+    ("chef_fee", ColumnType::NullableString),
+    CONTEXT_COLUMNS[0],
+    CONTEXT_COLUMNS[1],
+    CONTEXT_COLUMNS[2],
+    CONTEXT_COLUMNS[3],
+];
+
+fn context_values(context: &EventContext) -> [Value; 4] {
+    [
+        Value::String(context.transaction_id.to_string()),
+        Value::String(context.receipt_id.to_string()),
+        Value::U64(context.block_height),
+        Value::U64(context.block_timestamp_nanosec as u64),
+    ]
+}
+
+impl ExportToFiles {
+    pub fn new(
+        directory: impl Into<PathBuf>,
+        format: ExportFormat,
+        max_rows_per_file: usize,
+        max_buffered_blocks: BlockHeight,
+    ) -> Self {
+        Self {
+            directory: directory.into(),
+            format,
+            max_rows_per_file,
+            max_buffered_blocks,
+            partitions: HashMap::new(),
+            latest_day: None,
+            error: None,
+        }
+    }
+
+    fn add_row(
+        &mut self,
+        event_type: &'static str,
+        columns: &'static [(&'static str, ColumnType)],
+        mut row: Vec<Value>,
+        context: EventContext,
+    ) {
+        let Some(day) = i64::try_from(context.block_timestamp_nanosec / 1_000_000_000)
+            .ok()
+            .and_then(|secs| {
+                DateTime::from_timestamp(
+                    secs,
+                    (context.block_timestamp_nanosec % 1_000_000_000) as u32,
+                )
+            })
+        else {
+            self.error.get_or_insert_with(|| {
+                anyhow::anyhow!(
+                    "Block timestamp {} of {event_type} in block {} is out of range",
+                    context.block_timestamp_nanosec,
+                    context.block_height
+                )
+            });
+            return;
+        };
+        let day = day.date_naive();
+        if self.latest_day < Some(day) {
+            self.latest_day = Some(day);
+        }
+        let block_height = context.block_height;
+        row.extend(context_values(&context));
+        let partition = self
+            .partitions
+            .entry((event_type, day))
+            .or_insert_with(|| Partition {
+                columns,
+                rows: Vec::new(),
+                first_block: block_height,
+                last_block: block_height,
+            });
+        partition.first_block = partition.first_block.min(block_height);
+        partition.last_block = partition.last_block.max(block_height);
+        partition.rows.push(row);
+    }
+
+    /// Writes all buffered rows, regardless of partition size.
+    pub async fn finish(&mut self) -> Result<(), anyhow::Error> {
+        let all = self.partitions.keys().copied().collect();
+        self.write_partitions(all).await
+    }
+
+    async fn write_partitions(
+        &mut self,
+        keys: Vec<(&'static str, NaiveDate)>,
+    ) -> Result<(), anyhow::Error> {
+        if keys.is_empty() {
+            return Ok(());
+        }
+        let partitions = keys
+            .into_iter()
+            .map(|key| (key, self.partitions.remove(&key).unwrap()))
+            .collect::<Vec<_>>();
+        let directory = self.directory.clone();
+        let format = self.format;
+        let (unwritten, result) = tokio::task::spawn_blocking(move || {
+            let mut partitions = partitions.into_iter();
+            while let Some(((event_type, day), partition)) = partitions.next() {
+                if let Err(err) = write_partition(&directory, format, event_type, day, &partition) {
+                    let unwritten = std::iter::once(((event_type, day), partition))
+                        .chain(partitions)
+                        .collect::<Vec<_>>();
+                    return (unwritten, Err(err));
+                }
+            }
+            (Vec::new(), Ok(()))
+        })
+        .await?;
+        self.partitions.extend(unwritten);
+        result
+    }
+}
+
+fn write_partition(
+    directory: &Path,
+    format: ExportFormat,
+    event_type: &str,
+    day: NaiveDate,
+    partition: &Partition,
+) -> Result<(), anyhow::Error> {
+    let directory = directory
+        .join(event_type)
+        .join(format!("date={}", day.format("%Y-%m-%d")));
+    std::fs::create_dir_all(&directory)?;
+    let path = directory.join(format!(
+        "{}-{}.{}",
+        partition.first_block,
+        partition.last_block,
+        extension(format)
+    ));
+    let temporary_path = path.with_extension(format!("{}.tmp", extension(format)));
+    match format {
+        ExportFormat::Parquet => write_parquet(&temporary_path, partition)?,
+        ExportFormat::Csv => write_csv(&temporary_path, partition)?,
+    }
+    std::fs::rename(temporary_path, path)?;
+    Ok(())
+}
+
+fn extension(format: ExportFormat) -> &'static str {
+    match format {
+        ExportFormat::Parquet => "parquet",
+        ExportFormat::Csv => "csv",
+    }
+}
+
+fn write_parquet(path: &Path, partition: &Partition) -> Result<(), anyhow::Error> {
+    let schema = Arc::new(Schema::new(
+        partition
+            .columns
+            .iter()
+            .map(|(name, column_type)| match column_type {
+                ColumnType::U64 => Field::new(*name, DataType::UInt64, false),
+                ColumnType::String => Field::new(*name, DataType::Utf8, false),
+                ColumnType::NullableString => Field::new(*name, DataType::Utf8, true),
+            })
+            .collect::<Vec<_>>(),
+    ));
+    let mut arrays: Vec<ArrayRef> = Vec::with_capacity(partition.columns.len());
+    for (index, (_, column_type)) in partition.columns.iter().enumerate() {
+        let values = partition.rows.iter().map(|row| &row[index]);
+        let array: ArrayRef = match column_type {
+            ColumnType::U64 => Arc::new(UInt64Array::from(
+                values
+                    .map(|value| match value {
+                        Value::U64(value) => *value,
+                        _ => unreachable!("Column type mismatch"),
+                    })
+                    .collect::<Vec<_>>(),
+            )),
+            ColumnType::String | ColumnType::NullableString => Arc::new(StringArray::from(
+                values
+                    .map(|value| match value {
+                        Value::String(value) => Some(value.as_str()),
+                        Value::NullableString(value) => value.as_deref(),
+                        _ => unreachable!("Column type mismatch"),
+                    })
+                    .collect::<Vec<_>>(),
+            )),
+        };
+        arrays.push(array);
+    }
+    let batch = RecordBatch::try_new(schema.clone(), arrays)?;
+    let mut writer = ArrowWriter::try_new(File::create(path)?, schema, None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(())
+}
+
+fn write_csv(path: &Path, partition: &Partition) -> Result<(), anyhow::Error> {
+    let mut writer = csv::Writer::from_path(path)?;
+    writer.write_record(partition.columns.iter().map(|(name, _)| *name))?;
+    for row in &partition.rows {
+        writer.write_record(row.iter().map(Value::to_csv_field))?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[async_trait]
+impl PotlockEventHandler for ExportToFiles {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        let row = vec![
+            Value::U64(event.donation_id),
+            Value::String(event.donor_id.to_string()),
+            Value::String(event.total_amount.to_string()),
+            Value::String(event.ft_id.to_string()),
+            Value::NullableString(event.message),
+            Value::U64(event.donated_at),
+            Value::String(event.project_id.to_string()),
+            Value::String(event.protocol_fee.to_string()),
+            Value::NullableString(event.referrer_id.map(|id| id.to_string())),
+            Value::NullableString(event.referrer_fee.map(|fee| fee.to_string())),
+        ];
+        self.add_row("potlock_donation", DONATION_COLUMNS, row, context);
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        let row = vec![
+            Value::U64(event.donation_id),
+            Value::String(event.pot_id.to_string()),
+            Value::String(event.donor_id.to_string()),
+            Value::String(event.total_amount.to_string()),
+            Value::String(event.net_amount.to_string()),
+            Value::NullableString(event.message),
+            Value::U64(event.donated_at),
+            Value::String(event.project_id.to_string()),
+            Value::NullableString(event.referrer_id.map(|id| id.to_string())),
+            Value::NullableString(event.referrer_fee.map(|fee| fee.to_string())),
+            Value::String(event.protocol_fee.to_string()),
+            Value::NullableString(event.chef_id.map(|id| id.to_string())),
+            Value::NullableString(event.chef_fee.map(|fee| fee.to_string())),
+        ];
+        self.add_row(
+            "potlock_pot_project_donation",
+            POT_PROJECT_DONATION_COLUMNS,
+            row,
+            context,
+        );
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        let row = vec![
+            Value::U64(event.donation_id),
+            Value::String(event.pot_id.to_string()),
+            Value::String(event.donor_id.to_string()),
+            Value::String(event.total_amount.to_string()),
+            Value::String(event.net_amount.to_string()),
+            Value::NullableString(event.message),
+            Value::U64(event.donated_at),
+            Value::NullableString(event.referrer_id.map(|id| id.to_string())),
+            Value::NullableString(event.referrer_fee.map(|fee| fee.to_string())),
+            Value::String(event.protocol_fee.to_string()),
+            Value::NullableString(event.chef_id.map(|id| id.to_string())),
+            Value::NullableString(event.chef_fee.map(|fee| fee.to_string())),
+        ];
+        self.add_row("potlock_pot_donation", POT_DONATION_COLUMNS, row, context);
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        let ready = self
+            .partitions
+            .iter()
+            .filter(|((_, day), partition)| {
+                partition.rows.len() >= self.max_rows_per_file
+                    || self.latest_day.is_some_and(|latest_day| *day < latest_day)
+                    || block_height.saturating_sub(partition.first_block)
+                        >= self.max_buffered_blocks
+            })
+            .map(|(key, _)| *key)
+            .collect::<Vec<_>>();
+        self.write_partitions(ready).await
+    }
+}
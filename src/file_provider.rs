@@ -0,0 +1,175 @@
+use std::fmt::{self, Display};
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use anyhow::Context;
+use async_trait::async_trait;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::{StreamExt, TryStreamExt};
+use inindexer::message_provider::MessageProvider;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use inindexer::near_indexer_primitives::StreamerMessage;
+
+/// Reads blocks from `{directory}/{height}.json` or `{directory}/{height}.json.gz`,
+/// as written by [`download`]. An empty `{directory}/{height}.skipped` marker means
+/// that no block was produced at this height. A height with neither is an error,
+/// since it was never downloaded.
+#[derive(Debug, Clone)]
+pub struct FileProvider {
+    directory: PathBuf,
+}
+
+impl FileProvider {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct FileProviderError {
+    path: PathBuf,
+    message: String,
+}
+
+impl Display for FileProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Failed to read block {}: {}",
+            self.path.display(),
+            self.message
+        )
+    }
+}
+
+impl std::error::Error for FileProviderError {}
+
+#[async_trait]
+impl MessageProvider for FileProvider {
+    type Error = FileProviderError;
+
+    async fn get_message(
+        &self,
+        block_height: BlockHeight,
+    ) -> Result<Option<StreamerMessage>, Self::Error> {
+        let directory = self.directory.clone();
+        tokio::task::spawn_blocking(move || read_block(&directory, block_height))
+            .await
+            .expect("Block reading task panicked")
+    }
+}
+
+fn read_block(
+    directory: &Path,
+    block_height: BlockHeight,
+) -> Result<Option<StreamerMessage>, FileProviderError> {
+    for (path, compressed) in [
+        (directory.join(format!("{block_height}.json")), false),
+        (directory.join(format!("{block_height}.json.gz")), true),
+    ] {
+        let error = |message: String| FileProviderError {
+            path: path.clone(),
+            message,
+        };
+        let file = match File::open(&path) {
+            Ok(file) => BufReader::new(file),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(error(err.to_string())),
+        };
+        let reader: Box<dyn Read> = if compressed {
+            Box::new(GzDecoder::new(file))
+        } else {
+            Box::new(file)
+        };
+        return serde_json::from_reader(reader)
+            .map(Some)
+            .map_err(|err| error(err.to_string()));
+    }
+    let marker_path = skipped_marker_path(directory, block_height);
+    match marker_path.try_exists() {
+        Ok(true) => Ok(None),
+        Ok(false) => Err(FileProviderError {
+            path: marker_path,
+            message: "Neither the block nor a skipped marker was downloaded".to_string(),
+        }),
+        Err(err) => Err(FileProviderError {
+            path: marker_path,
+            message: err.to_string(),
+        }),
+    }
+}
+
+fn skipped_marker_path(directory: &Path, block_height: BlockHeight) -> PathBuf {
+    directory.join(format!("{block_height}.skipped"))
+}
+
+/// Fetches blocks from `provider` and writes them in the format read by
+/// [`FileProvider`], `concurrency` blocks at a time. Heights without a block get a
+/// skipped marker. Heights that already have a block or a marker in `directory`
+/// are not downloaded again. Returns the number of blocks written, not counting
+/// markers.
+pub async fn download<P: MessageProvider>(
+    provider: &P,
+    range: Range<BlockHeight>,
+    directory: &Path,
+    compress: bool,
+    concurrency: usize,
+) -> Result<usize, anyhow::Error> {
+    std::fs::create_dir_all(directory)
+        .with_context(|| format!("Failed to create {}", directory.display()))?;
+    let extension = if compress { "json.gz" } else { "json" };
+    futures::stream::iter(range)
+        .filter(|block_height| {
+            let exists = directory.join(format!("{block_height}.json")).exists()
+                || directory.join(format!("{block_height}.json.gz")).exists()
+                || skipped_marker_path(directory, block_height).exists();
+            async move { !exists }
+        })
+        .map(|block_height| async move {
+            let message = provider
+                .get_message(block_height)
+                .await
+                .map_err(|err| anyhow::anyhow!("Failed to fetch block {block_height}: {err}"))?;
+            Ok::<_, anyhow::Error>((block_height, message))
+        })
+        .buffered(concurrency)
+        .try_fold(0, |written, (block_height, message)| async move {
+            let Some(message) = message else {
+                let path = skipped_marker_path(directory, block_height);
+                File::create(&path)
+                    .with_context(|| format!("Failed to write {}", path.display()))?;
+                return Ok(written);
+            };
+            let path = directory.join(format!("{block_height}.{extension}"));
+            write_block(&path, &message, compress)
+                .with_context(|| format!("Failed to write {}", path.display()))?;
+            Ok(written + 1)
+        })
+        .await
+}
+
+fn write_block(
+    path: &Path,
+    message: &StreamerMessage,
+    compress: bool,
+) -> Result<(), anyhow::Error> {
+    // Written to a temporary file first, so that an interrupted download never leaves
+    // a truncated block behind
+    let temp_path = path.with_extension("tmp");
+    let mut file = BufWriter::new(File::create(&temp_path)?);
+    if compress {
+        let mut encoder = GzEncoder::new(&mut file, flate2::Compression::default());
+        serde_json::to_writer(&mut encoder, message)?;
+        encoder.finish()?;
+    } else {
+        serde_json::to_writer(&mut file, message)?;
+    }
+    file.into_inner()?.sync_data()?;
+    std::fs::rename(temp_path, path)?;
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use serde::Deserialize;
+
+use crate::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEvent,
+    PotlockEventHandler,
+};
+
+pub const EVENT_TYPES: [&str; 3] = [
+    "potlock_donation",
+    "potlock_pot_project_donation",
+    "potlock_pot_donation",
+];
+
+/// Restricts which events are indexed. An empty list allows everything, otherwise
+/// the event must match one of the listed values, for each non-empty list.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EventFilter {
+    /// See [`EVENT_TYPES`]
+    pub event_types: Vec<String>,
+    /// Direct donations never match if this is set
+    pub pot_ids: Vec<AccountId>,
+    /// Pot donations to the matching pool never match if this is set
+    pub project_ids: Vec<AccountId>,
+    pub donor_ids: Vec<AccountId>,
+}
+
+impl EventFilter {
+    pub fn is_empty(&self) -> bool {
+        self.event_types.is_empty()
+            && self.pot_ids.is_empty()
+            && self.project_ids.is_empty()
+            && self.donor_ids.is_empty()
+    }
+
+    pub fn matches(&self, event: &PotlockEvent) -> bool {
+        (self.event_types.is_empty()
+            || self
+                .event_types
+                .iter()
+                .any(|event_type| event_type == event.event_type()))
+            && (self.pot_ids.is_empty()
+                || event
+                    .pot_id()
+                    .is_some_and(|pot_id| self.pot_ids.contains(pot_id)))
+            && (self.project_ids.is_empty()
+                || event
+                    .project_id()
+                    .is_some_and(|project_id| self.project_ids.contains(project_id)))
+            && (self.donor_ids.is_empty() || self.donor_ids.contains(event.donor_id()))
+    }
+}
+
+/// Passes only the events matching the filter to the inner handler. Every block is
+/// still flushed.
+pub struct FilteringHandler<T: PotlockEventHandler> {
+    inner: T,
+    filter: EventFilter,
+}
+
+impl<T: PotlockEventHandler> FilteringHandler<T> {
+    pub fn new(inner: T, filter: EventFilter) -> Self {
+        Self { inner, filter }
+    }
+
+    pub fn inner(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[async_trait]
+impl<T: PotlockEventHandler> PotlockEventHandler for FilteringHandler<T> {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        if self.filter.is_empty() || self.filter.matches(&PotlockEvent::Donation(event.clone())) {
+            self.inner.handle_donation(event, context).await;
+        }
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        if self.filter.is_empty()
+            || self
+                .filter
+                .matches(&PotlockEvent::PotProjectDonation(event.clone()))
+        {
+            self.inner.handle_pot_project_donation(event, context).await;
+        }
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        if self.filter.is_empty()
+            || self
+                .filter
+                .matches(&PotlockEvent::PotDonation(event.clone()))
+        {
+            self.inner.handle_pot_donation(event, context).await;
+        }
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        self.inner.flush_events(block_height).await
+    }
+}
@@ -0,0 +1,800 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use async_graphql::dataloader::{DataLoader, Loader};
+use async_graphql::http::GraphiQLSource;
+use async_graphql::{
+    Context, EmptyMutation, Enum, InputObject, Object, Schema, SimpleObject, Subscription,
+};
+use async_graphql_axum::{GraphQL, GraphQLSubscription};
+use axum::response::Html;
+use axum::routing::get;
+use axum::Router;
+use futures::Stream;
+use inindexer::near_indexer_primitives::types::AccountId;
+use tokio::sync::broadcast;
+
+use crate::aggregating_handler::Totals as StoredTotals;
+use crate::live_feed::{LiveEvent, LiveFeed, LiveFeedFilter};
+use crate::storage::{
+    Cursor, DonationFilter, DonationStore, GroupBy, Order as StoredOrder, Page, StoredDonation,
+    TopDonor as StoredTopDonor,
+};
+use crate::PotlockEvent;
+
+const DEFAULT_LIMIT: usize = 20;
+const MAX_LIMIT: usize = 100;
+/// Deep enough for `pot { projects { projects { donations { donations { donor { totals {
+/// amounts { amount } } } } } } } }`
+const MAX_DEPTH: usize = 10;
+/// Each field counts 1, multiplied by `limit` inside paginated lists, so a full page
+/// of donations with every field fits
+const MAX_COMPLEXITY: usize = 5_000;
+
+pub type PotlockSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+/// Queries are served from `store`, subscriptions from `feed`. Totals, donations, and
+/// top donors requested for several pots, projects, or donors in one query are
+/// loaded together.
+pub fn schema(store: Arc<dyn DonationStore>, feed: LiveFeed) -> PotlockSchema {
+    Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+        .data(DataLoader::new(
+            StoreLoader(Arc::clone(&store)),
+            tokio::spawn,
+        ))
+        .data(store)
+        .data(feed)
+        .limit_depth(MAX_DEPTH)
+        .limit_complexity(MAX_COMPLEXITY)
+        .finish()
+}
+
+/// `POST /graphql` for queries, `GET /graphql` for GraphiQL, `/graphql/ws` for
+/// subscriptions
+pub fn router(schema: PotlockSchema) -> Router {
+    Router::new()
+        .route(
+            "/graphql",
+            get(graphiql).post_service(GraphQL::new(schema.clone())),
+        )
+        .route_service("/graphql/ws", GraphQLSubscription::new(schema))
+}
+
+async fn graphiql() -> Html<String> {
+    Html(
+        GraphiQLSource::build()
+            .endpoint("/graphql")
+            .subscription_endpoint("/graphql/ws")
+            .finish(),
+    )
+}
+
+fn store<'a>(ctx: &Context<'a>) -> &'a Arc<dyn DonationStore> {
+    ctx.data_unchecked::<Arc<dyn DonationStore>>()
+}
+
+fn limit(limit: Option<usize>) -> usize {
+    limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+}
+
+/// Loads data of several accounts with one store query per kind of data
+struct StoreLoader(Arc<dyn DonationStore>);
+
+fn loader<'a>(ctx: &Context<'a>) -> &'a DataLoader<StoreLoader> {
+    ctx.data_unchecked::<DataLoader<StoreLoader>>()
+}
+
+impl Loader<(GroupBy, AccountId)> for StoreLoader {
+    type Value = StoredTotals;
+    type Error = Arc<anyhow::Error>;
+
+    async fn load(
+        &self,
+        keys: &[(GroupBy, AccountId)],
+    ) -> Result<HashMap<(GroupBy, AccountId), StoredTotals>, Self::Error> {
+        let mut totals = HashMap::with_capacity(keys.len());
+        for group_by in [GroupBy::Pot, GroupBy::Project, GroupBy::Donor] {
+            let ids = keys
+                .iter()
+                .filter(|(key_group_by, _)| *key_group_by == group_by)
+                .map(|(_, id)| id.clone())
+                .collect::<Vec<_>>();
+            if ids.is_empty() {
+                continue;
+            }
+            let mut group_totals = self.0.totals_by(group_by, &ids).await.map_err(Arc::new)?;
+            for id in ids {
+                let value = group_totals.remove(&id).unwrap_or_default();
+                totals.insert((group_by, id), value);
+            }
+        }
+        Ok(totals)
+    }
+}
+
+async fn load_totals(
+    ctx: &Context<'_>,
+    group_by: GroupBy,
+    id: &AccountId,
+) -> async_graphql::Result<Totals> {
+    Ok(loader(ctx)
+        .load_one((group_by, id.clone()))
+        .await?
+        .unwrap_or_default()
+        .into())
+}
+
+/// A page of donations of the account `id`, which is the `group_by` field of `filter`
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct DonationsKey {
+    group_by: GroupBy,
+    id: AccountId,
+    /// Without the `group_by` field
+    filter: DonationFilter,
+    order: StoredOrder,
+    after: Option<Cursor>,
+    limit: usize,
+}
+
+impl Loader<DonationsKey> for StoreLoader {
+    type Value = Page;
+    type Error = Arc<anyhow::Error>;
+
+    async fn load(
+        &self,
+        keys: &[DonationsKey],
+    ) -> Result<HashMap<DonationsKey, Page>, Self::Error> {
+        // Keys that differ only in the account are loaded with one query
+        let mut queries = HashMap::<_, Vec<AccountId>>::new();
+        for key in keys {
+            queries
+                .entry((
+                    key.group_by,
+                    key.filter.clone(),
+                    key.order,
+                    key.after,
+                    key.limit,
+                ))
+                .or_default()
+                .push(key.id.clone());
+        }
+        let mut pages = HashMap::with_capacity(keys.len());
+        for ((group_by, filter, order, after, limit), ids) in queries {
+            let mut group_pages = self
+                .0
+                .donations_by(group_by, &ids, &filter, order, after, limit)
+                .await
+                .map_err(Arc::new)?;
+            for id in ids {
+                let page = group_pages.remove(&id).unwrap_or(Page {
+                    donations: Vec::new(),
+                    next_cursor: None,
+                });
+                let key = DonationsKey {
+                    group_by,
+                    id,
+                    filter: filter.clone(),
+                    order,
+                    after,
+                    limit,
+                };
+                pages.insert(key, page);
+            }
+        }
+        Ok(pages)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Hash)]
+struct TopDonorsKey {
+    group_by: GroupBy,
+    id: AccountId,
+    ft_id: AccountId,
+    limit: usize,
+}
+
+impl Loader<TopDonorsKey> for StoreLoader {
+    type Value = Vec<StoredTopDonor>;
+    type Error = Arc<anyhow::Error>;
+
+    async fn load(
+        &self,
+        keys: &[TopDonorsKey],
+    ) -> Result<HashMap<TopDonorsKey, Vec<StoredTopDonor>>, Self::Error> {
+        let mut queries = HashMap::<_, Vec<AccountId>>::new();
+        for key in keys {
+            queries
+                .entry((key.group_by, key.ft_id.clone(), key.limit))
+                .or_default()
+                .push(key.id.clone());
+        }
+        let mut top_donors = HashMap::with_capacity(keys.len());
+        for ((group_by, ft_id, limit), ids) in queries {
+            let mut group_top_donors = self
+                .0
+                .top_donors_by(group_by, &ids, &ft_id, limit)
+                .await
+                .map_err(Arc::new)?;
+            for id in ids {
+                let value = group_top_donors.remove(&id).unwrap_or_default();
+                let key = TopDonorsKey {
+                    group_by,
+                    id,
+                    ft_id: ft_id.clone(),
+                    limit,
+                };
+                top_donors.insert(key, value);
+            }
+        }
+        Ok(top_donors)
+    }
+}
+
+#[derive(Enum, Clone, Copy, PartialEq, Eq, Default)]
+enum Order {
+    Ascending,
+    /// Newest first
+    #[default]
+    Descending,
+}
+
+impl From<Order> for StoredOrder {
+    fn from(order: Order) -> Self {
+        match order {
+            Order::Ascending => StoredOrder::Ascending,
+            Order::Descending => StoredOrder::Descending,
+        }
+    }
+}
+
+/// All set fields must match
+#[derive(InputObject, Clone, Default)]
+struct DonationFilterInput {
+    project_id: Option<String>,
+    donor_id: Option<String>,
+    pot_id: Option<String>,
+    /// `potlock_donation`, `potlock_pot_project_donation`, or `potlock_pot_donation`
+    event_type: Option<String>,
+    from_block: Option<u64>,
+}
+
+fn parse_account_id(id: Option<String>) -> async_graphql::Result<Option<AccountId>> {
+    Ok(id.map(|id| id.parse()).transpose()?)
+}
+
+impl DonationFilterInput {
+    fn into_filter(self) -> async_graphql::Result<DonationFilter> {
+        Ok(DonationFilter {
+            project_id: parse_account_id(self.project_id)?,
+            donor_id: parse_account_id(self.donor_id)?,
+            pot_id: parse_account_id(self.pot_id)?,
+            event_type: self.event_type,
+            from_block: self.from_block,
+        })
+    }
+}
+
+async fn donation_page(
+    ctx: &Context<'_>,
+    filter: Option<DonationFilterInput>,
+    order: Option<Order>,
+    after: Option<String>,
+    limit: Option<usize>,
+) -> async_graphql::Result<DonationPage> {
+    let filter = filter.unwrap_or_default().into_filter()?;
+    let after = after.map(|after| after.parse()).transpose()?;
+    let page = store(ctx)
+        .donations(
+            &filter,
+            order.unwrap_or_default().into(),
+            after,
+            self::limit(limit),
+        )
+        .await?;
+    Ok(DonationPage {
+        donations: page.donations.into_iter().map(Donation).collect(),
+        next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+    })
+}
+
+/// Donations of the `group_by` account `id`, loaded together with those of other
+/// accounts. A `group_by` field set in `filter` replaces `id`.
+async fn grouped_donation_page(
+    ctx: &Context<'_>,
+    group_by: GroupBy,
+    id: &AccountId,
+    filter: Option<DonationFilterInput>,
+    order: Option<Order>,
+    after: Option<String>,
+    limit: Option<usize>,
+) -> async_graphql::Result<DonationPage> {
+    let mut filter = filter.unwrap_or_default().into_filter()?;
+    let id = filter
+        .group_id_mut(group_by)
+        .take()
+        .unwrap_or_else(|| id.clone());
+    let key = DonationsKey {
+        group_by,
+        id,
+        filter,
+        order: order.unwrap_or_default().into(),
+        after: after.map(|after| after.parse()).transpose()?,
+        limit: self::limit(limit),
+    };
+    let page = loader(ctx).load_one(key).await?.unwrap_or(Page {
+        donations: Vec::new(),
+        next_cursor: None,
+    });
+    Ok(DonationPage {
+        donations: page.donations.into_iter().map(Donation).collect(),
+        next_cursor: page.next_cursor.map(|cursor| cursor.to_string()),
+    })
+}
+
+#[derive(SimpleObject)]
+struct DonationPage {
+    donations: Vec<Donation>,
+    /// Pass as `after` to get the next page. Null if this is the last page.
+    next_cursor: Option<String>,
+}
+
+#[derive(SimpleObject)]
+struct ProjectPage {
+    projects: Vec<Project>,
+    /// Pass as `after` to get the next page. Null if this is the last page.
+    next_cursor: Option<String>,
+}
+
+#[derive(SimpleObject)]
+struct TokenAmount {
+    ft_id: String,
+    /// Decimal string, in the smallest units of the token
+    amount: String,
+}
+
+#[derive(SimpleObject)]
+struct Totals {
+    amounts: Vec<TokenAmount>,
+    donation_count: u64,
+    unique_donors: u64,
+}
+
+impl From<StoredTotals> for Totals {
+    fn from(totals: StoredTotals) -> Self {
+        Self {
+            amounts: totals
+                .amounts
+                .into_iter()
+                .map(|(ft_id, amount)| TokenAmount {
+                    ft_id: ft_id.to_string(),
+                    amount: amount.0.to_string(),
+                })
+                .collect(),
+            donation_count: totals.donation_count,
+            unique_donors: totals.unique_donors,
+        }
+    }
+}
+
+#[derive(SimpleObject)]
+struct TopDonor {
+    donor: Donor,
+    /// Decimal string, in the smallest units of the token
+    amount: String,
+    donation_count: u64,
+}
+
+impl From<StoredTopDonor> for TopDonor {
+    fn from(top_donor: StoredTopDonor) -> Self {
+        Self {
+            donor: Donor(top_donor.donor_id),
+            amount: top_donor.amount.0.to_string(),
+            donation_count: top_donor.donation_count,
+        }
+    }
+}
+
+fn ft_id(ft_id: Option<String>) -> async_graphql::Result<AccountId> {
+    Ok(ft_id.as_deref().unwrap_or("near").parse()?)
+}
+
+/// Top donors of the `group_by` account `id`, loaded together with those of other
+/// accounts
+async fn grouped_top_donors(
+    ctx: &Context<'_>,
+    group_by: GroupBy,
+    id: &AccountId,
+    ft_id: Option<String>,
+    limit: Option<usize>,
+) -> async_graphql::Result<Vec<TopDonor>> {
+    let key = TopDonorsKey {
+        group_by,
+        id: id.clone(),
+        ft_id: self::ft_id(ft_id)?,
+        limit: self::limit(limit),
+    };
+    Ok(loader(ctx)
+        .load_one(key)
+        .await?
+        .unwrap_or_default()
+        .into_iter()
+        .map(TopDonor::from)
+        .collect())
+}
+
+pub struct QueryRoot;
+
+#[Object]
+impl QueryRoot {
+    #[graphql(complexity = "self::limit(limit) * child_complexity")]
+    async fn donations(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<DonationFilterInput>,
+        order: Option<Order>,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<DonationPage> {
+        donation_page(ctx, filter, order, after, limit).await
+    }
+
+    async fn pot(&self, id: String) -> async_graphql::Result<Pot> {
+        Ok(Pot(id.parse()?))
+    }
+
+    async fn project(&self, id: String) -> async_graphql::Result<Project> {
+        Ok(Project(id.parse()?))
+    }
+
+    async fn donor(&self, id: String) -> async_graphql::Result<Donor> {
+        Ok(Donor(id.parse()?))
+    }
+
+    /// Donors sorted by the total amount of `ft_id` (default `near`) donated
+    #[graphql(complexity = "self::limit(limit) * child_complexity")]
+    async fn top_donors(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<DonationFilterInput>,
+        ft_id: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<Vec<TopDonor>> {
+        let filter = filter.unwrap_or_default().into_filter()?;
+        Ok(store(ctx)
+            .top_donors(&filter, &self::ft_id(ft_id)?, self::limit(limit))
+            .await?
+            .into_iter()
+            .map(TopDonor::from)
+            .collect())
+    }
+}
+
+/// A pot, which represents a funding round
+struct Pot(AccountId);
+
+#[Object]
+impl Pot {
+    async fn id(&self) -> &str {
+        self.0.as_str()
+    }
+
+    async fn totals(&self, ctx: &Context<'_>) -> async_graphql::Result<Totals> {
+        load_totals(ctx, GroupBy::Pot, &self.0).await
+    }
+
+    /// Projects that received donations in this pot, sorted by id
+    #[graphql(complexity = "self::limit(limit) * child_complexity")]
+    async fn projects(
+        &self,
+        ctx: &Context<'_>,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<ProjectPage> {
+        let after = after.map(|after| after.parse()).transpose()?;
+        let limit = self::limit(limit);
+        // Fetching one more project to know if there's a next page
+        let mut project_ids = store(ctx)
+            .project_ids(&self.filter(), after, limit + 1)
+            .await?;
+        let next_cursor = if project_ids.len() > limit {
+            project_ids.truncate(limit);
+            project_ids.last().map(|id| id.to_string())
+        } else {
+            None
+        };
+        Ok(ProjectPage {
+            projects: project_ids.into_iter().map(Project).collect(),
+            next_cursor,
+        })
+    }
+
+    #[graphql(complexity = "self::limit(limit) * child_complexity")]
+    async fn donations(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<DonationFilterInput>,
+        order: Option<Order>,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<DonationPage> {
+        grouped_donation_page(ctx, GroupBy::Pot, &self.0, filter, order, after, limit).await
+    }
+
+    #[graphql(complexity = "self::limit(limit) * child_complexity")]
+    async fn top_donors(
+        &self,
+        ctx: &Context<'_>,
+        ft_id: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<Vec<TopDonor>> {
+        grouped_top_donors(ctx, GroupBy::Pot, &self.0, ft_id, limit).await
+    }
+}
+
+impl Pot {
+    fn filter(&self) -> DonationFilter {
+        DonationFilter {
+            pot_id: Some(self.0.clone()),
+            ..Default::default()
+        }
+    }
+}
+
+struct Project(AccountId);
+
+#[Object]
+impl Project {
+    async fn id(&self) -> &str {
+        self.0.as_str()
+    }
+
+    async fn totals(&self, ctx: &Context<'_>) -> async_graphql::Result<Totals> {
+        load_totals(ctx, GroupBy::Project, &self.0).await
+    }
+
+    #[graphql(complexity = "self::limit(limit) * child_complexity")]
+    async fn donations(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<DonationFilterInput>,
+        order: Option<Order>,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<DonationPage> {
+        grouped_donation_page(ctx, GroupBy::Project, &self.0, filter, order, after, limit).await
+    }
+
+    #[graphql(complexity = "self::limit(limit) * child_complexity")]
+    async fn top_donors(
+        &self,
+        ctx: &Context<'_>,
+        ft_id: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<Vec<TopDonor>> {
+        grouped_top_donors(ctx, GroupBy::Project, &self.0, ft_id, limit).await
+    }
+}
+
+struct Donor(AccountId);
+
+#[Object]
+impl Donor {
+    async fn id(&self) -> &str {
+        self.0.as_str()
+    }
+
+    /// Amounts donated by this donor. `uniqueDonors` is always 1.
+    async fn totals(&self, ctx: &Context<'_>) -> async_graphql::Result<Totals> {
+        load_totals(ctx, GroupBy::Donor, &self.0).await
+    }
+
+    #[graphql(complexity = "self::limit(limit) * child_complexity")]
+    async fn donations(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<DonationFilterInput>,
+        order: Option<Order>,
+        after: Option<String>,
+        limit: Option<usize>,
+    ) -> async_graphql::Result<DonationPage> {
+        grouped_donation_page(ctx, GroupBy::Donor, &self.0, filter, order, after, limit).await
+    }
+}
+
+struct Donation(StoredDonation);
+
+#[Object]
+impl Donation {
+    /// Pass as `after` to paginate from this donation
+    async fn cursor(&self) -> String {
+        self.0.cursor().to_string()
+    }
+
+    /// `potlock_donation`, `potlock_pot_project_donation`, or `potlock_pot_donation`
+    async fn event_type(&self) -> &str {
+        self.0.event.event_type()
+    }
+
+    async fn donation_id(&self) -> u64 {
+        self.0.event.donation_id()
+    }
+
+    async fn donor(&self) -> Donor {
+        Donor(self.0.event.donor_id().clone())
+    }
+
+    /// Null for pot (matching pool) donations
+    async fn project(&self) -> Option<Project> {
+        self.0.event.project_id().cloned().map(Project)
+    }
+
+    /// Null for direct donations
+    async fn pot(&self) -> Option<Pot> {
+        self.0.event.pot_id().cloned().map(Pot)
+    }
+
+    async fn ft_id(&self) -> String {
+        self.0.event.ft_id().to_string()
+    }
+
+    /// Decimal string, in the smallest units of the token
+    async fn total_amount(&self) -> String {
+        self.0.event.total_amount().to_string()
+    }
+
+    /// Amount after all fees, only for pot donations
+    async fn net_amount(&self) -> Option<String> {
+        match &self.0.event {
+            PotlockEvent::Donation(_) => None,
+            PotlockEvent::PotProjectDonation(event) => Some(event.net_amount.to_string()),
+            PotlockEvent::PotDonation(event) => Some(event.net_amount.to_string()),
+        }
+    }
+
+    async fn message(&self) -> Option<&str> {
+        match &self.0.event {
+            PotlockEvent::Donation(event) => event.message.as_deref(),
+            PotlockEvent::PotProjectDonation(event) => event.message.as_deref(),
+            PotlockEvent::PotDonation(event) => event.message.as_deref(),
+        }
+    }
+
+    /// Timestamp in milliseconds
+    async fn donated_at(&self) -> u64 {
+        match &self.0.event {
+            PotlockEvent::Donation(event) => event.donated_at,
+            PotlockEvent::PotProjectDonation(event) => event.donated_at,
+            PotlockEvent::PotDonation(event) => event.donated_at,
+        }
+    }
+
+    async fn protocol_fee(&self) -> String {
+        match &self.0.event {
+            PotlockEvent::Donation(event) => event.protocol_fee.to_string(),
+            PotlockEvent::PotProjectDonation(event) => event.protocol_fee.to_string(),
+            PotlockEvent::PotDonation(event) => event.protocol_fee.to_string(),
+        }
+    }
+
+    async fn referrer_id(&self) -> Option<&str> {
+        match &self.0.event {
+            PotlockEvent::Donation(event) => event.referrer_id.as_ref(),
+            PotlockEvent::PotProjectDonation(event) => event.referrer_id.as_ref(),
+            PotlockEvent::PotDonation(event) => event.referrer_id.as_ref(),
+        }
+        .map(|id| id.as_str())
+    }
+
+    async fn referrer_fee(&self) -> Option<String> {
+        match &self.0.event {
+            PotlockEvent::Donation(event) => event.referrer_fee,
+            PotlockEvent::PotProjectDonation(event) => event.referrer_fee,
+            PotlockEvent::PotDonation(event) => event.referrer_fee,
+        }
+        .map(|fee| fee.to_string())
+    }
+
+    async fn chef_id(&self) -> Option<&str> {
+        match &self.0.event {
+            PotlockEvent::Donation(_) => None,
+            PotlockEvent::PotProjectDonation(event) => event.chef_id.as_ref(),
+            PotlockEvent::PotDonation(event) => event.chef_id.as_ref(),
+        }
+        .map(|id| id.as_str())
+    }
+
+    async fn chef_fee(&self) -> Option<String> {
+        match &self.0.event {
+            PotlockEvent::Donation(_) => None,
+            PotlockEvent::PotProjectDonation(event) => event.chef_fee,
+            PotlockEvent::PotDonation(event) => event.chef_fee,
+        }
+        .map(|fee| fee.to_string())
+    }
+
+    async fn transaction_id(&self) -> String {
+        self.0.context.transaction_id.to_string()
+    }
+
+    async fn receipt_id(&self) -> String {
+        self.0.context.receipt_id.to_string()
+    }
+
+    async fn block_height(&self) -> u64 {
+        self.0.context.block_height
+    }
+
+    /// Decimal string
+    async fn block_timestamp_nanosec(&self) -> String {
+        self.0.context.block_timestamp_nanosec.to_string()
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription]
+impl SubscriptionRoot {
+    /// New donations as soon as the indexer processes them. With `fromBlock`,
+    /// donations from that block still in the live feed backlog (the last 1,000
+    /// events) are sent first, older ones can be fetched with the `donations` query.
+    ///
+    /// A subscriber that falls too far behind receives an error and the subscription
+    /// ends. Fetch the missed donations with the `donations` query, then subscribe
+    /// again.
+    async fn donations(
+        &self,
+        ctx: &Context<'_>,
+        filter: Option<DonationFilterInput>,
+    ) -> async_graphql::Result<impl Stream<Item = async_graphql::Result<Donation>>> {
+        let filter = filter.unwrap_or_default().into_filter()?;
+        let since_block = filter
+            .from_block
+            .map(|from_block| from_block.saturating_sub(1));
+        let filter = LiveFeedFilter {
+            pot_id: filter.pot_id,
+            project_id: filter.project_id,
+            donor_id: filter.donor_id,
+            event_type: filter.event_type,
+        };
+        let (missed, receiver) = ctx.data_unchecked::<LiveFeed>().subscribe(since_block);
+        Ok(futures::stream::unfold(
+            Some((missed.into_iter(), receiver, filter)),
+            |state| async move {
+                let (mut missed, mut receiver, filter) = state?;
+                let item = next_donation(&mut missed, &mut receiver, &filter).await?;
+                // Ends the subscription after an error
+                let state = item.is_ok().then_some((missed, receiver, filter));
+                Some((item, state))
+            },
+        ))
+    }
+}
+
+/// Backlog events in `missed` first, then events from `receiver`. `None` once the
+/// feed is closed.
+async fn next_donation(
+    missed: &mut std::vec::IntoIter<Arc<LiveEvent>>,
+    receiver: &mut broadcast::Receiver<Arc<LiveEvent>>,
+    filter: &LiveFeedFilter,
+) -> Option<async_graphql::Result<Donation>> {
+    loop {
+        let event = match missed.next() {
+            Some(event) => event,
+            None => match receiver.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(missed)) => {
+                    return Some(Err(async_graphql::Error::new(format!(
+                        "Subscriber fell behind and {missed} events were dropped, fetch them with the `donations` query and subscribe again"
+                    ))))
+                }
+                Err(broadcast::error::RecvError::Closed) => return None,
+            },
+        };
+        if filter.matches(&event.event) {
+            return Some(Ok(Donation(StoredDonation {
+                event: event.event.clone(),
+                context: event.context.clone(),
+            })));
+        }
+    }
+}
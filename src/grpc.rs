@@ -0,0 +1,288 @@
+use std::sync::Arc;
+
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use tokio::sync::{broadcast, mpsc};
+use tokio_stream::wrappers::{ReceiverStream, TcpListenerStream};
+use tonic::{Request, Response, Status};
+
+use crate::live_feed::{LiveEvent, LiveFeed, LiveFeedFilter};
+use crate::storage::{Cursor, DonationFilter, DonationStore, Order, StoredDonation};
+use crate::{EventContext, PotlockEvent};
+
+pub mod proto {
+    tonic::include_proto!("potlock");
+}
+
+use proto::potlock_server::{Potlock, PotlockServer};
+
+/// Number of events buffered per subscriber before the server waits for the client
+const SUBSCRIBER_BUFFER: usize = 128;
+const REPLAY_PAGE_SIZE: usize = 100;
+
+/// `potlock.Potlock` gRPC service (see `proto/potlock.proto`). Stored events are
+/// read from `store`, live events from `feed`.
+pub struct PotlockService {
+    store: Arc<dyn DonationStore>,
+    feed: LiveFeed,
+}
+
+impl PotlockService {
+    pub fn new(store: Arc<dyn DonationStore>, feed: LiveFeed) -> Self {
+        Self { store, feed }
+    }
+}
+
+pub async fn serve(
+    service: PotlockService,
+    listener: tokio::net::TcpListener,
+) -> Result<(), anyhow::Error> {
+    tonic::transport::Server::builder()
+        .add_service(PotlockServer::new(service))
+        .serve_with_incoming(TcpListenerStream::new(listener))
+        .await?;
+    Ok(())
+}
+
+fn parse_account_id(id: Option<String>) -> Result<Option<AccountId>, Status> {
+    id.map(|id| {
+        id.parse()
+            .map_err(|err| Status::invalid_argument(format!("Invalid account id {id}: {err}")))
+    })
+    .transpose()
+}
+
+#[tonic::async_trait]
+impl Potlock for PotlockService {
+    type SubscribeStream = ReceiverStream<Result<proto::Event, Status>>;
+
+    async fn subscribe(
+        &self,
+        request: Request<proto::SubscribeRequest>,
+    ) -> Result<Response<Self::SubscribeStream>, Status> {
+        let request = request.into_inner();
+        let filter = request.filter.unwrap_or_default();
+        let filter = LiveFeedFilter {
+            project_id: parse_account_id(filter.project_id)?,
+            donor_id: parse_account_id(filter.donor_id)?,
+            pot_id: parse_account_id(filter.pot_id)?,
+            event_type: filter.event_type,
+        };
+        let (sender, receiver) = mpsc::channel(SUBSCRIBER_BUFFER);
+        let Some(from_block) = request.from_block else {
+            let (_, live) = self.feed.subscribe(None);
+            tokio::spawn(async move { tail(Vec::new(), live, &filter, None, &sender).await });
+            return Ok(Response::new(ReceiverStream::new(receiver)));
+        };
+        let store = Arc::clone(&self.store);
+        let feed = self.feed.clone();
+        tokio::spawn(async move {
+            match catch_up(&*store, &feed, &filter, from_block, &sender).await {
+                Ok(Some((missed, live, sent_up_to))) => {
+                    tail(missed, live, &filter, Some(sent_up_to), &sender).await
+                }
+                // Client disconnected
+                Ok(None) => (),
+                Err(status) => {
+                    let _ = sender.send(Err(status)).await;
+                }
+            }
+        });
+        Ok(Response::new(ReceiverStream::new(receiver)))
+    }
+
+    async fn get_donation(
+        &self,
+        request: Request<proto::GetDonationRequest>,
+    ) -> Result<Response<proto::Event>, Status> {
+        let request = request.into_inner();
+        let cursor = Cursor {
+            block_height: request.block_height,
+            receipt_id: request
+                .receipt_id
+                .parse()
+                .map_err(|err| Status::invalid_argument(format!("Invalid receipt id: {err}")))?,
+            donation_id: request.donation_id,
+        };
+        match self.store.donation(cursor).await {
+            Ok(Some(donation)) => Ok(Response::new(event_to_proto(
+                &donation.event,
+                &donation.context,
+            ))),
+            Ok(None) => Err(Status::not_found("Donation not found")),
+            Err(err) => Err(Status::internal(format!("{err:#}"))),
+        }
+    }
+}
+
+/// Backlog events, the live receiver, and the height up to which events were sent
+type LiveTail = (
+    Vec<Arc<LiveEvent>>,
+    broadcast::Receiver<Arc<LiveEvent>>,
+    BlockHeight,
+);
+
+/// Replays stored events from `from_block` onwards, then subscribes to the live
+/// feed. The live feed is subscribed to only once the replay has caught up with the
+/// store, so that a long replay doesn't overflow the broadcast channel. Events
+/// stored while subscribing are replayed once more, and events not stored yet are
+/// taken from the feed backlog. Returns `None` if the client disconnected.
+async fn catch_up(
+    store: &dyn DonationStore,
+    feed: &LiveFeed,
+    filter: &LiveFeedFilter,
+    from_block: BlockHeight,
+    sender: &mpsc::Sender<Result<proto::Event, Status>>,
+) -> Result<Option<LiveTail>, Status> {
+    let filter = DonationFilter {
+        project_id: filter.project_id.clone(),
+        donor_id: filter.donor_id.clone(),
+        pot_id: filter.pot_id.clone(),
+        event_type: filter.event_type.clone(),
+        from_block: Some(from_block),
+    };
+    let Some(last) = replay(store, &filter, None, sender).await? else {
+        return Ok(None);
+    };
+    let since_block = last
+        .map(|cursor| cursor.block_height)
+        .unwrap_or(from_block.saturating_sub(1));
+    let (missed, live) = feed.subscribe(Some(since_block));
+    let Some(last) = replay(store, &filter, last, sender).await? else {
+        return Ok(None);
+    };
+    let sent_up_to = last
+        .map(|cursor| cursor.block_height)
+        .unwrap_or(from_block.saturating_sub(1));
+    Ok(Some((missed, live, sent_up_to)))
+}
+
+/// Sends stored events after `after` until there are none left. Returns the cursor
+/// of the last event sent (or `after` if none were), or `None` if the client
+/// disconnected.
+async fn replay(
+    store: &dyn DonationStore,
+    filter: &DonationFilter,
+    mut after: Option<Cursor>,
+    sender: &mpsc::Sender<Result<proto::Event, Status>>,
+) -> Result<Option<Option<Cursor>>, Status> {
+    loop {
+        let page = store
+            .donations(filter, Order::Ascending, after, REPLAY_PAGE_SIZE)
+            .await
+            .map_err(|err| Status::internal(format!("{err:#}")))?;
+        for donation in page.donations {
+            after = Some(donation.cursor());
+            let StoredDonation { event, context } = donation;
+            if sender
+                .send(Ok(event_to_proto(&event, &context)))
+                .await
+                .is_err()
+            {
+                return Ok(None);
+            }
+        }
+        if page.next_cursor.is_none() {
+            return Ok(Some(after));
+        }
+    }
+}
+
+/// Sends `missed` backlog events and then live events, skipping blocks up to
+/// `sent_up_to` that were already sent from the store or are before `from_block`
+async fn tail(
+    missed: Vec<Arc<LiveEvent>>,
+    mut live: broadcast::Receiver<Arc<LiveEvent>>,
+    filter: &LiveFeedFilter,
+    sent_up_to: Option<BlockHeight>,
+    sender: &mpsc::Sender<Result<proto::Event, Status>>,
+) {
+    let mut missed = missed.into_iter();
+    loop {
+        let event = match missed.next() {
+            Some(event) => event,
+            None => match live.recv().await {
+                Ok(event) => event,
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    let _ = sender
+                        .send(Err(Status::resource_exhausted(format!(
+                            "Subscriber lagged behind by {skipped} events, resubscribe with from_block"
+                        ))))
+                        .await;
+                    return;
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+        };
+        if sent_up_to.is_some_and(|up_to| event.context.block_height <= up_to)
+            || !filter.matches(&event.event)
+        {
+            continue;
+        }
+        if sender
+            .send(Ok(event_to_proto(&event.event, &event.context)))
+            .await
+            .is_err()
+        {
+            return;
+        }
+    }
+}
+
+pub fn event_to_proto(event: &PotlockEvent, context: &EventContext) -> proto::Event {
+    let event = match event {
+        PotlockEvent::Donation(event) => proto::event::Event::Donation(proto::DonationEvent {
+            donation_id: event.donation_id,
+            donor_id: event.donor_id.to_string(),
+            total_amount: event.total_amount.to_string(),
+            ft_id: event.ft_id.to_string(),
+            message: event.message.clone(),
+            donated_at: event.donated_at,
+            project_id: event.project_id.to_string(),
+            protocol_fee: event.protocol_fee.to_string(),
+            referrer_id: event.referrer_id.as_ref().map(|id| id.to_string()),
+            referrer_fee: event.referrer_fee.map(|fee| fee.to_string()),
+        }),
+        PotlockEvent::PotProjectDonation(event) => {
+            proto::event::Event::PotProjectDonation(proto::PotProjectDonationEvent {
+                donation_id: event.donation_id,
+                pot_id: event.pot_id.to_string(),
+                donor_id: event.donor_id.to_string(),
+                total_amount: event.total_amount.to_string(),
+                net_amount: event.net_amount.to_string(),
+                message: event.message.clone(),
+                donated_at: event.donated_at,
+                project_id: event.project_id.to_string(),
+                referrer_id: event.referrer_id.as_ref().map(|id| id.to_string()),
+                referrer_fee: event.referrer_fee.map(|fee| fee.to_string()),
+                protocol_fee: event.protocol_fee.to_string(),
+                chef_id: event.chef_id.as_ref().map(|id| id.to_string()),
+                chef_fee: event.chef_fee.map(|fee| fee.to_string()),
+            })
+        }
+        PotlockEvent::PotDonation(event) => {
+            proto::event::Event::PotDonation(proto::PotDonationEvent {
+                donation_id: event.donation_id,
+                pot_id: event.pot_id.to_string(),
+                donor_id: event.donor_id.to_string(),
+                total_amount: event.total_amount.to_string(),
+                net_amount: event.net_amount.to_string(),
+                message: event.message.clone(),
+                donated_at: event.donated_at,
+                referrer_id: event.referrer_id.as_ref().map(|id| id.to_string()),
+                referrer_fee: event.referrer_fee.map(|fee| fee.to_string()),
+                protocol_fee: event.protocol_fee.to_string(),
+                chef_id: event.chef_id.as_ref().map(|id| id.to_string()),
+                chef_fee: event.chef_fee.map(|fee| fee.to_string()),
+            })
+        }
+    };
+    proto::Event {
+        event: Some(event),
+        context: Some(proto::EventContext {
+            transaction_id: context.transaction_id.to_string(),
+            receipt_id: context.receipt_id.to_string(),
+            block_height: context.block_height,
+            block_timestamp_nanosec: context.block_timestamp_nanosec.to_string(),
+        }),
+    }
+}
@@ -0,0 +1,147 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+#[cfg(feature = "http")]
+use axum::extract::State;
+#[cfg(feature = "http")]
+use axum::http::StatusCode;
+#[cfg(feature = "http")]
+use axum::routing::get;
+#[cfg(feature = "http")]
+use axum::{Json, Router};
+use inindexer::near_indexer_primitives::types::BlockHeight;
+#[cfg(feature = "http")]
+use serde::Serialize;
+
+use crate::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEventHandler,
+};
+
+/// Shared between [`HealthTrackingHandler`] and the HTTP endpoints. Cheap to clone.
+#[derive(Clone)]
+pub struct Health {
+    state: Arc<Mutex<HealthState>>,
+    max_block_interval: Duration,
+}
+
+struct HealthState {
+    started_at: Instant,
+    last_block: Option<(BlockHeight, Instant)>,
+    last_flush_error: Option<String>,
+}
+
+#[cfg(feature = "http")]
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    last_block_height: Option<BlockHeight>,
+    seconds_since_last_block: f64,
+    last_flush_error: Option<String>,
+}
+
+impl Health {
+    /// The indexer is not ready if no block was processed within `max_block_interval`
+    /// (or since start), or if the last flush failed.
+    pub fn new(max_block_interval: Duration) -> Self {
+        Self {
+            state: Arc::new(Mutex::new(HealthState {
+                started_at: Instant::now(),
+                last_block: None,
+                last_flush_error: None,
+            })),
+            max_block_interval,
+        }
+    }
+
+    /// A block that failed to flush is not counted as processed
+    fn record_flush(&self, block_height: BlockHeight, result: &Result<(), anyhow::Error>) {
+        let mut state = self.state.lock().unwrap();
+        match result {
+            Ok(()) => {
+                state.last_block = Some((block_height, Instant::now()));
+                state.last_flush_error = None;
+            }
+            Err(err) => {
+                state.last_flush_error = Some(format!("Block {block_height}: {err:#}"));
+            }
+        }
+    }
+
+    #[cfg(feature = "http")]
+    fn readiness(&self) -> Readiness {
+        let state = self.state.lock().unwrap();
+        let since_last_block = state
+            .last_block
+            .map_or(state.started_at, |(_, at)| at)
+            .elapsed();
+        Readiness {
+            ready: since_last_block <= self.max_block_interval && state.last_flush_error.is_none(),
+            last_block_height: state.last_block.map(|(height, _)| height),
+            seconds_since_last_block: since_last_block.as_secs_f64(),
+            last_flush_error: state.last_flush_error.clone(),
+        }
+    }
+}
+
+/// Records the time and result of every flush of the inner handler to [`Health`]
+pub struct HealthTrackingHandler<T: PotlockEventHandler> {
+    inner: T,
+    health: Health,
+}
+
+impl<T: PotlockEventHandler> HealthTrackingHandler<T> {
+    pub fn new(inner: T, health: Health) -> Self {
+        Self { inner, health }
+    }
+
+    pub fn inner(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[async_trait]
+impl<T: PotlockEventHandler> PotlockEventHandler for HealthTrackingHandler<T> {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        self.inner.handle_donation(event, context).await;
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        self.inner.handle_pot_project_donation(event, context).await;
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        self.inner.handle_pot_donation(event, context).await;
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        let result = self.inner.flush_events(block_height).await;
+        self.health.record_flush(block_height, &result);
+        result
+    }
+}
+
+/// `GET /healthz` always succeeds while the process is responsive, `GET /readyz`
+/// returns 503 if the indexer is stuck or failing to flush events.
+#[cfg(feature = "http")]
+pub fn router(health: Health) -> Router {
+    Router::new()
+        .route("/healthz", get(|| async { "ok" }))
+        .route("/readyz", get(readyz))
+        .with_state(health)
+}
+
+#[cfg(feature = "http")]
+async fn readyz(State(health): State<Health>) -> (StatusCode, Json<Readiness>) {
+    let readiness = health.readiness();
+    let status = if readiness.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(readiness))
+}
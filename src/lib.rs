@@ -1,7 +1,35 @@
+pub mod aggregating_handler;
+#[cfg(feature = "http")]
+pub mod api;
+pub mod backfill;
+pub mod config;
+#[cfg(feature = "export")]
+pub mod export_handler;
+pub mod file_provider;
+pub mod filter;
+#[cfg(feature = "graphql")]
+pub mod graphql;
+#[cfg(feature = "grpc")]
+pub mod grpc;
+pub mod health;
+pub mod live_feed;
+pub mod metrics;
 pub mod redis_handler;
+pub mod shutdown;
+pub mod spill;
+#[cfg(feature = "sqlite")]
+pub mod sqlite_handler;
+pub mod stdout_handler;
+pub mod storage;
+#[cfg(any(test, feature = "testing"))]
+pub mod synthetic;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
 #[cfg(test)]
 mod tests;
 
+use std::sync::LazyLock;
+
 use async_trait::async_trait;
 use inindexer::near_indexer_primitives::types::AccountId;
 use inindexer::near_indexer_primitives::types::BlockHeight;
@@ -16,7 +44,10 @@ use inindexer::near_utils::FtBalance;
 use inindexer::Indexer;
 use inindexer::TransactionReceipt;
 use inindexer::{CompleteTransaction, IncompleteTransaction};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+
+/// FT id of NEAR donations. Pots only accept NEAR.
+pub static NEAR_FT_ID: LazyLock<AccountId> = LazyLock::new(|| "near".parse().unwrap());
 
 pub type DonationId = u64;
 pub type ProjectId = AccountId;
@@ -32,11 +63,184 @@ pub trait PotlockEventHandler: Send + Sync {
     );
     async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext);
 
-    /// Called after each block
-    async fn flush_events(&mut self, block_height: BlockHeight);
+    /// Called after each block. An error stops the indexer: [`PotlockIndexer`] returns
+    /// it and refuses to process any later block, so events are never silently lost
+    /// while indexing continues. A handler may still keep unwritten events and retry
+    /// them on the next flush.
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error>;
 }
 
-pub struct PotlockIndexer<T: PotlockEventHandler>(pub T);
+/// Sends every event to both handlers. Both handlers are flushed even if one of them fails.
+#[async_trait]
+impl<A: PotlockEventHandler, B: PotlockEventHandler> PotlockEventHandler for (A, B) {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        self.0.handle_donation(event.clone(), context.clone()).await;
+        self.1.handle_donation(event, context).await;
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        self.0
+            .handle_pot_project_donation(event.clone(), context.clone())
+            .await;
+        self.1.handle_pot_project_donation(event, context).await;
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        self.0
+            .handle_pot_donation(event.clone(), context.clone())
+            .await;
+        self.1.handle_pot_donation(event, context).await;
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        let result = self.0.flush_events(block_height).await;
+        self.1.flush_events(block_height).await?;
+        result
+    }
+}
+
+/// Optional handler, does nothing if `None`
+#[async_trait]
+impl<T: PotlockEventHandler> PotlockEventHandler for Option<T> {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        if let Some(handler) = self {
+            handler.handle_donation(event, context).await;
+        }
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        if let Some(handler) = self {
+            handler.handle_pot_project_donation(event, context).await;
+        }
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        if let Some(handler) = self {
+            handler.handle_pot_donation(event, context).await;
+        }
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        if let Some(handler) = self {
+            handler.flush_events(block_height).await
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Stands in for a handler whose cargo feature is disabled. It can't be created,
+/// so `Option<Disabled>` is always `None`.
+pub enum Disabled {}
+
+#[async_trait]
+impl PotlockEventHandler for Disabled {
+    async fn handle_donation(&mut self, _event: DonationEvent, _context: EventContext) {
+        match *self {}
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        _event: PotProjectDonationEvent,
+        _context: EventContext,
+    ) {
+        match *self {}
+    }
+
+    async fn handle_pot_donation(&mut self, _event: PotDonationEvent, _context: EventContext) {
+        match *self {}
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        match *self {}
+    }
+}
+
+/// Created with [`PotlockIndexer::new`] or [`PotlockIndexer::with_contracts`]. The
+/// handler is public to inspect it after a run.
+///
+/// Run it with `stop_on_error` in [`inindexer::IndexerOptions`] to exit on the first
+/// failed flush, otherwise every following block fails too.
+pub struct PotlockIndexer<T: PotlockEventHandler>(
+    pub T,
+    PotlockContracts,
+    /// Block whose flush failed
+    Option<BlockHeight>,
+    /// Records metrics, see [`PotlockIndexer::without_metrics`]
+    bool,
+);
+
+impl<T: PotlockEventHandler> PotlockIndexer<T> {
+    /// Indexes the mainnet contracts
+    pub fn new(handler: T) -> Self {
+        Self::with_contracts(handler, PotlockContracts::mainnet())
+    }
+
+    pub fn with_contracts(handler: T, contracts: PotlockContracts) -> Self {
+        Self(handler, contracts, None, true)
+    }
+
+    /// Doesn't record metrics, for indexers that run alongside others, like the
+    /// chunk indexers of a parallel backfill. The block height gauge would jump
+    /// between their blocks, and events in blocks they share would be counted twice.
+    pub fn without_metrics(mut self) -> Self {
+        self.3 = false;
+        self
+    }
+
+    pub fn contracts(&self) -> &PotlockContracts {
+        &self.1
+    }
+
+    fn ensure_not_failed(&self) -> Result<(), anyhow::Error> {
+        match self.2 {
+            Some(block_height) => {
+                anyhow::bail!("Not indexing after block {block_height}, which failed to flush")
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Accounts of the Potlock contracts to index
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PotlockContracts {
+    /// Direct donations contract
+    pub donation: AccountId,
+    /// Pots are subaccounts of this account
+    pub pot_factory: AccountId,
+}
+
+impl PotlockContracts {
+    pub fn mainnet() -> Self {
+        Self {
+            donation: "donate.potlock.near".parse().unwrap(),
+            pot_factory: "v1.potfactory.potlock.near".parse().unwrap(),
+        }
+    }
+
+    pub fn testnet() -> Self {
+        Self {
+            donation: "donate.potlock.testnet".parse().unwrap(),
+            pot_factory: "v1.potfactory.potlock.testnet".parse().unwrap(),
+        }
+    }
+
+    pub fn is_pot(&self, account_id: &AccountId) -> bool {
+        account_id
+            .as_str()
+            .strip_suffix(self.pot_factory.as_str())
+            .is_some_and(|pot| pot.ends_with('.'))
+    }
+}
 
 #[async_trait]
 impl<T: PotlockEventHandler + 'static> Indexer for PotlockIndexer<T> {
@@ -47,78 +251,97 @@ impl<T: PotlockEventHandler + 'static> Indexer for PotlockIndexer<T> {
         tx: &CompleteTransaction,
         block: &StreamerMessage,
     ) -> Result<(), Self::Error> {
+        self.ensure_not_failed()?;
         for receipt in tx.receipts.iter() {
             if let ReceiptEnumView::Action { actions, .. } = &receipt.receipt.receipt.receipt {
                 for action in actions.iter() {
                     if let ActionView::FunctionCall { method_name, .. } = action {
                         if method_name == "donate"
-                            && receipt
-                                .receipt
-                                .receipt
-                                .receiver_id
-                                .as_str()
-                                .ends_with(".v1.potfactory.potlock.near")
+                            && self.1.is_pot(&receipt.receipt.receipt.receiver_id)
                         {
                             if let Some(result) = get_result(receipt, tx) {
-                                if let Ok(donation) =
-                                    serde_json::from_slice::<PotDonationExternal>(result)
-                                {
-                                    let context = EventContext {
-                                        transaction_id: tx.transaction.transaction.hash,
-                                        receipt_id: receipt.receipt.receipt.receipt_id,
-                                        block_height: block.block.header.height,
-                                        block_timestamp_nanosec: block
-                                            .block
-                                            .header
-                                            .timestamp_nanosec
-                                            as u128,
+                                let donation =
+                                    match serde_json::from_slice::<PotDonationExternal>(result) {
+                                        Ok(donation) => donation,
+                                        Err(err) => {
+                                            log::warn!(
+                                            "Failed to decode pot donation in receipt {}: {err}",
+                                            receipt.receipt.receipt.receipt_id
+                                        );
+                                            if self.3 {
+                                                metrics::record_decode_failure(
+                                                    receipt.receipt.receipt.receiver_id.as_str(),
+                                                );
+                                            }
+                                            continue;
+                                        }
                                     };
-                                    if let Some(project_id) = donation.project_id {
-                                        let event = PotProjectDonationEvent {
-                                            donation_id: donation.id,
-                                            pot_id: receipt.receipt.receipt.receiver_id.clone(),
-                                            donor_id: donation.donor_id,
-                                            total_amount: donation.total_amount,
-                                            net_amount: donation.net_amount,
-                                            message: donation.message.and_then(|msg| {
-                                                if msg.is_empty() {
-                                                    None
-                                                } else {
-                                                    Some(msg)
-                                                }
-                                            }),
-                                            donated_at: donation.donated_at,
-                                            project_id,
-                                            referrer_id: donation.referrer_id,
-                                            referrer_fee: donation.referrer_fee,
-                                            protocol_fee: donation.protocol_fee,
-                                            chef_id: donation.chef_id,
-                                            chef_fee: donation.chef_fee,
-                                        };
-                                        self.0.handle_pot_project_donation(event, context).await;
-                                    } else {
-                                        let event = PotDonationEvent {
-                                            donation_id: donation.id,
-                                            pot_id: receipt.receipt.receipt.receiver_id.clone(),
-                                            donor_id: donation.donor_id,
-                                            total_amount: donation.total_amount,
-                                            net_amount: donation.net_amount,
-                                            message: donation.message.and_then(|msg| {
-                                                if msg.is_empty() {
-                                                    None
-                                                } else {
-                                                    Some(msg)
-                                                }
-                                            }),
-                                            donated_at: donation.donated_at,
-                                            referrer_id: donation.referrer_id,
-                                            referrer_fee: donation.referrer_fee,
-                                            protocol_fee: donation.protocol_fee,
-                                            chef_id: donation.chef_id,
-                                            chef_fee: donation.chef_fee,
-                                        };
-                                        self.0.handle_pot_donation(event, context).await;
+                                let context = EventContext {
+                                    transaction_id: tx.transaction.transaction.hash,
+                                    receipt_id: receipt.receipt.receipt.receipt_id,
+                                    block_height: block.block.header.height,
+                                    block_timestamp_nanosec: block.block.header.timestamp_nanosec
+                                        as u128,
+                                };
+                                if let Some(project_id) = donation.project_id {
+                                    let event = PotProjectDonationEvent {
+                                        donation_id: donation.id,
+                                        pot_id: receipt.receipt.receipt.receiver_id.clone(),
+                                        donor_id: donation.donor_id,
+                                        total_amount: donation.total_amount,
+                                        net_amount: donation.net_amount,
+                                        message: donation.message.and_then(|msg| {
+                                            if msg.is_empty() {
+                                                None
+                                            } else {
+                                                Some(msg)
+                                            }
+                                        }),
+                                        donated_at: donation.donated_at,
+                                        project_id,
+                                        referrer_id: donation.referrer_id,
+                                        referrer_fee: donation.referrer_fee,
+                                        protocol_fee: donation.protocol_fee,
+                                        chef_id: donation.chef_id,
+                                        chef_fee: donation.chef_fee,
+                                    };
+                                    if self.3 {
+                                        metrics::record_event(
+                                            "potlock_pot_project_donation",
+                                            "near",
+                                            event.total_amount,
+                                        );
+                                    }
+                                    self.0.handle_pot_project_donation(event, context).await;
+                                } else {
+                                    let event = PotDonationEvent {
+                                        donation_id: donation.id,
+                                        pot_id: receipt.receipt.receipt.receiver_id.clone(),
+                                        donor_id: donation.donor_id,
+                                        total_amount: donation.total_amount,
+                                        net_amount: donation.net_amount,
+                                        message: donation.message.and_then(|msg| {
+                                            if msg.is_empty() {
+                                                None
+                                            } else {
+                                                Some(msg)
+                                            }
+                                        }),
+                                        donated_at: donation.donated_at,
+                                        referrer_id: donation.referrer_id,
+                                        referrer_fee: donation.referrer_fee,
+                                        protocol_fee: donation.protocol_fee,
+                                        chef_id: donation.chef_id,
+                                        chef_fee: donation.chef_fee,
+                                    };
+                                    if self.3 {
+                                        metrics::record_event(
+                                            "potlock_pot_donation",
+                                            "near",
+                                            event.total_amount,
+                                        );
                                     }
+                                    self.0.handle_pot_donation(event, context).await;
                                 }
                             }
                         }
@@ -136,11 +359,34 @@ impl<T: PotlockEventHandler + 'static> Indexer for PotlockIndexer<T> {
         tx: &IncompleteTransaction,
         _block: &StreamerMessage,
     ) -> Result<(), Self::Error> {
-        if receipt.receipt.receipt.receiver_id == "donate.potlock.near" {
+        self.ensure_not_failed()?;
+        // Logs of a failed receipt are kept, but its donations were reverted
+        if receipt.receipt.receipt.receiver_id == self.1.donation
+            && !matches!(
+                receipt.receipt.execution_outcome.outcome.status,
+                ExecutionStatusView::Failure(_)
+            )
+        {
             for log in receipt.receipt.execution_outcome.outcome.logs.iter() {
-                if let Ok(log) = EventLogData::<Vec<DonationLogWrapper>>::deserialize(log) {
+                if let Ok(log) = EventLogData::<serde_json::Value>::deserialize(log) {
                     if log.event == "donation" && log.standard == "potlock" {
-                        for donation in log.data {
+                        let donations =
+                            match serde_json::from_value::<Vec<DonationLogWrapper>>(log.data) {
+                                Ok(donations) => donations,
+                                Err(err) => {
+                                    log::warn!(
+                                        "Failed to decode donation log in receipt {}: {err}",
+                                        receipt.receipt.receipt.receipt_id
+                                    );
+                                    if self.3 {
+                                        metrics::record_decode_failure(
+                                            receipt.receipt.receipt.receiver_id.as_str(),
+                                        );
+                                    }
+                                    continue;
+                                }
+                            };
+                        for donation in donations {
                             let donation = donation.donation;
                             let event = DonationEvent {
                                 donation_id: donation.id,
@@ -166,6 +412,13 @@ impl<T: PotlockEventHandler + 'static> Indexer for PotlockIndexer<T> {
                                 block_height: receipt.block_height,
                                 block_timestamp_nanosec: receipt.block_timestamp_nanosec,
                             };
+                            if self.3 {
+                                metrics::record_event(
+                                    "potlock_donation",
+                                    event.ft_id.as_str(),
+                                    event.total_amount,
+                                );
+                            }
                             self.0.handle_donation(event, context).await;
                         }
                     }
@@ -177,7 +430,15 @@ impl<T: PotlockEventHandler + 'static> Indexer for PotlockIndexer<T> {
     }
 
     async fn process_block_end(&mut self, block: &StreamerMessage) -> Result<(), Self::Error> {
-        self.0.flush_events(block.block.header.height).await;
+        self.ensure_not_failed()?;
+        let block_height = block.block.header.height;
+        if let Err(err) = self.0.flush_events(block_height).await {
+            self.2 = Some(block_height);
+            return Err(err.context(format!("Failed to flush block {block_height}")));
+        }
+        if self.3 {
+            metrics::record_block(block_height, block.block.header.timestamp_nanosec);
+        }
         Ok(())
     }
 }
@@ -251,13 +512,14 @@ pub struct PotDonationExternal {
     pub chef_fee: Option<u128>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct DonationEvent {
     /// ID of the donation
     pub donation_id: DonationId,
     /// ID of the donor
     pub donor_id: AccountId,
     /// Amount donated
+    #[serde(with = "dec_format")]
     pub total_amount: FtBalance,
     /// FT id (e.g. "near")
     pub ft_id: AccountId,
@@ -268,14 +530,16 @@ pub struct DonationEvent {
     /// ID of the project receiving the donation
     pub project_id: AccountId,
     /// Protocol fee
+    #[serde(with = "dec_format")]
     pub protocol_fee: FtBalance,
     /// Referrer ID
     pub referrer_id: Option<AccountId>,
     /// Referrer fee
+    #[serde(with = "dec_format")]
     pub referrer_fee: Option<FtBalance>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PotProjectDonationEvent {
     /// ID of the donation
     pub donation_id: DonationId,
@@ -284,8 +548,10 @@ pub struct PotProjectDonationEvent {
     /// ID of the donor
     pub donor_id: AccountId,
     /// Amount donated
+    #[serde(with = "dec_format")]
     pub total_amount: FtBalance,
     /// Amount after all fees/expenses (incl. storage)
+    #[serde(with = "dec_format")]
     pub net_amount: FtBalance,
     /// Optional message from the donor
     pub message: Option<String>,
@@ -296,16 +562,19 @@ pub struct PotProjectDonationEvent {
     /// Referrer ID
     pub referrer_id: Option<AccountId>,
     /// Referrer fee
+    #[serde(with = "dec_format")]
     pub referrer_fee: Option<FtBalance>,
     /// Protocol fee
+    #[serde(with = "dec_format")]
     pub protocol_fee: FtBalance,
     /// Chef ID
     pub chef_id: Option<AccountId>,
     /// Chef fee
+    #[serde(with = "dec_format")]
     pub chef_fee: Option<FtBalance>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct PotDonationEvent {
     /// ID of the donation
     pub donation_id: DonationId,
@@ -314,8 +583,10 @@ pub struct PotDonationEvent {
     /// ID of the donor
     pub donor_id: AccountId,
     /// Amount donated
+    #[serde(with = "dec_format")]
     pub total_amount: FtBalance,
     /// Amount after all fees/expenses (incl. storage)
+    #[serde(with = "dec_format")]
     pub net_amount: FtBalance,
     /// Optional message from the donor
     pub message: Option<String>,
@@ -324,25 +595,119 @@ pub struct PotDonationEvent {
     /// Referrer ID
     pub referrer_id: Option<AccountId>,
     /// Referrer fee
+    #[serde(with = "dec_format")]
     pub referrer_fee: Option<FtBalance>,
     /// Protocol fee
+    #[serde(with = "dec_format")]
     pub protocol_fee: FtBalance,
     /// Chef ID
     pub chef_id: Option<AccountId>,
     /// Chef fee
+    #[serde(with = "dec_format")]
     pub chef_fee: Option<FtBalance>,
 }
 
-#[derive(Clone, Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct EventContext {
     pub transaction_id: CryptoHash,
     pub receipt_id: CryptoHash,
     /// In the event of pot or pot project donation, represents the block when the
     /// transaction was completed, not the donation receipt
     pub block_height: BlockHeight,
+    #[serde(with = "dec_format")]
     pub block_timestamp_nanosec: u128,
 }
 
+/// Any of the events emitted by [`PotlockIndexer`]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event_type", content = "event")]
+pub enum PotlockEvent {
+    #[serde(rename = "potlock_donation")]
+    Donation(DonationEvent),
+    #[serde(rename = "potlock_pot_project_donation")]
+    PotProjectDonation(PotProjectDonationEvent),
+    #[serde(rename = "potlock_pot_donation")]
+    PotDonation(PotDonationEvent),
+}
+
+impl PotlockEvent {
+    /// Same as the name of the Redis stream this event is pushed to
+    pub fn event_type(&self) -> &'static str {
+        match self {
+            PotlockEvent::Donation(_) => "potlock_donation",
+            PotlockEvent::PotProjectDonation(_) => "potlock_pot_project_donation",
+            PotlockEvent::PotDonation(_) => "potlock_pot_donation",
+        }
+    }
+
+    pub fn donation_id(&self) -> DonationId {
+        match self {
+            PotlockEvent::Donation(event) => event.donation_id,
+            PotlockEvent::PotProjectDonation(event) => event.donation_id,
+            PotlockEvent::PotDonation(event) => event.donation_id,
+        }
+    }
+
+    pub fn donor_id(&self) -> &AccountId {
+        match self {
+            PotlockEvent::Donation(event) => &event.donor_id,
+            PotlockEvent::PotProjectDonation(event) => &event.donor_id,
+            PotlockEvent::PotDonation(event) => &event.donor_id,
+        }
+    }
+
+    /// `None` for direct donations
+    pub fn pot_id(&self) -> Option<&AccountId> {
+        match self {
+            PotlockEvent::Donation(_) => None,
+            PotlockEvent::PotProjectDonation(event) => Some(&event.pot_id),
+            PotlockEvent::PotDonation(event) => Some(&event.pot_id),
+        }
+    }
+
+    /// `None` for pot (matching pool) donations
+    pub fn project_id(&self) -> Option<&AccountId> {
+        match self {
+            PotlockEvent::Donation(event) => Some(&event.project_id),
+            PotlockEvent::PotProjectDonation(event) => Some(&event.project_id),
+            PotlockEvent::PotDonation(_) => None,
+        }
+    }
+
+    /// FT id of the donated token. Pots only accept NEAR.
+    pub fn ft_id(&self) -> AccountId {
+        match self {
+            PotlockEvent::Donation(event) => event.ft_id.clone(),
+            PotlockEvent::PotProjectDonation(_) | PotlockEvent::PotDonation(_) => {
+                NEAR_FT_ID.clone()
+            }
+        }
+    }
+
+    pub fn total_amount(&self) -> FtBalance {
+        match self {
+            PotlockEvent::Donation(event) => event.total_amount,
+            PotlockEvent::PotProjectDonation(event) => event.total_amount,
+            PotlockEvent::PotDonation(event) => event.total_amount,
+        }
+    }
+
+    /// Calls the corresponding `handle_*` method of `handler`
+    pub async fn send_to<H: PotlockEventHandler + ?Sized>(
+        self,
+        handler: &mut H,
+        context: EventContext,
+    ) {
+        match self {
+            PotlockEvent::Donation(event) => handler.handle_donation(event, context).await,
+            PotlockEvent::PotProjectDonation(event) => {
+                handler.handle_pot_project_donation(event, context).await
+            }
+            PotlockEvent::PotDonation(event) => handler.handle_pot_donation(event, context).await,
+        }
+    }
+}
+
 fn get_result<'a>(
     receipt: &'a TransactionReceipt,
     tx: &'a CompleteTransaction,
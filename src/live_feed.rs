@@ -0,0 +1,249 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+#[cfg(feature = "http")]
+use axum::extract::ws::{Message, WebSocket, WebSocketUpgrade};
+#[cfg(feature = "http")]
+use axum::extract::{Query, State};
+#[cfg(feature = "http")]
+use axum::response::Response;
+#[cfg(feature = "http")]
+use axum::routing::get;
+#[cfg(feature = "http")]
+use axum::Router;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEvent,
+    PotlockEventHandler,
+};
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct LiveEvent {
+    #[serde(flatten)]
+    pub event: PotlockEvent,
+    pub context: EventContext,
+}
+
+/// Broadcasts events to live subscribers and keeps the last `backlog_size` events
+/// for clients that reconnect. Cheap to clone.
+#[derive(Clone)]
+pub struct LiveFeed {
+    sender: broadcast::Sender<Arc<LiveEvent>>,
+    backlog: Arc<Mutex<VecDeque<Arc<LiveEvent>>>>,
+    backlog_size: usize,
+}
+
+impl LiveFeed {
+    pub fn new(backlog_size: usize) -> Self {
+        Self {
+            sender: broadcast::channel(backlog_size.max(1)).0,
+            backlog: Arc::new(Mutex::new(VecDeque::with_capacity(backlog_size))),
+            backlog_size,
+        }
+    }
+
+    pub fn publish(&self, events: impl IntoIterator<Item = LiveEvent>) {
+        let mut backlog = self.backlog.lock().unwrap();
+        for event in events {
+            let event = Arc::new(event);
+            if backlog.len() == self.backlog_size {
+                backlog.pop_front();
+            }
+            if self.backlog_size != 0 {
+                backlog.push_back(Arc::clone(&event));
+            }
+            // Err means there are no subscribers at the moment
+            let _ = self.sender.send(event);
+        }
+    }
+
+    /// Returns backlog events from blocks after `since_block` and a receiver for all
+    /// events published after them.
+    pub fn subscribe(
+        &self,
+        since_block: Option<BlockHeight>,
+    ) -> (Vec<Arc<LiveEvent>>, broadcast::Receiver<Arc<LiveEvent>>) {
+        // Holding the lock while subscribing, so that no event is missed or sent twice
+        let backlog = self.backlog.lock().unwrap();
+        let receiver = self.sender.subscribe();
+        let missed = match since_block {
+            Some(since_block) => backlog
+                .iter()
+                .filter(|event| event.context.block_height > since_block)
+                .cloned()
+                .collect(),
+            None => Vec::new(),
+        };
+        (missed, receiver)
+    }
+}
+
+/// Publishes events to a [`LiveFeed`] at the end of each block
+pub struct LiveFeedHandler {
+    feed: LiveFeed,
+    pending: Vec<LiveEvent>,
+}
+
+impl LiveFeedHandler {
+    pub fn new(feed: LiveFeed) -> Self {
+        Self {
+            feed,
+            pending: Vec::new(),
+        }
+    }
+}
+
+#[async_trait]
+impl PotlockEventHandler for LiveFeedHandler {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        self.pending.push(LiveEvent {
+            event: PotlockEvent::Donation(event),
+            context,
+        });
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        self.pending.push(LiveEvent {
+            event: PotlockEvent::PotProjectDonation(event),
+            context,
+        });
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        self.pending.push(LiveEvent {
+            event: PotlockEvent::PotDonation(event),
+            context,
+        });
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        self.feed.publish(self.pending.drain(..));
+        Ok(())
+    }
+}
+
+/// Subscription filter. All set fields must match.
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct LiveFeedFilter {
+    pub pot_id: Option<AccountId>,
+    pub project_id: Option<AccountId>,
+    pub donor_id: Option<AccountId>,
+    /// `potlock_donation`, `potlock_pot_project_donation`, or `potlock_pot_donation`
+    pub event_type: Option<String>,
+}
+
+impl LiveFeedFilter {
+    pub fn matches(&self, event: &PotlockEvent) -> bool {
+        self.pot_id
+            .as_ref()
+            .is_none_or(|pot_id| event.pot_id() == Some(pot_id))
+            && self
+                .project_id
+                .as_ref()
+                .is_none_or(|project_id| event.project_id() == Some(project_id))
+            && self
+                .donor_id
+                .as_ref()
+                .is_none_or(|donor_id| event.donor_id() == donor_id)
+            && self
+                .event_type
+                .as_ref()
+                .is_none_or(|event_type| event.event_type() == event_type)
+    }
+}
+
+#[cfg(feature = "http")]
+#[derive(Deserialize)]
+struct ConnectParams {
+    pot_id: Option<AccountId>,
+    project_id: Option<AccountId>,
+    donor_id: Option<AccountId>,
+    event_type: Option<String>,
+    /// Last block the client has received events from, to replay missed events
+    since_block: Option<BlockHeight>,
+}
+
+/// Serves `GET /events` WebSocket endpoint.
+///
+/// The initial filter is taken from query parameters (`pot_id`, `project_id`,
+/// `donor_id`, `event_type`), and can be replaced at any time by sending the filter
+/// as a JSON text message. Reconnecting clients can pass `since_block` to receive
+/// events they missed, if they're still in the backlog.
+#[cfg(feature = "http")]
+pub async fn serve(feed: LiveFeed, listener: tokio::net::TcpListener) -> Result<(), anyhow::Error> {
+    let app = Router::new()
+        .route("/events", get(connect))
+        .with_state(feed);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+#[cfg(feature = "http")]
+async fn connect(
+    ws: WebSocketUpgrade,
+    Query(params): Query<ConnectParams>,
+    State(feed): State<LiveFeed>,
+) -> Response {
+    ws.on_upgrade(move |socket| handle_socket(socket, feed, params))
+}
+
+#[cfg(feature = "http")]
+async fn handle_socket(mut socket: WebSocket, feed: LiveFeed, params: ConnectParams) {
+    let (missed, mut receiver) = feed.subscribe(params.since_block);
+    let mut filter = LiveFeedFilter {
+        pot_id: params.pot_id,
+        project_id: params.project_id,
+        donor_id: params.donor_id,
+        event_type: params.event_type,
+    };
+    for event in missed {
+        if filter.matches(&event.event) && send_event(&mut socket, &event).await.is_err() {
+            return;
+        }
+    }
+    loop {
+        tokio::select! {
+            event = receiver.recv() => match event {
+                Ok(event) => {
+                    if filter.matches(&event.event) && send_event(&mut socket, &event).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                    let message = serde_json::json!({ "error": "lagged", "skipped": skipped });
+                    if socket.send(Message::Text(message.to_string())).await.is_err() {
+                        return;
+                    }
+                }
+                Err(broadcast::error::RecvError::Closed) => return,
+            },
+            message = socket.recv() => match message {
+                Some(Ok(Message::Text(text))) => match serde_json::from_str(&text) {
+                    Ok(new_filter) => filter = new_filter,
+                    Err(err) => {
+                        let message = serde_json::json!({ "error": format!("Invalid filter: {err}") });
+                        if socket.send(Message::Text(message.to_string())).await.is_err() {
+                            return;
+                        }
+                    }
+                },
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => return,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+#[cfg(feature = "http")]
+async fn send_event(socket: &mut WebSocket, event: &LiveEvent) -> Result<(), axum::Error> {
+    let json = serde_json::to_string(event).expect("Failed to serialize event");
+    socket.send(Message::Text(json)).await
+}
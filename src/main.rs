@@ -1,61 +1,882 @@
-use inindexer::neardata::NeardataProvider;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 
+use anyhow::Context;
+use async_trait::async_trait;
+use clap::{Args, Parser, Subcommand};
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::neardata::NeardataProvider;
 use inindexer::{
     run_indexer, AutoContinue, BlockRange, IndexerOptions, PreprocessTransactionsSettings,
 };
-use potlock_indexer::redis_handler::PushToRedisStream;
+use potlock_indexer::aggregating_handler::{AggregatingHandler, RedisAggregateSink};
+use potlock_indexer::backfill::{backfill, BackfillOptions, BufferingHandler};
+use potlock_indexer::config::{
+    Config, ExportFormat, Network, Provider, RedisConfig, ServerConfig, Sink,
+};
+#[cfg(feature = "export")]
+use potlock_indexer::export_handler::ExportToFiles;
+use potlock_indexer::file_provider::{download, FileProvider};
+use potlock_indexer::filter::FilteringHandler;
+#[cfg(feature = "grpc")]
+use potlock_indexer::grpc::PotlockService;
+use potlock_indexer::health::{Health, HealthTrackingHandler};
+use potlock_indexer::live_feed::{LiveFeed, LiveFeedHandler};
+use potlock_indexer::redis_handler::{PushToRedisStream, Retention};
+use potlock_indexer::shutdown::{Shutdown, ShutdownHandler};
+#[cfg(feature = "sqlite")]
+use potlock_indexer::sqlite_handler::StoreInSqlite;
+use potlock_indexer::stdout_handler::{PrintFormat, PrintToStdout};
+use potlock_indexer::storage::DonationStore;
+use potlock_indexer::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEventHandler,
+    PotlockIndexer,
+};
 use redis::aio::ConnectionManager;
+use redis::AsyncCommands;
+
+/// Indexes Potlock donations into Redis streams and other sinks
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    #[command(flatten)]
+    options: Options,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Index new blocks, continuing after the last block written to Redis
+    Run,
+    /// Index a range of blocks and exit. With the redis sink, `--from` must be after
+    /// the last entry of the streams, since earlier entries would be skipped.
+    Backfill {
+        #[arg(long, value_parser = parse_block_height)]
+        from: BlockHeight,
+        /// Exclusive
+        #[arg(long, value_parser = parse_block_height)]
+        to: BlockHeight,
+        /// Number of chunks indexed in parallel. With more than 1, chunks are merged
+        /// into the sinks in block order, and an interrupted backfill resumes from
+        /// `--checkpoint-directory`.
+        #[arg(long, default_value_t = 1)]
+        concurrency: usize,
+        #[arg(long, value_parser = parse_block_height, default_value = "100000")]
+        chunk_size: BlockHeight,
+        #[arg(long, default_value = "backfill-checkpoint")]
+        checkpoint_directory: PathBuf,
+    },
+    /// Index again starting from a block, regardless of the checkpoint. Without
+    /// `--to`, continues with new blocks afterwards.
+    ///
+    /// Redis streams skip events that are not after their last entry, so replaying
+    /// already written blocks doesn't change them. It re-emits events to the other
+    /// sinks: export, stdout, and the live feed write them again. SQLite ignores
+    /// donations it already has, and aggregates ignore blocks up to their last
+    /// snapshot, so neither counts a donation twice.
+    Replay {
+        #[arg(long, value_parser = parse_block_height)]
+        from: BlockHeight,
+        /// Exclusive
+        #[arg(long, value_parser = parse_block_height)]
+        to: Option<BlockHeight>,
+    },
+    /// Print the checkpoint, stream lengths, and spilled blocks
+    Inspect,
+    /// Export a range of blocks to files, without writing to any other sink
+    Export {
+        #[arg(long, value_parser = parse_block_height)]
+        from: BlockHeight,
+        /// Exclusive
+        #[arg(long, value_parser = parse_block_height)]
+        to: BlockHeight,
+        #[arg(long)]
+        directory: PathBuf,
+        #[arg(long, default_value = "parquet")]
+        format: ExportFormat,
+    },
+    /// Save a range of blocks from neardata to files, to index them later with
+    /// `--provider files`
+    Download {
+        #[arg(long, value_parser = parse_block_height)]
+        from: BlockHeight,
+        /// Exclusive
+        #[arg(long, value_parser = parse_block_height)]
+        to: BlockHeight,
+        #[arg(long)]
+        directory: PathBuf,
+        /// Write `.json.gz` instead of `.json`
+        #[arg(long)]
+        compress: bool,
+        #[arg(long, default_value_t = 10)]
+        concurrency: usize,
+    },
+}
+
+/// Overrides the configuration file. Unset options keep the value from the file, or
+/// the default.
+#[derive(Args)]
+struct Options {
+    /// TOML or YAML configuration file, see `config.example.toml`
+    #[arg(long, global = true, env = "CONFIG")]
+    config: Option<PathBuf>,
+    /// [default: mainnet]
+    #[arg(long, global = true, env = "NETWORK")]
+    network: Option<Network>,
+    /// Where blocks are read from [default: neardata]
+    #[arg(long, global = true, env = "PROVIDER")]
+    provider: Option<Provider>,
+    /// Only print events to stdout, same as `--sinks stdout`
+    #[arg(long, global = true, env = "DRY_RUN")]
+    dry_run: bool,
+    /// Comma-separated list of sinks to write events to [default: redis]
+    #[arg(long, global = true, env = "SINKS", value_delimiter = ',')]
+    sinks: Option<Vec<Sink>>,
+    /// [default: info]
+    #[arg(long, global = true, env = "LOG_LEVEL")]
+    log_level: Option<log::LevelFilter>,
+    /// [default: donate.potlock.near or donate.potlock.testnet]
+    #[arg(long, global = true, env = "DONATION_CONTRACT")]
+    donation_contract: Option<AccountId>,
+    /// [default: v1.potfactory.potlock.near or v1.potfactory.potlock.testnet]
+    #[arg(long, global = true, env = "POT_FACTORY")]
+    pot_factory: Option<AccountId>,
+    /// [default: 20]
+    #[arg(long, global = true, env = "PREFETCH_BLOCKS")]
+    prefetch_blocks: Option<usize>,
+    /// [default: 20]
+    #[arg(long, global = true, env = "POSTFETCH_BLOCKS")]
+    postfetch_blocks: Option<usize>,
+    /// Required for `--provider files`
+    #[arg(long, global = true, env = "BLOCKS_DIRECTORY")]
+    blocks_directory: Option<PathBuf>,
+    /// How long to wait for the current block to be flushed after SIGINT or SIGTERM
+    /// [default: 30]
+    #[arg(long, global = true, env = "SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+    #[command(flatten)]
+    redis: RedisOptions,
+    #[command(flatten)]
+    sink: SinkOptions,
+    #[command(flatten)]
+    server: ServerOptions,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Redis")]
+struct RedisOptions {
+    #[arg(long, global = true, env = "REDIS_URL")]
+    redis_url: Option<String>,
+    /// Prepended to every key, e.g. `testnet:`
+    #[arg(long, global = true, env = "REDIS_PREFIX")]
+    redis_prefix: Option<String>,
+    /// Approximate number of entries kept in each stream, unless `--redis-retention`
+    /// is set [default: 1000]
+    #[arg(long, global = true, env = "MAX_STREAM_SIZE")]
+    max_stream_size: Option<usize>,
+    /// `unlimited`, a number of entries, or an age like `7d`, for all streams
+    #[arg(long, global = true, env = "REDIS_RETENTION")]
+    redis_retention: Option<Retention>,
+    #[arg(long, global = true, env = "REDIS_RETENTION_DONATION")]
+    redis_retention_donation: Option<Retention>,
+    #[arg(long, global = true, env = "REDIS_RETENTION_POT_PROJECT_DONATION")]
+    redis_retention_pot_project_donation: Option<Retention>,
+    #[arg(long, global = true, env = "REDIS_RETENTION_POT_DONATION")]
+    redis_retention_pot_donation: Option<Retention>,
+    /// File where blocks are kept while Redis is unavailable
+    #[arg(long, global = true, env = "REDIS_SPILL_PATH")]
+    redis_spill_path: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        env = "REDIS_SECONDARY_INDEXES",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    redis_secondary_indexes: Option<bool>,
+    #[arg(
+        long,
+        global = true,
+        env = "REDIS_PUBLISH",
+        num_args = 0..=1,
+        default_missing_value = "true"
+    )]
+    redis_publish: Option<bool>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Sinks")]
+struct SinkOptions {
+    /// Required for the `export` sink
+    #[arg(long, global = true, env = "EXPORT_DIRECTORY")]
+    export_directory: Option<PathBuf>,
+    /// [default: parquet]
+    #[arg(long, global = true, env = "EXPORT_FORMAT")]
+    export_format: Option<ExportFormat>,
+    /// Required for the `sqlite` sink
+    #[arg(long, global = true, env = "SQLITE_PATH")]
+    sqlite_path: Option<PathBuf>,
+    /// `pretty` or `json` [default: pretty]
+    #[arg(long, global = true, env = "STDOUT_FORMAT")]
+    stdout_format: Option<PrintFormat>,
+}
+
+#[derive(Args)]
+#[command(next_help_heading = "Servers")]
+struct ServerOptions {
+    /// Metrics, health checks, and with the `sqlite` sink, REST and GraphQL APIs
+    #[arg(long, global = true, env = "HTTP_ADDRESS")]
+    http_address: Option<SocketAddr>,
+    #[arg(long, global = true, env = "LIVE_FEED_ADDRESS")]
+    live_feed_address: Option<SocketAddr>,
+    /// Requires the `sqlite` sink
+    #[arg(long, global = true, env = "GRPC_ADDRESS")]
+    grpc_address: Option<SocketAddr>,
+    /// [default: 120]
+    #[arg(long, global = true, env = "READINESS_MAX_BLOCK_INTERVAL_SECS")]
+    readiness_max_block_interval_secs: Option<u64>,
+}
+
+impl Options {
+    /// Reads the configuration file, if any, and applies the options on top of it
+    fn into_config(self) -> Result<Config, anyhow::Error> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        fn set<T>(field: &mut T, value: Option<T>) {
+            if let Some(value) = value {
+                *field = value;
+            }
+        }
+        fn set_some<T>(field: &mut Option<T>, value: Option<T>) {
+            if value.is_some() {
+                *field = value;
+            }
+        }
+        set(&mut config.network, self.network);
+        set(&mut config.provider, self.provider);
+        set(&mut config.sinks, self.sinks);
+        if self.dry_run {
+            config.sinks = vec![Sink::Stdout];
+        }
+        set(&mut config.log_level, self.log_level);
+        set_some(&mut config.contracts.donation, self.donation_contract);
+        set_some(&mut config.contracts.pot_factory, self.pot_factory);
+        set(&mut config.indexer.prefetch_blocks, self.prefetch_blocks);
+        set(&mut config.indexer.postfetch_blocks, self.postfetch_blocks);
+        set_some(&mut config.indexer.blocks_directory, self.blocks_directory);
+        set(
+            &mut config.indexer.shutdown_timeout_secs,
+            self.shutdown_timeout_secs,
+        );
+
+        let redis = self.redis;
+        set_some(&mut config.redis.url, redis.redis_url);
+        set(&mut config.redis.prefix, redis.redis_prefix);
+        set(&mut config.redis.max_stream_size, redis.max_stream_size);
+        set_some(&mut config.redis.retention, redis.redis_retention);
+        set_some(
+            &mut config.redis.retention_donation,
+            redis.redis_retention_donation,
+        );
+        set_some(
+            &mut config.redis.retention_pot_project_donation,
+            redis.redis_retention_pot_project_donation,
+        );
+        set_some(
+            &mut config.redis.retention_pot_donation,
+            redis.redis_retention_pot_donation,
+        );
+        set_some(&mut config.redis.spill_path, redis.redis_spill_path);
+        set(
+            &mut config.redis.secondary_indexes,
+            redis.redis_secondary_indexes,
+        );
+        set(&mut config.redis.publish, redis.redis_publish);
+
+        set_some(&mut config.export.directory, self.sink.export_directory);
+        set(&mut config.export.format, self.sink.export_format);
+        set_some(&mut config.sqlite.path, self.sink.sqlite_path);
+        set(&mut config.stdout.format, self.sink.stdout_format);
+
+        set_some(&mut config.server.http_address, self.server.http_address);
+        set_some(
+            &mut config.server.live_feed_address,
+            self.server.live_feed_address,
+        );
+        set_some(&mut config.server.grpc_address, self.server.grpc_address);
+        set(
+            &mut config.server.readiness_max_block_interval_secs,
+            self.server.readiness_max_block_interval_secs,
+        );
+        Ok(config)
+    }
+}
+
+/// Accepts `_`, `,`, ` `, and `.` as digit separators
+fn parse_block_height(s: &str) -> Result<BlockHeight, String> {
+    s.replace(['_', ',', ' ', '.'], "")
+        .parse()
+        .map_err(|err| format!("Invalid block height {s:?}: {err}"))
+}
+
+#[cfg(not(feature = "export"))]
+type ExportToFiles = potlock_indexer::Disabled;
+#[cfg(not(feature = "sqlite"))]
+type StoreInSqlite = potlock_indexer::Disabled;
+
+/// Every sink, `None` unless enabled. Events are sent to the sinks in field order,
+/// and all of them are flushed even if one fails.
+struct Sinks {
+    redis_stream: Option<PushToRedisStream>,
+    export: Option<ExportToFiles>,
+    sqlite: Option<StoreInSqlite>,
+    live_feed: LiveFeedHandler,
+    aggregates: Option<AggregatingHandler<RedisAggregateSink>>,
+    stdout: Option<PrintToStdout>,
+}
+
+#[async_trait]
+impl PotlockEventHandler for Sinks {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        self.redis_stream
+            .handle_donation(event.clone(), context.clone())
+            .await;
+        self.export
+            .handle_donation(event.clone(), context.clone())
+            .await;
+        self.sqlite
+            .handle_donation(event.clone(), context.clone())
+            .await;
+        self.live_feed
+            .handle_donation(event.clone(), context.clone())
+            .await;
+        self.aggregates
+            .handle_donation(event.clone(), context.clone())
+            .await;
+        self.stdout.handle_donation(event, context).await;
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        self.redis_stream
+            .handle_pot_project_donation(event.clone(), context.clone())
+            .await;
+        self.export
+            .handle_pot_project_donation(event.clone(), context.clone())
+            .await;
+        self.sqlite
+            .handle_pot_project_donation(event.clone(), context.clone())
+            .await;
+        self.live_feed
+            .handle_pot_project_donation(event.clone(), context.clone())
+            .await;
+        self.aggregates
+            .handle_pot_project_donation(event.clone(), context.clone())
+            .await;
+        self.stdout
+            .handle_pot_project_donation(event, context)
+            .await;
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        self.redis_stream
+            .handle_pot_donation(event.clone(), context.clone())
+            .await;
+        self.export
+            .handle_pot_donation(event.clone(), context.clone())
+            .await;
+        self.sqlite
+            .handle_pot_donation(event.clone(), context.clone())
+            .await;
+        self.live_feed
+            .handle_pot_donation(event.clone(), context.clone())
+            .await;
+        self.aggregates
+            .handle_pot_donation(event.clone(), context.clone())
+            .await;
+        self.stdout.handle_pot_donation(event, context).await;
+    }
+
+    /// Returns the first error
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        [
+            self.redis_stream.flush_events(block_height).await,
+            self.export.flush_events(block_height).await,
+            self.sqlite.flush_events(block_height).await,
+            self.live_feed.flush_events(block_height).await,
+            self.aggregates.flush_events(block_height).await,
+            self.stdout.flush_events(block_height).await,
+        ]
+        .into_iter()
+        .collect()
+    }
+}
+
+/// Saves the aggregates snapshot and writes the remaining exported rows. Both are
+/// attempted even if one fails. `mid_block` skips the export, since its rows would
+/// include a block that wasn't completely indexed.
+#[cfg_attr(not(feature = "export"), allow(unused_variables))]
+async fn finish_sinks(sinks: &mut Sinks, mid_block: bool) -> Result<(), anyhow::Error> {
+    let snapshot = match &mut sinks.aggregates {
+        // Only saves blocks that were completely counted
+        Some(aggregates) => aggregates
+            .save_snapshot()
+            .await
+            .context("Failed to save aggregates snapshot"),
+        None => Ok(()),
+    };
+    #[cfg(feature = "export")]
+    if let Some(export) = sinks.export.as_mut().filter(|_| !mid_block) {
+        export
+            .finish()
+            .await
+            .context("Failed to write remaining exported events")?;
+    }
+    snapshot
+}
 
 #[tokio::main]
-async fn main() {
+async fn main() -> Result<(), anyhow::Error> {
     dotenv::dotenv().ok();
+    let cli = Cli::parse();
+    let mut config = cli.options.into_config()?;
+
+    let mut parallel_backfill = None;
+    let mut backfill_from = None;
+    let range = match cli.command {
+        Command::Run => None,
+        Command::Backfill {
+            from,
+            to,
+            concurrency,
+            chunk_size,
+            checkpoint_directory,
+        } => {
+            backfill_from = Some(from);
+            if concurrency > 1 {
+                parallel_backfill = Some(BackfillOptions {
+                    range: from..to,
+                    chunk_size,
+                    concurrency,
+                    checkpoint_directory,
+                });
+            }
+            Some(BlockRange::Range {
+                start_inclusive: from,
+                end_exclusive: Some(to),
+            })
+        }
+        Command::Replay { from, to } => Some(BlockRange::Range {
+            start_inclusive: from,
+            end_exclusive: to,
+        }),
+        Command::Inspect => {
+            init_logger(&config)?;
+            return inspect(&config).await;
+        }
+        Command::Download {
+            from,
+            to,
+            directory,
+            compress,
+            concurrency,
+        } => {
+            init_logger(&config)?;
+            let provider = match config.network {
+                Network::Mainnet => NeardataProvider::mainnet(),
+                Network::Testnet => NeardataProvider::testnet(),
+            };
+            let written = download(&provider, from..to, &directory, compress, concurrency).await?;
+            log::info!("Downloaded {written} blocks to {}", directory.display());
+            return Ok(());
+        }
+        Command::Export {
+            from,
+            to,
+            directory,
+            format,
+        } => {
+            config.sinks = vec![Sink::Export];
+            config.export.directory = Some(directory);
+            config.export.format = format;
+            Some(BlockRange::Range {
+                start_inclusive: from,
+                end_exclusive: Some(to),
+            })
+        }
+    };
+    config.validate()?;
+    init_logger(&config)?;
+    if let Some(from) = backfill_from.filter(|_| config.sinks.contains(&Sink::Redis)) {
+        ensure_redis_accepts_blocks_from(&config, from).await?;
+    }
+
+    let (sinks, resume_after) = build_sinks(&config).await?;
+    let shutdown = Shutdown::new();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            if let Err(err) = shutdown.listen_for_signals().await {
+                log::error!("Failed to listen for shutdown signals: {err:#}");
+            }
+        }
+    });
+    let mut indexer = PotlockIndexer::with_contracts(
+        ShutdownHandler::new(sinks, shutdown.clone()),
+        config.contracts(),
+    );
+    let indexing = async {
+        if let Some(options) = parallel_backfill {
+            let config = &config;
+            backfill(&mut indexer.0, options, |chunk| async move {
+                let mut indexer = PotlockIndexer::with_contracts(
+                    BufferingHandler::new(chunk.clone()),
+                    config.contracts(),
+                )
+                .without_metrics();
+                let range = BlockRange::Range {
+                    start_inclusive: chunk.start,
+                    end_exclusive: Some(chunk.end),
+                };
+                run(&mut indexer, config, range).await?;
+                Ok(indexer.0.into_events())
+            })
+            .await
+            .context("Backfill failed")
+        } else {
+            let range = match range {
+                Some(range) => range,
+                // Events and the checkpoint are written atomically, so this doesn't skip
+                // or repeat anything
+                None => match resume_after
+                    .map(|block| block + 1)
+                    .or(config.indexer.start_block)
+                {
+                    Some(start_block) => BlockRange::Range {
+                        start_inclusive: start_block,
+                        end_exclusive: config.indexer.end_block,
+                    },
+                    None => BlockRange::AutoContinue(AutoContinue::default()),
+                },
+            };
+            run(&mut indexer, &config, range)
+                .await
+                .context("Indexer run failed")
+        }
+    };
+    let shutdown_timeout = Duration::from_secs(config.indexer.shutdown_timeout_secs);
+    let timed_out = async {
+        shutdown.requested().await;
+        tokio::time::sleep(shutdown_timeout).await;
+    };
+    let (result, mid_block) = tokio::select! {
+        result = indexing => (result, false),
+        // Drops `indexing` between blocks, after everything was flushed
+        () = shutdown.stopped() => (Ok(()), false),
+        () = timed_out => (
+            Err(anyhow::anyhow!(
+                "The current block wasn't flushed within {shutdown_timeout:?} after the shutdown signal"
+            )),
+            true,
+        ),
+    };
+
+    // Also after an error, so that a failed run doesn't lose what was already indexed
+    let finished = finish_sinks(indexer.0.inner().inner().inner(), mid_block).await;
+    if let Err(err) = &finished {
+        if result.is_err() {
+            log::error!("{err:#}");
+        }
+    }
+    result.and(finished)
+}
+
+async fn run<T: PotlockEventHandler + 'static>(
+    indexer: &mut PotlockIndexer<T>,
+    config: &Config,
+    range: BlockRange,
+) -> Result<(), anyhow::Error> {
+    let options = IndexerOptions {
+        preprocess_transactions: Some(PreprocessTransactionsSettings {
+            prefetch_blocks: config.indexer.prefetch_blocks,
+            postfetch_blocks: config.indexer.postfetch_blocks,
+        }),
+        // Signals are handled by `Shutdown`
+        ctrl_c_handler: false,
+        // The next run starts again from the block that failed to flush
+        stop_on_error: true,
+        ..IndexerOptions::default_with_range(range)
+    };
+    match (config.provider, config.network) {
+        (Provider::Neardata, Network::Mainnet) => {
+            run_indexer(indexer, NeardataProvider::mainnet(), options).await
+        }
+        (Provider::Neardata, Network::Testnet) => {
+            run_indexer(indexer, NeardataProvider::testnet(), options).await
+        }
+        (Provider::Files, _) => {
+            // Validated to be set
+            let directory = config.indexer.blocks_directory.as_ref().unwrap();
+            run_indexer(indexer, FileProvider::new(directory), options).await
+        }
+    }
+}
+
+fn init_logger(config: &Config) -> Result<(), anyhow::Error> {
     simple_logger::SimpleLogger::new()
-        .with_level(log::LevelFilter::Info)
+        .with_level(config.log_level)
         .with_module_level("inindexer::performance", log::LevelFilter::Debug)
-        .init()
-        .unwrap();
-
-    let client = redis::Client::open(
-        std::env::var("REDIS_URL").expect("No $REDIS_URL environment variable set"),
-    )
-    .unwrap();
-    let connection = ConnectionManager::new(client).await.unwrap();
-
-    let mut indexer =
-        potlock_indexer::PotlockIndexer(PushToRedisStream::new(connection, 1_000).await);
-
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: 20,
-                postfetch_blocks: 20,
-            }),
-            ..IndexerOptions::default_with_range(if std::env::args().len() > 1 {
-                // For debugging
-                let msg = "Usage: `indexer` or `indexer [start-block] [end-block]`";
-                BlockRange::Range {
-                    start_inclusive: std::env::args()
-                        .nth(1)
-                        .expect(msg)
-                        .replace(['_', ',', ' ', '.'], "")
-                        .parse()
-                        .expect(msg),
-                    end_exclusive: Some(
-                        std::env::args()
-                            .nth(2)
-                            .expect(msg)
-                            .replace(['_', ',', ' ', '.'], "")
-                            .parse()
-                            .expect(msg),
-                    ),
-                }
-            } else {
-                BlockRange::AutoContinue(AutoContinue::default())
-            })
-        },
-    )
-    .await
-    .expect("Indexer run failed");
+        .init()?;
+    Ok(())
+}
+
+async fn redis_connection(config: &RedisConfig) -> Result<ConnectionManager, anyhow::Error> {
+    let url = config
+        .url
+        .as_deref()
+        .context("redis.url (--redis-url, $REDIS_URL) is required")?;
+    let client = redis::Client::open(url).context("Invalid Redis URL")?;
+    ConnectionManager::new(client)
+        .await
+        .context("Failed to connect to Redis")
+}
+
+/// Redis skips entries that are not after the last one of the stream, so backfilling
+/// earlier blocks would silently write nothing there
+async fn ensure_redis_accepts_blocks_from(
+    config: &Config,
+    from: BlockHeight,
+) -> Result<(), anyhow::Error> {
+    let connection = redis_connection(&config.redis).await?;
+    let mut redis_stream = PushToRedisStream::new(connection, config.redis.streams_config())
+        .await
+        .context("Failed to open Redis spill")?;
+    let last_entry_block = redis_stream
+        .last_entry_block()
+        .await
+        .context("Failed to read Redis streams")?;
+    if let Some(last_entry_block) = last_entry_block.filter(|block| from <= *block) {
+        anyhow::bail!(
+            "Redis streams already have entries up to block {last_entry_block}, so events of \
+            earlier blocks would be skipped. Backfill from a later block, write to new streams \
+            with another --redis-prefix, or backfill without the redis sink, e.g. with \
+            --sinks export,sqlite"
+        );
+    }
+    Ok(())
+}
+
+/// Also starts the servers. Returns the block to continue after: the last block
+/// written to Redis, or the block of the aggregates snapshot if it's earlier.
+async fn build_sinks(
+    config: &Config,
+) -> Result<
+    (
+        HealthTrackingHandler<FilteringHandler<Sinks>>,
+        Option<BlockHeight>,
+    ),
+    anyhow::Error,
+> {
+    let health = Health::new(Duration::from_secs(
+        config.server.readiness_max_block_interval_secs,
+    ));
+
+    let connection =
+        if config.sinks.contains(&Sink::Redis) || config.sinks.contains(&Sink::Aggregates) {
+            Some(redis_connection(&config.redis).await?)
+        } else {
+            None
+        };
+
+    let (redis_stream, checkpoint) = if config.sinks.contains(&Sink::Redis) {
+        let mut redis_stream =
+            PushToRedisStream::new(connection.clone().unwrap(), config.redis.streams_config())
+                .await
+                .context("Failed to open Redis spill")?;
+        let checkpoint = redis_stream
+            .last_processed_block()
+            .await
+            .context("Failed to read last processed block from Redis")?;
+        (Some(redis_stream), checkpoint)
+    } else {
+        (None, None)
+    };
+
+    // Validated to be enabled
+    #[cfg(feature = "export")]
+    let export = if config.sinks.contains(&Sink::Export) {
+        Some(ExportToFiles::new(
+            config.export.directory.clone().unwrap(),
+            config.export.format,
+            config.export.max_rows_per_file,
+            config.export.max_buffered_blocks,
+        ))
+    } else {
+        None
+    };
+    #[cfg(not(feature = "export"))]
+    let export = None;
+
+    // Validated to be enabled
+    #[cfg(feature = "sqlite")]
+    let sqlite = if config.sinks.contains(&Sink::Sqlite) {
+        let path = config.sqlite.path.as_ref().unwrap();
+        Some(StoreInSqlite::open(path).context("Failed to open SQLite database")?)
+    } else {
+        None
+    };
+    #[cfg(feature = "sqlite")]
+    let store = sqlite
+        .as_ref()
+        .map(|sqlite| Arc::new(sqlite.store()) as Arc<dyn DonationStore>);
+    #[cfg(not(feature = "sqlite"))]
+    let (sqlite, store) = (None, None);
+
+    let aggregates = if config.sinks.contains(&Sink::Aggregates) {
+        let mut sink = RedisAggregateSink::new(
+            connection.clone().unwrap(),
+            config.redis.prefix.clone(),
+            10_000,
+            10,
+        );
+        let snapshot = sink
+            .load_latest_snapshot()
+            .await
+            .context("Failed to load aggregates snapshot")?;
+        Some(AggregatingHandler::new(sink, snapshot, 100))
+    } else {
+        None
+    };
+
+    // Aggregates ignore blocks up to their snapshot and Redis skips entries it already
+    // has, so continuing after the earlier of the two doesn't count anything twice.
+    // Other sinks may receive these blocks again.
+    let snapshot_block = aggregates
+        .as_ref()
+        .and_then(AggregatingHandler::snapshot_block);
+    let resume_after = match (checkpoint, snapshot_block) {
+        (Some(checkpoint), Some(snapshot_block)) => Some(checkpoint.min(snapshot_block)),
+        (checkpoint, snapshot_block) => checkpoint.or(snapshot_block),
+    };
+
+    let stdout = if config.sinks.contains(&Sink::Stdout) {
+        Some(PrintToStdout::new(config.stdout.format))
+    } else {
+        None
+    };
+
+    // Always created, GraphQL and gRPC subscriptions use it too
+    let live_feed = LiveFeed::new(1_000);
+    start_servers(&config.server, &health, &live_feed, store).await?;
+
+    Ok((
+        HealthTrackingHandler::new(
+            FilteringHandler::new(
+                Sinks {
+                    redis_stream,
+                    export,
+                    sqlite,
+                    live_feed: LiveFeedHandler::new(live_feed),
+                    aggregates,
+                    stdout,
+                },
+                config.filter.clone(),
+            ),
+            health,
+        ),
+        resume_after,
+    ))
+}
+
+/// Binds every server before spawning it, so that an address that is already in use
+/// fails startup instead of only being logged
+#[cfg_attr(not(all(feature = "http", feature = "grpc")), allow(unused_variables))]
+async fn start_servers(
+    config: &ServerConfig,
+    health: &Health,
+    live_feed: &LiveFeed,
+    store: Option<Arc<dyn DonationStore>>,
+) -> Result<(), anyhow::Error> {
+    #[cfg(feature = "http")]
+    if let Some(address) = config.live_feed_address {
+        let listener = bind("Live feed", address).await?;
+        let live_feed = live_feed.clone();
+        tokio::spawn(async move {
+            if let Err(err) = potlock_indexer::live_feed::serve(live_feed, listener).await {
+                log::error!("Live feed server failed: {err:#}");
+            }
+        });
+    }
+
+    // Validated to have the sqlite sink
+    #[cfg(feature = "grpc")]
+    if let (Some(address), Some(store)) = (config.grpc_address, &store) {
+        let listener = bind("gRPC server", address).await?;
+        let service = PotlockService::new(Arc::clone(store), live_feed.clone());
+        tokio::spawn(async move {
+            if let Err(err) = potlock_indexer::grpc::serve(service, listener).await {
+                log::error!("gRPC server failed: {err:#}");
+            }
+        });
+    }
+
+    #[cfg(feature = "http")]
+    if let Some(address) = config.http_address {
+        let mut app = potlock_indexer::health::router(health.clone());
+        #[cfg(feature = "metrics")]
+        {
+            app = app.merge(potlock_indexer::metrics::router());
+        }
+        if let Some(store) = store {
+            #[cfg(feature = "graphql")]
+            {
+                let schema =
+                    potlock_indexer::graphql::schema(Arc::clone(&store), live_feed.clone());
+                app = app.merge(potlock_indexer::graphql::router(schema));
+            }
+            app = app.merge(potlock_indexer::api::router(store));
+        }
+        let listener = bind("HTTP server", address).await?;
+        tokio::spawn(async move {
+            if let Err(err) = axum::serve(listener, app).await {
+                log::error!("HTTP server failed: {err}");
+            }
+        });
+    }
+    Ok(())
+}
+
+#[cfg(any(feature = "http", feature = "grpc"))]
+async fn bind(name: &str, address: SocketAddr) -> Result<tokio::net::TcpListener, anyhow::Error> {
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("{name} failed to listen on {address}"))?;
+    log::info!("{name} listening on {address}");
+    Ok(listener)
+}
+
+async fn inspect(config: &Config) -> Result<(), anyhow::Error> {
+    let mut connection = redis_connection(&config.redis).await?;
+    let mut redis_stream =
+        PushToRedisStream::new(connection.clone(), config.redis.streams_config())
+            .await
+            .context("Failed to open Redis spill")?;
+    match redis_stream.last_processed_block().await? {
+        Some(block_height) => println!("Last processed block: {block_height}"),
+        None => println!("Last processed block: none"),
+    }
+    for key in redis_stream.stream_keys() {
+        let len: usize = connection.xlen(key).await?;
+        println!("{key}: {len} entries");
+    }
+    println!("Spilled blocks: {}", redis_stream.spilled_blocks());
+    Ok(())
 }
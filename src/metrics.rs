@@ -0,0 +1,149 @@
+//! Prometheus metrics. Without the `metrics` feature, recording does nothing.
+
+#[cfg(feature = "metrics")]
+use std::sync::LazyLock;
+#[cfg(feature = "metrics")]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(all(feature = "metrics", feature = "http"))]
+use axum::http::header::CONTENT_TYPE;
+#[cfg(all(feature = "metrics", feature = "http"))]
+use axum::response::IntoResponse;
+#[cfg(all(feature = "metrics", feature = "http"))]
+use axum::routing::get;
+#[cfg(all(feature = "metrics", feature = "http"))]
+use axum::Router;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+#[cfg(feature = "metrics")]
+use prometheus::{
+    register_counter_vec, register_gauge, register_histogram_vec, register_int_counter_vec,
+    register_int_gauge, CounterVec, Gauge, HistogramTimer, HistogramVec, IntCounterVec, IntGauge,
+};
+#[cfg(all(feature = "metrics", feature = "http"))]
+use prometheus::{Encoder, TextEncoder};
+
+#[cfg(feature = "metrics")]
+static EVENTS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "potlock_events_total",
+        "Number of events emitted",
+        &["event_type"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static DONATED_VOLUME: LazyLock<CounterVec> = LazyLock::new(|| {
+    register_counter_vec!(
+        "potlock_donated_volume_total",
+        "Total amount donated in the smallest units of the token, approximated as f64",
+        &["ft_id"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static DECODE_FAILURES: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "potlock_decode_failures_total",
+        "Number of donation logs or results that couldn't be decoded",
+        &["contract"]
+    )
+    .unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static BLOCK_HEIGHT: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("potlock_block_height", "Height of the last processed block").unwrap()
+});
+
+#[cfg(feature = "metrics")]
+static BLOCK_LAG: LazyLock<Gauge> = LazyLock::new(|| {
+    register_gauge!(
+        "potlock_block_lag_seconds",
+        "Time between the last processed block being produced and processed"
+    )
+    .unwrap()
+});
+
+/// Observed by sinks in their `flush_events`
+#[cfg(feature = "metrics")]
+pub static FLUSH_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "potlock_sink_flush_duration_seconds",
+        "Time it takes for a sink to flush events of a block",
+        &["sink"]
+    )
+    .unwrap()
+});
+
+/// Observes the flush duration of `sink` when dropped
+pub struct FlushTimer {
+    #[cfg(feature = "metrics")]
+    _timer: HistogramTimer,
+}
+
+#[cfg_attr(not(feature = "metrics"), allow(unused_variables))]
+pub fn flush_timer(sink: &str) -> FlushTimer {
+    FlushTimer {
+        #[cfg(feature = "metrics")]
+        _timer: FLUSH_DURATION.with_label_values(&[sink]).start_timer(),
+    }
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_event(event_type: &str, ft_id: &str, amount: u128) {
+    EVENTS.with_label_values(&[event_type]).inc();
+    DONATED_VOLUME
+        .with_label_values(&[ft_id])
+        .inc_by(amount as f64);
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_decode_failure(contract: &str) {
+    DECODE_FAILURES.with_label_values(&[contract]).inc();
+}
+
+/// Without the lag, which only makes sense for blocks indexed as they're produced
+#[cfg(feature = "metrics")]
+pub(crate) fn record_block_height(block_height: BlockHeight) {
+    BLOCK_HEIGHT.set(block_height as i64);
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_block(block_height: BlockHeight, block_timestamp_nanosec: u64) {
+    record_block_height(block_height);
+    let now_nanosec = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_nanos();
+    BLOCK_LAG.set(now_nanosec.saturating_sub(block_timestamp_nanosec as u128) as f64 / 1e9);
+}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_event(_event_type: &str, _ft_id: &str, _amount: u128) {}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_decode_failure(_contract: &str) {}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_block_height(_block_height: BlockHeight) {}
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_block(_block_height: BlockHeight, _block_timestamp_nanosec: u64) {}
+
+/// `GET /metrics` in Prometheus text format
+#[cfg(all(feature = "metrics", feature = "http"))]
+pub fn router() -> Router {
+    Router::new().route("/metrics", get(metrics))
+}
+
+#[cfg(all(feature = "metrics", feature = "http"))]
+async fn metrics() -> impl IntoResponse {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .expect("Failed to encode metrics");
+    ([(CONTENT_TYPE, encoder.format_type().to_owned())], buffer)
+}
@@ -1,58 +1,877 @@
+use std::collections::{HashMap, VecDeque};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use anyhow::Context;
 use async_trait::async_trait;
-use chrono::DateTime;
-use inevents_redis::RedisEventStream;
-use inindexer::near_indexer_primitives::types::BlockHeight;
+use chrono::{DateTime, Utc};
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
 use intear_events::events::potlock::{
     potlock_donation::PotlockDonationEvent, potlock_pot_donation::PotlockPotDonationEvent,
     potlock_pot_project_donation::PotlockPotProjectDonationEvent,
 };
+use rand::Rng;
 use redis::aio::ConnectionManager;
+use redis::streams::StreamInfoStreamReply;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
 
+use crate::spill::Spill;
 use crate::{
-    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEventHandler,
+    metrics, DonationEvent, DonationId, EventContext, PotDonationEvent, PotProjectDonationEvent,
+    PotlockEvent, PotlockEventHandler, TimestampMs,
 };
 
+static WRITE_BLOCK: LazyLock<redis::Script> =
+    LazyLock::new(|| redis::Script::new(WRITE_BLOCK_SCRIPT));
+
+/// Adds events of a block to all streams, trims them, and updates the checkpoint,
+/// atomically. Entry IDs are `{block_height}-{index}`, and IDs that are not after the
+/// last ID of the stream are skipped. The checkpoint never moves backwards. Returns
+/// the number of added entries of each stream, which are always its last events,
+/// since earlier ones are the ones skipped.
+///
+/// Events of indexed streams are also added to secondary indexes, only if the stream
+/// entry was added, so running totals never count a donation twice.
+///
+/// Every key the script touches is passed in KEYS, as Redis Cluster requires.
+///
+/// KEYS: checkpoint, streams, then the index keys referenced by events. ARGV: block
+/// height, number of streams, then for each stream the number of events, retention
+/// (`maxlen`, `minage`, or `none`), its value (max number of entries, or the minimum
+/// block timestamp in milliseconds), and whether it's indexed (`1` or `0`), then
+/// events. An event of an indexed stream is followed by its index member, timestamp
+/// in milliseconds, ft id, total amount, and the positions in KEYS of the donations
+/// hash, the donor, project, and pot sorted sets, and the project totals hash (`0`
+/// if the event has no project or pot).
+const WRITE_BLOCK_SCRIPT: &str = r"
+local block_height = ARGV[1]
+local height = tonumber(block_height)
+local stream_count = tonumber(ARGV[2])
+local event_arg = 4 * stream_count + 3
+local added = {}
+
+local function parse_id(id)
+    local dash = string.find(id, '-', 1, true)
+    return tonumber(string.sub(id, 1, dash - 1)), tonumber(string.sub(id, dash + 1))
+end
+
+-- Adds two non-negative decimal strings, amounts don't fit in Lua numbers
+local function add_decimal(a, b)
+    local digits = {}
+    local carry = 0
+    local i, j = #a, #b
+    while i > 0 or j > 0 or carry > 0 do
+        local digit = carry
+        if i > 0 then
+            digit = digit + tonumber(string.sub(a, i, i))
+            i = i - 1
+        end
+        if j > 0 then
+            digit = digit + tonumber(string.sub(b, j, j))
+            j = j - 1
+        end
+        table.insert(digits, 1, digit % 10)
+        carry = math.floor(digit / 10)
+    end
+    return table.concat(digits)
+end
+
+local function index_event(arg)
+    local event, member, score = ARGV[arg], ARGV[arg + 1], ARGV[arg + 2]
+    local ft_id, amount = ARGV[arg + 3], ARGV[arg + 4]
+    local donations, donor = tonumber(ARGV[arg + 5]), tonumber(ARGV[arg + 6])
+    local project, pot = tonumber(ARGV[arg + 7]), tonumber(ARGV[arg + 8])
+    local totals = tonumber(ARGV[arg + 9])
+    redis.call('HSET', KEYS[donations], member, event)
+    redis.call('ZADD', KEYS[donor], score, member)
+    if project > 0 then
+        redis.call('ZADD', KEYS[project], score, member)
+        local total = redis.call('HGET', KEYS[totals], ft_id) or '0'
+        redis.call('HSET', KEYS[totals], ft_id, add_decimal(total, amount))
+    end
+    if pot > 0 then
+        redis.call('ZADD', KEYS[pot], score, member)
+    end
+end
+
+-- Trims entries from blocks older than min_timestamp_ms, at most 1000 per call
+local function trim_by_age(stream, min_timestamp_ms)
+    local entries = redis.call('XRANGE', stream, '-', '+', 'COUNT', 1000)
+    local min_id = nil
+    for _, entry in ipairs(entries) do
+        local event = cjson.decode(entry[2][2])
+        if tonumber(event.block_timestamp_nanosec) / 1000000 >= min_timestamp_ms then
+            min_id = entry[1]
+            break
+        end
+        local entry_height, entry_index = parse_id(entry[1])
+        min_id = entry_height .. '-' .. (entry_index + 1)
+    end
+    if min_id then
+        redis.call('XTRIM', stream, 'MINID', min_id)
+    end
+end
+
+for s = 1, stream_count do
+    local stream = KEYS[s + 1]
+    local count = tonumber(ARGV[4 * s - 1])
+    local retention = ARGV[4 * s]
+    local retention_value = ARGV[4 * s + 1]
+    local args_per_event = 1
+    if ARGV[4 * s + 2] == '1' then
+        args_per_event = 10
+    end
+    added[s] = 0
+    local last_height, last_index = -1, -1
+    if redis.call('EXISTS', stream) == 1 then
+        local info = redis.call('XINFO', 'STREAM', stream)
+        for i = 1, #info, 2 do
+            if info[i] == 'last-generated-id' then
+                last_height, last_index = parse_id(info[i + 1])
+            end
+        end
+    end
+    for index = 0, count - 1 do
+        if height > last_height or (height == last_height and index > last_index) then
+            local id = block_height .. '-' .. index
+            if retention == 'maxlen' then
+                redis.call('XADD', stream, 'MAXLEN', '~', retention_value, id, 'event', ARGV[event_arg])
+            else
+                redis.call('XADD', stream, id, 'event', ARGV[event_arg])
+            end
+            if args_per_event > 1 then
+                index_event(event_arg)
+            end
+            added[s] = added[s] + 1
+        end
+        event_arg = event_arg + args_per_event
+    end
+    if retention == 'minage' and count > 0 then
+        trim_by_age(stream, tonumber(retention_value))
+    end
+end
+
+local checkpoint = redis.call('GET', KEYS[1])
+if not checkpoint or height > tonumber(checkpoint) then
+    redis.call('SET', KEYS[1], block_height)
+end
+return added
+";
+
+/// How many entries a stream keeps. Streams are trimmed when new events are added.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Retention {
+    /// Approximately this number of latest entries
+    MaxLen(usize),
+    /// Entries from blocks not older than this, relative to the block being written
+    MaxAge(Duration),
+    /// Never trim, for archival instances
+    Unlimited,
+}
+
+impl FromStr for Retention {
+    type Err = anyhow::Error;
+
+    /// `unlimited`, a number of entries (`10000`), or an age with a unit (`3600s`,
+    /// `30m`, `24h`, `7d`)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "unlimited" {
+            return Ok(Retention::Unlimited);
+        }
+        if let Ok(max_len) = s.parse() {
+            return Ok(Retention::MaxLen(max_len));
+        }
+        let unit = match s.chars().last() {
+            Some('s') => 1,
+            Some('m') => 60,
+            Some('h') => 60 * 60,
+            Some('d') => 24 * 60 * 60,
+            _ => anyhow::bail!(
+                "Invalid retention {s:?}, expected `unlimited`, a number of entries, or an age like `7d`"
+            ),
+        };
+        let amount: u64 = s[..s.len() - 1]
+            .parse()
+            .with_context(|| format!("Invalid retention {s:?}"))?;
+        Ok(Retention::MaxAge(Duration::from_secs(amount * unit)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Retention {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct RedisStreamsConfig {
+    /// Prepended to all keys, e.g. `testnet:`, to run multiple instances against the
+    /// same Redis. With Redis Cluster, include a hash tag, e.g. `{potlock}:`, so that
+    /// all keys written for a block are in the same slot.
+    pub prefix: String,
+    pub donation_retention: Retention,
+    pub pot_project_donation_retention: Retention,
+    pub pot_donation_retention: Retention,
+    pub retry: Retry,
+    /// File where blocks are kept while Redis is unavailable after all retries. If
+    /// `None`, the flush fails instead, and the block is kept in memory and written
+    /// before the next one.
+    pub spill_path: Option<PathBuf>,
+    /// When the spill is full, indexing waits until Redis is available again
+    pub max_spilled_blocks: usize,
+    /// Maintain `potlock_index:*` sorted sets and `potlock_totals:project:*` hashes
+    /// for lookups by project, donor, and pot without reading the streams
+    pub secondary_indexes: bool,
+    /// After a block is written, also PUBLISH each event that was added to the streams
+    /// (not skipped as already written) to `potlock_events`,
+    /// `potlock_events:pot:{pot_id}`, and `potlock_events:project:{project_id}`
+    pub publish: bool,
+}
+
+impl Default for RedisStreamsConfig {
+    fn default() -> Self {
+        Self {
+            prefix: String::new(),
+            donation_retention: Retention::MaxLen(1_000),
+            pot_project_donation_retention: Retention::MaxLen(1_000),
+            pot_donation_retention: Retention::MaxLen(1_000),
+            retry: Retry::default(),
+            spill_path: None,
+            max_spilled_blocks: 10_000,
+            secondary_indexes: false,
+            publish: false,
+        }
+    }
+}
+
+/// Exponential backoff with jitter
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Retry {
+    /// Including the first attempt
+    pub max_attempts: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+        }
+    }
+}
+
+impl Retry {
+    /// Random delay between half and full exponential backoff after `attempt` (0-based)
+    fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        backoff.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+/// Everything written for one block, in the form it's kept in the spill
+#[derive(Debug, Serialize, Deserialize)]
+struct BlockBatch {
+    block_height: BlockHeight,
+    block_timestamp_nanosec: Option<u128>,
+    donation_events: Vec<StreamEntry>,
+    pot_project_donation_events: Vec<StreamEntry>,
+    pot_donation_events: Vec<StreamEntry>,
+    dead_letters: Vec<StreamEntry>,
+}
+
+impl BlockBatch {
+    fn event_count(&self) -> usize {
+        self.donation_events.len()
+            + self.pot_project_donation_events.len()
+            + self.pot_donation_events.len()
+            + self.dead_letters.len()
+    }
+}
+
+/// An event that couldn't be converted to the stream format, written to the
+/// `potlock_dead_letter` stream instead
+#[derive(Debug, Serialize)]
+struct DeadLetter {
+    reason: String,
+    #[serde(flatten)]
+    event: PotlockEvent,
+    context: EventContext,
+}
+
+/// What's written to secondary indexes for a donation
+#[derive(Debug, Serialize, Deserialize)]
+struct IndexEntry {
+    /// `{receipt_id}:{donation_id}`, field in `potlock_index:donations` hash and member
+    /// of sorted sets
+    member: String,
+    timestamp_ms: TimestampMs,
+    project_id: Option<AccountId>,
+    donor_id: AccountId,
+    pot_id: Option<AccountId>,
+    ft_id: AccountId,
+    total_amount: String,
+}
+
+impl IndexEntry {
+    fn new(event: &PotlockEvent, context: &EventContext) -> Self {
+        Self {
+            member: format!("{}:{}", context.receipt_id, event.donation_id()),
+            timestamp_ms: match event {
+                PotlockEvent::Donation(event) => event.donated_at,
+                PotlockEvent::PotProjectDonation(event) => event.donated_at,
+                PotlockEvent::PotDonation(event) => event.donated_at,
+            },
+            project_id: event.project_id().cloned(),
+            donor_id: event.donor_id().clone(),
+            pot_id: event.pot_id().cloned(),
+            ft_id: event.ft_id(),
+            total_amount: event.total_amount().to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StreamEntry {
+    /// JSON of the stream event
+    event: String,
+    /// Set if secondary indexes or pub/sub are enabled
+    index: Option<IndexEntry>,
+}
+
+impl StreamEntry {
+    /// Blocks spilled before secondary indexes were enabled are written without them
+    fn all_indexed(entries: &[StreamEntry]) -> bool {
+        !entries.is_empty() && entries.iter().all(|entry| entry.index.is_some())
+    }
+}
+
+/// Index keys passed to [`WRITE_BLOCK_SCRIPT`] after the checkpoint and streams
+struct IndexKeys {
+    /// Number of keys before these
+    offset: usize,
+    keys: Vec<String>,
+    positions: HashMap<String, usize>,
+}
+
+impl IndexKeys {
+    fn new(offset: usize) -> Self {
+        Self {
+            offset,
+            keys: Vec::new(),
+            positions: HashMap::new(),
+        }
+    }
+
+    /// 1-based position of `key` in KEYS, added if it's not there yet
+    fn position(&mut self, key: String) -> usize {
+        if let Some(position) = self.positions.get(&key) {
+            return *position;
+        }
+        self.keys.push(key.clone());
+        let position = self.offset + self.keys.len();
+        self.positions.insert(key, position);
+        position
+    }
+}
+
+/// Buffers events of one stream until the end of the block
+struct EventStream<E: Serialize> {
+    name: String,
+    retention: Retention,
+    events: Vec<(E, Option<IndexEntry>)>,
+}
+
+impl<E: Serialize> EventStream<E> {
+    fn new(name: String, retention: Retention) -> Self {
+        Self {
+            name,
+            retention,
+            events: Vec::new(),
+        }
+    }
+
+    fn add_event(&mut self, event: E, index: Option<IndexEntry>) {
+        self.events.push((event, index));
+    }
+
+    fn take_serialized(&mut self) -> Result<Vec<StreamEntry>, anyhow::Error> {
+        std::mem::take(&mut self.events)
+            .into_iter()
+            .map(|(event, index)| {
+                Ok(StreamEntry {
+                    event: serde_json::to_string(&event)
+                        .with_context(|| format!("Failed to serialize event for {}", self.name))?,
+                    index,
+                })
+            })
+            .collect()
+    }
+
+    /// Arguments of [`WRITE_BLOCK_SCRIPT`] describing retention
+    fn retention_args(&self, block_timestamp_nanosec: Option<u128>) -> (&'static str, u128) {
+        match self.retention {
+            Retention::MaxLen(max_len) => ("maxlen", max_len as u128),
+            Retention::MaxAge(max_age) => match block_timestamp_nanosec {
+                Some(block_timestamp_nanosec) => (
+                    "minage",
+                    (block_timestamp_nanosec / 1_000_000).saturating_sub(max_age.as_millis()),
+                ),
+                // Nothing is added in this block, so nothing to trim
+                None => ("none", 0),
+            },
+            Retention::Unlimited => ("none", 0),
+        }
+    }
+}
+
+/// Writes each block's events to `potlock_donation`, `potlock_pot_project_donation`,
+/// and `potlock_pot_donation` streams together with the `potlock_last_processed_block`
+/// checkpoint in a single atomic script, so a crash never leaves a block half-written.
+/// Writing the same block again is a no-op. Events that can't be represented in the
+/// stream format (donation id over `u32::MAX`, timestamp out of range) go to
+/// `potlock_dead_letter` with the reason. All keys are prefixed with
+/// [`RedisStreamsConfig::prefix`].
+///
+/// Failed writes are retried according to [`RedisStreamsConfig::retry`]. If Redis is
+/// still unavailable, blocks are kept in [`RedisStreamsConfig::spill_path`] and written
+/// in order once it's back. Without a spill, the flush fails, and the block is kept in
+/// memory to be written before the next one.
 pub struct PushToRedisStream {
-    donation_stream: RedisEventStream<PotlockDonationEvent>,
-    pot_project_donation_stream: RedisEventStream<PotlockPotProjectDonationEvent>,
-    pot_donation_stream: RedisEventStream<PotlockPotDonationEvent>,
-    max_stream_size: usize,
+    connection: ConnectionManager,
+    prefix: String,
+    secondary_indexes: bool,
+    publish: bool,
+    checkpoint_key: String,
+    retry: Retry,
+    spill: Option<Spill<BlockBatch>>,
+    /// Blocks that failed to be written without a spill, oldest first
+    unwritten: VecDeque<BlockBatch>,
+    donation_stream: EventStream<PotlockDonationEvent>,
+    pot_project_donation_stream: EventStream<PotlockPotProjectDonationEvent>,
+    pot_donation_stream: EventStream<PotlockPotDonationEvent>,
+    dead_letter_stream: EventStream<DeadLetter>,
+    block_timestamp_nanosec: Option<u128>,
 }
 
 impl PushToRedisStream {
-    pub async fn new(connection: ConnectionManager, max_stream_size: usize) -> Self {
-        Self {
-            donation_stream: RedisEventStream::new(connection.clone(), "potlock_donation"),
-            pot_project_donation_stream: RedisEventStream::new(
-                connection.clone(),
+    pub async fn new(
+        connection: ConnectionManager,
+        config: RedisStreamsConfig,
+    ) -> Result<Self, anyhow::Error> {
+        let spill = config
+            .spill_path
+            .map(|path| Spill::open(path, config.max_spilled_blocks))
+            .transpose()?;
+        if let Some(spill) = &spill {
+            if !spill.is_empty() {
+                log::info!("{} spilled blocks will be written to Redis", spill.len());
+            }
+        }
+        let prefix = config.prefix;
+        Ok(Self {
+            connection,
+            secondary_indexes: config.secondary_indexes,
+            publish: config.publish,
+            checkpoint_key: format!("{prefix}potlock_last_processed_block"),
+            retry: config.retry,
+            spill,
+            unwritten: VecDeque::new(),
+            donation_stream: EventStream::new(
+                format!("{prefix}potlock_donation"),
+                config.donation_retention,
+            ),
+            pot_project_donation_stream: EventStream::new(
+                format!("{prefix}potlock_pot_project_donation"),
+                config.pot_project_donation_retention,
+            ),
+            pot_donation_stream: EventStream::new(
+                format!("{prefix}potlock_pot_donation"),
+                config.pot_donation_retention,
+            ),
+            // Never trimmed, these need to be looked at
+            dead_letter_stream: EventStream::new(
+                format!("{prefix}potlock_dead_letter"),
+                Retention::Unlimited,
+            ),
+            block_timestamp_nanosec: None,
+            prefix,
+        })
+    }
+
+    /// Block to resume from is the one after this
+    pub async fn last_processed_block(&mut self) -> Result<Option<BlockHeight>, anyhow::Error> {
+        Ok(self.connection.get(&self.checkpoint_key).await?)
+    }
+
+    /// Block of the last entry ever added to any of the streams, even if it was
+    /// trimmed since. Events of this block and earlier ones would be skipped.
+    pub async fn last_entry_block(&mut self) -> Result<Option<BlockHeight>, anyhow::Error> {
+        let mut last_entry_block = None;
+        for key in self.stream_keys().map(str::to_owned) {
+            if !self.connection.exists::<_, bool>(&key).await? {
+                continue;
+            }
+            let info: StreamInfoStreamReply = self.connection.xinfo_stream(&key).await?;
+            let block_height = info
+                .last_generated_id
+                .split('-')
+                .next()
+                .and_then(|block_height| block_height.parse().ok())
+                .with_context(|| {
+                    format!("Invalid last entry ID {} in {key}", info.last_generated_id)
+                })?;
+            last_entry_block = last_entry_block.max(Some(block_height));
+        }
+        Ok(last_entry_block)
+    }
+
+    /// Keys of all streams written by this handler, including the dead letter stream
+    pub fn stream_keys(&self) -> [&str; 4] {
+        [
+            &self.donation_stream.name,
+            &self.pot_project_donation_stream.name,
+            &self.pot_donation_stream.name,
+            &self.dead_letter_stream.name,
+        ]
+    }
+
+    /// Blocks waiting in the spill for Redis to become available
+    pub fn spilled_blocks(&self) -> usize {
+        self.spill.as_ref().map_or(0, Spill::len)
+    }
+
+    fn take_batch(&mut self, block_height: BlockHeight) -> Result<BlockBatch, anyhow::Error> {
+        Ok(BlockBatch {
+            block_height,
+            block_timestamp_nanosec: self.block_timestamp_nanosec.take(),
+            donation_events: self.donation_stream.take_serialized()?,
+            pot_project_donation_events: self.pot_project_donation_stream.take_serialized()?,
+            pot_donation_events: self.pot_donation_stream.take_serialized()?,
+            dead_letters: self.dead_letter_stream.take_serialized()?,
+        })
+    }
+
+    fn add_dead_letter(&mut self, event: PotlockEvent, context: EventContext, err: anyhow::Error) {
+        log::warn!(
+            "Sending {} {} to dead letter stream: {err:#}",
+            event.event_type(),
+            event.donation_id()
+        );
+        self.dead_letter_stream.add_event(
+            DeadLetter {
+                reason: format!("{err:#}"),
+                event,
+                context,
+            },
+            None,
+        );
+    }
+
+    async fn write_block(&mut self, batch: &BlockBatch) -> Result<(), anyhow::Error> {
+        let block_height = batch.block_height;
+        let streams = [
+            (
+                &self.donation_stream.name,
+                self.donation_stream
+                    .retention_args(batch.block_timestamp_nanosec),
+                &batch.donation_events,
+            ),
+            (
+                &self.pot_project_donation_stream.name,
+                self.pot_project_donation_stream
+                    .retention_args(batch.block_timestamp_nanosec),
+                &batch.pot_project_donation_events,
+            ),
+            (
+                &self.pot_donation_stream.name,
+                self.pot_donation_stream
+                    .retention_args(batch.block_timestamp_nanosec),
+                &batch.pot_donation_events,
+            ),
+            (
+                &self.dead_letter_stream.name,
+                self.dead_letter_stream
+                    .retention_args(batch.block_timestamp_nanosec),
+                &batch.dead_letters,
+            ),
+        ];
+        let mut invocation = WRITE_BLOCK.prepare_invoke();
+        invocation
+            .key(&self.checkpoint_key)
+            .arg(block_height)
+            .arg(streams.len());
+        let mut index_keys = IndexKeys::new(1 + streams.len());
+        for (name, (retention, retention_value), entries) in &streams {
+            let indexed = self.secondary_indexes && StreamEntry::all_indexed(entries);
+            invocation
+                .key(*name)
+                .arg(entries.len())
+                .arg(*retention)
+                .arg(retention_value.to_string())
+                .arg(if indexed { "1" } else { "0" });
+        }
+        for (_, _, entries) in &streams {
+            let indexed = self.secondary_indexes && StreamEntry::all_indexed(entries);
+            for entry in entries.iter() {
+                invocation.arg(&entry.event);
+                if let (true, Some(index)) = (indexed, &entry.index) {
+                    let prefix = &self.prefix;
+                    invocation
+                        .arg(&index.member)
+                        .arg(index.timestamp_ms)
+                        .arg(index.ft_id.as_str())
+                        .arg(&index.total_amount)
+                        .arg(index_keys.position(format!("{prefix}potlock_index:donations")))
+                        .arg(
+                            index_keys.position(format!(
+                                "{prefix}potlock_index:donor:{}",
+                                index.donor_id
+                            )),
+                        )
+                        .arg(index.project_id.as_ref().map_or(0, |project_id| {
+                            index_keys
+                                .position(format!("{prefix}potlock_index:project:{project_id}"))
+                        }))
+                        .arg(index.pot_id.as_ref().map_or(0, |pot_id| {
+                            index_keys.position(format!("{prefix}potlock_index:pot:{pot_id}"))
+                        }))
+                        .arg(index.project_id.as_ref().map_or(0, |project_id| {
+                            index_keys
+                                .position(format!("{prefix}potlock_totals:project:{project_id}"))
+                        }));
+                }
+            }
+        }
+        for key in &index_keys.keys {
+            invocation.key(key);
+        }
+        let added: Vec<usize> = invocation
+            .invoke_async(&mut self.connection)
+            .await
+            .with_context(|| format!("Failed to write block {block_height} to Redis"))?;
+        let total = batch.event_count();
+        let added_total = added.iter().sum::<usize>();
+        if added_total < total {
+            log::info!(
+                "Skipped {} already written events at block {block_height}",
+                total - added_total
+            );
+        }
+        if self.publish && added_total > 0 {
+            if let Err(err) = self.publish_events(batch, &added).await {
+                log::warn!("Failed to publish events of block {block_height}: {err:#}");
+            }
+        }
+        Ok(())
+    }
+
+    fn needs_index_entries(&self) -> bool {
+        self.secondary_indexes || self.publish
+    }
+
+    /// Fire-and-forget notifications for the entries the script added, `added` being
+    /// the number of added entries of each stream as returned by it. Events skipped
+    /// as already written were published when they were first written.
+    async fn publish_events(
+        &mut self,
+        batch: &BlockBatch,
+        added: &[usize],
+    ) -> Result<(), anyhow::Error> {
+        let mut pipe = redis::pipe();
+        for ((event_type, entries), added) in [
+            ("potlock_donation", &batch.donation_events),
+            (
                 "potlock_pot_project_donation",
+                &batch.pot_project_donation_events,
             ),
-            pot_donation_stream: RedisEventStream::new(connection.clone(), "potlock_pot_donation"),
-            max_stream_size,
+            ("potlock_pot_donation", &batch.pot_donation_events),
+        ]
+        .into_iter()
+        .zip(added)
+        {
+            for entry in &entries[entries.len().saturating_sub(*added)..] {
+                let message = format!(r#"{{"event_type":"{event_type}","event":{}}}"#, entry.event);
+                pipe.publish(format!("{}potlock_events", self.prefix), &message)
+                    .ignore();
+                let Some(index) = &entry.index else {
+                    continue;
+                };
+                if let Some(pot_id) = &index.pot_id {
+                    pipe.publish(
+                        format!("{}potlock_events:pot:{pot_id}", self.prefix),
+                        &message,
+                    )
+                    .ignore();
+                }
+                if let Some(project_id) = &index.project_id {
+                    pipe.publish(
+                        format!("{}potlock_events:project:{project_id}", self.prefix),
+                        &message,
+                    )
+                    .ignore();
+                }
+            }
         }
+        pipe.query_async::<_, ()>(&mut self.connection).await?;
+        Ok(())
     }
+
+    async fn write_block_with_retry(&mut self, batch: &BlockBatch) -> Result<(), anyhow::Error> {
+        let mut attempt = 0;
+        loop {
+            match self.write_block(batch).await {
+                Ok(()) => return Ok(()),
+                Err(err) if attempt + 1 < self.retry.max_attempts => {
+                    let backoff = self.retry.backoff(attempt);
+                    log::warn!("{err:#}, retrying in {backoff:?}");
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Writes spilled blocks in order until the spill is empty or a write fails
+    async fn drain_spill(&mut self) -> Result<(), anyhow::Error> {
+        let Some(mut spill) = self.spill.take() else {
+            return Ok(());
+        };
+        let mut result = Ok(());
+        let mut drained = 0;
+        while let Some(batch) = spill.front() {
+            if let Err(err) = self.write_block(batch).await {
+                result = Err(err);
+                break;
+            }
+            spill.pop_front();
+            drained += 1;
+        }
+        if drained > 0 {
+            log::info!(
+                "Wrote {drained} spilled blocks to Redis, {} left",
+                spill.len()
+            );
+            if let Err(err) = spill.save() {
+                result = Err(err);
+            }
+        }
+        self.spill = Some(spill);
+        result
+    }
+}
+
+/// Stream events use `u32` donation ids
+pub(crate) fn checked_donation_id(donation_id: DonationId) -> Result<u32, anyhow::Error> {
+    u32::try_from(donation_id)
+        .with_context(|| format!("Donation id {donation_id} doesn't fit in u32"))
+}
+
+pub(crate) fn checked_timestamp(timestamp_ms: TimestampMs) -> Result<DateTime<Utc>, anyhow::Error> {
+    i64::try_from(timestamp_ms)
+        .ok()
+        .and_then(DateTime::from_timestamp_millis)
+        .with_context(|| format!("Timestamp {timestamp_ms}ms is out of range"))
+}
+
+pub(crate) fn donation_event(
+    event: &DonationEvent,
+    context: &EventContext,
+) -> Result<PotlockDonationEvent, anyhow::Error> {
+    Ok(PotlockDonationEvent {
+        donation_id: checked_donation_id(event.donation_id)?,
+        donor_id: event.donor_id.clone(),
+        total_amount: event.total_amount,
+        ft_id: event.ft_id.clone(),
+        message: event.message.clone(),
+        donated_at: checked_timestamp(event.donated_at)?,
+        project_id: event.project_id.clone(),
+        protocol_fee: event.protocol_fee,
+        referrer_id: event.referrer_id.clone(),
+        referrer_fee: event.referrer_fee,
+
+        transaction_id: context.transaction_id,
+        receipt_id: context.receipt_id,
+        block_height: context.block_height,
+        block_timestamp_nanosec: context.block_timestamp_nanosec,
+    })
+}
+
+pub(crate) fn pot_project_donation_event(
+    event: &PotProjectDonationEvent,
+    context: &EventContext,
+) -> Result<PotlockPotProjectDonationEvent, anyhow::Error> {
+    Ok(PotlockPotProjectDonationEvent {
+        donation_id: checked_donation_id(event.donation_id)?,
+        pot_id: event.pot_id.clone(),
+        donor_id: event.donor_id.clone(),
+        total_amount: event.total_amount,
+        net_amount: event.net_amount,
+        message: event.message.clone(),
+        donated_at: checked_timestamp(event.donated_at)?,
+        project_id: event.project_id.clone(),
+        protocol_fee: event.protocol_fee,
+        referrer_id: event.referrer_id.clone(),
+        referrer_fee: event.referrer_fee,
+        chef_id: event.chef_id.clone(),
+        chef_fee: event.chef_fee,
+
+        transaction_id: context.transaction_id,
+        receipt_id: context.receipt_id,
+        block_height: context.block_height,
+        block_timestamp_nanosec: context.block_timestamp_nanosec,
+    })
+}
+
+pub(crate) fn pot_donation_event(
+    event: &PotDonationEvent,
+    context: &EventContext,
+) -> Result<PotlockPotDonationEvent, anyhow::Error> {
+    Ok(PotlockPotDonationEvent {
+        donation_id: checked_donation_id(event.donation_id)?,
+        pot_id: event.pot_id.clone(),
+        donor_id: event.donor_id.clone(),
+        total_amount: event.total_amount,
+        net_amount: event.net_amount,
+        message: event.message.clone(),
+        donated_at: checked_timestamp(event.donated_at)?,
+        protocol_fee: event.protocol_fee,
+        referrer_id: event.referrer_id.clone(),
+        referrer_fee: event.referrer_fee,
+        chef_id: event.chef_id.clone(),
+        chef_fee: event.chef_fee,
+
+        transaction_id: context.transaction_id,
+        receipt_id: context.receipt_id,
+        block_height: context.block_height,
+        block_timestamp_nanosec: context.block_timestamp_nanosec,
+    })
 }
 
 #[async_trait]
 impl PotlockEventHandler for PushToRedisStream {
     async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
-        self.donation_stream.add_event(PotlockDonationEvent {
-            donation_id: event.donation_id as u32,
-            donor_id: event.donor_id,
-            total_amount: event.total_amount,
-            ft_id: event.ft_id,
-            message: event.message,
-            donated_at: DateTime::from_timestamp_millis(event.donated_at as i64).unwrap(),
-            project_id: event.project_id,
-            protocol_fee: event.protocol_fee,
-            referrer_id: event.referrer_id,
-            referrer_fee: event.referrer_fee,
-
-            transaction_id: context.transaction_id,
-            receipt_id: context.receipt_id,
-            block_height: context.block_height,
-            block_timestamp_nanosec: context.block_timestamp_nanosec,
-        });
+        self.block_timestamp_nanosec = Some(context.block_timestamp_nanosec);
+        match donation_event(&event, &context) {
+            Ok(stream_event) => {
+                let index = self
+                    .needs_index_entries()
+                    .then(|| IndexEntry::new(&PotlockEvent::Donation(event), &context));
+                self.donation_stream.add_event(stream_event, index);
+            }
+            Err(err) => self.add_dead_letter(PotlockEvent::Donation(event), context, err),
+        }
     }
 
     async fn handle_pot_project_donation(
@@ -60,63 +879,68 @@ impl PotlockEventHandler for PushToRedisStream {
         event: PotProjectDonationEvent,
         context: EventContext,
     ) {
-        self.pot_project_donation_stream
-            .add_event(PotlockPotProjectDonationEvent {
-                donation_id: event.donation_id as u32,
-                pot_id: event.pot_id,
-                donor_id: event.donor_id,
-                total_amount: event.total_amount,
-                net_amount: event.net_amount,
-                message: event.message,
-                donated_at: DateTime::from_timestamp_millis(event.donated_at as i64).unwrap(),
-                project_id: event.project_id,
-                protocol_fee: event.protocol_fee,
-                referrer_id: event.referrer_id,
-                referrer_fee: event.referrer_fee,
-                chef_id: event.chef_id,
-                chef_fee: event.chef_fee,
-
-                transaction_id: context.transaction_id,
-                receipt_id: context.receipt_id,
-                block_height: context.block_height,
-                block_timestamp_nanosec: context.block_timestamp_nanosec,
-            });
+        self.block_timestamp_nanosec = Some(context.block_timestamp_nanosec);
+        match pot_project_donation_event(&event, &context) {
+            Ok(stream_event) => {
+                let index = self
+                    .needs_index_entries()
+                    .then(|| IndexEntry::new(&PotlockEvent::PotProjectDonation(event), &context));
+                self.pot_project_donation_stream
+                    .add_event(stream_event, index);
+            }
+            Err(err) => self.add_dead_letter(PotlockEvent::PotProjectDonation(event), context, err),
+        }
     }
 
     async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
-        self.pot_donation_stream.add_event(PotlockPotDonationEvent {
-            donation_id: event.donation_id as u32,
-            pot_id: event.pot_id,
-            donor_id: event.donor_id,
-            total_amount: event.total_amount,
-            net_amount: event.net_amount,
-            message: event.message,
-            donated_at: DateTime::from_timestamp_millis(event.donated_at as i64).unwrap(),
-            protocol_fee: event.protocol_fee,
-            referrer_id: event.referrer_id,
-            referrer_fee: event.referrer_fee,
-            chef_id: event.chef_id,
-            chef_fee: event.chef_fee,
-
-            transaction_id: context.transaction_id,
-            receipt_id: context.receipt_id,
-            block_height: context.block_height,
-            block_timestamp_nanosec: context.block_timestamp_nanosec,
-        });
-    }
-
-    async fn flush_events(&mut self, block_height: BlockHeight) {
-        self.donation_stream
-            .flush_events(block_height, self.max_stream_size)
-            .await
-            .expect("Failed to flush donation stream");
-        self.pot_project_donation_stream
-            .flush_events(block_height, self.max_stream_size)
-            .await
-            .expect("Failed to flush pot project donation stream");
-        self.pot_donation_stream
-            .flush_events(block_height, self.max_stream_size)
-            .await
-            .expect("Failed to flush pot donation stream");
+        self.block_timestamp_nanosec = Some(context.block_timestamp_nanosec);
+        match pot_donation_event(&event, &context) {
+            Ok(stream_event) => {
+                let index = self
+                    .needs_index_entries()
+                    .then(|| IndexEntry::new(&PotlockEvent::PotDonation(event), &context));
+                self.pot_donation_stream.add_event(stream_event, index);
+            }
+            Err(err) => self.add_dead_letter(PotlockEvent::PotDonation(event), context, err),
+        }
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        let _timer = metrics::flush_timer("redis");
+        let batch = self.take_batch(block_height)?;
+        let Some(spill) = &self.spill else {
+            self.unwritten.push_back(batch);
+            while let Some(batch) = self.unwritten.pop_front() {
+                if let Err(err) = self.write_block_with_retry(&batch).await {
+                    self.unwritten.push_front(batch);
+                    return Err(err);
+                }
+            }
+            return Ok(());
+        };
+        if spill.is_empty() {
+            match self.write_block_with_retry(&batch).await {
+                Ok(()) => return Ok(()),
+                Err(err) => log::warn!("Redis is unavailable, spilling events to disk: {err:#}"),
+            }
+        }
+        // Spilled blocks go first to keep the order. Blocks without events only move
+        // the checkpoint, which the next written block does anyway.
+        if batch.event_count() > 0 {
+            while self.spill.as_ref().is_some_and(Spill::is_full) {
+                if let Err(err) = self.drain_spill().await {
+                    log::error!("Spill is full, waiting for Redis: {err:#}");
+                    tokio::time::sleep(self.retry.max_backoff).await;
+                }
+            }
+            self.spill.as_mut().unwrap().push(batch)?;
+        }
+        if let Err(err) = self.drain_spill().await {
+            log::warn!(
+                "Redis is still unavailable, {} blocks spilled: {err:#}",
+                self.spill.as_ref().map_or(0, Spill::len)
+            );
+        }
+        Ok(())
     }
 }
@@ -0,0 +1,129 @@
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use tokio::sync::watch;
+
+use crate::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEventHandler,
+};
+
+/// Shared between [`ShutdownHandler`] and the code running the indexer. Cheap to clone.
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: Arc<watch::Sender<bool>>,
+    /// The handler received events that are not flushed yet, or is flushing
+    busy: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        Self {
+            requested: Arc::new(watch::Sender::new(false)),
+            busy: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    pub fn request(&self) {
+        self.requested.send_replace(true);
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    pub async fn requested(&self) {
+        let _ = self
+            .requested
+            .subscribe()
+            .wait_for(|requested| *requested)
+            .await;
+    }
+
+    /// Completes once shutdown is requested and every event given to the handler
+    /// has been flushed. The future running the indexer can be dropped at this
+    /// point without losing or half-writing a block, as long as it's polled from
+    /// the same task as this future (e.g. in `tokio::select!`).
+    pub async fn stopped(&self) {
+        self.requested().await;
+        let _ = self.busy.subscribe().wait_for(|busy| !*busy).await;
+    }
+
+    /// Requests shutdown on SIGINT or SIGTERM. A second signal exits immediately.
+    pub async fn listen_for_signals(self) -> Result<(), anyhow::Error> {
+        #[cfg(unix)]
+        let mut terminate =
+            tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+        for attempt in 0.. {
+            #[cfg(unix)]
+            tokio::select! {
+                result = tokio::signal::ctrl_c() => result?,
+                _ = terminate.recv() => {}
+            }
+            #[cfg(not(unix))]
+            tokio::signal::ctrl_c().await?;
+            if attempt == 0 {
+                log::info!("Shutting down after the current block, signal again to exit now");
+                self.request();
+            } else {
+                log::warn!("Exiting without waiting for the current block");
+                std::process::exit(130);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Tracks whether the inner handler has unflushed events for [`Shutdown::stopped`]
+pub struct ShutdownHandler<T: PotlockEventHandler> {
+    inner: T,
+    shutdown: Shutdown,
+}
+
+impl<T: PotlockEventHandler> ShutdownHandler<T> {
+    pub fn new(inner: T, shutdown: Shutdown) -> Self {
+        Self { inner, shutdown }
+    }
+
+    pub fn inner(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+#[async_trait]
+impl<T: PotlockEventHandler> PotlockEventHandler for ShutdownHandler<T> {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        self.shutdown.busy.send_replace(true);
+        self.inner.handle_donation(event, context).await;
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        self.shutdown.busy.send_replace(true);
+        self.inner.handle_pot_project_donation(event, context).await;
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        self.shutdown.busy.send_replace(true);
+        self.inner.handle_pot_donation(event, context).await;
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        self.shutdown.busy.send_replace(true);
+        let result = self.inner.flush_events(block_height).await;
+        self.shutdown.busy.send_replace(false);
+        if self.shutdown.is_requested() {
+            log::info!("Stopped after block {block_height}");
+        }
+        result
+    }
+}
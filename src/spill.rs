@@ -0,0 +1,113 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Bounded FIFO queue persisted to a JSON lines file, used to hold data while a sink
+/// is unavailable. Entries left from a previous run are loaded on open.
+///
+/// Every entry is appended with its newline, so a line without one was cut short by
+/// a crash while pushing. It's dropped on open, since its push never returned.
+pub struct Spill<T> {
+    path: PathBuf,
+    max_len: usize,
+    entries: VecDeque<T>,
+}
+
+impl<T: Serialize + DeserializeOwned> Spill<T> {
+    pub fn open(path: impl Into<PathBuf>, max_len: usize) -> Result<Self, anyhow::Error> {
+        let path = path.into();
+        let mut entries = VecDeque::new();
+        let mut truncated = false;
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.split_inclusive('\n') {
+                    let Some(line) = line.strip_suffix('\n') else {
+                        log::warn!(
+                            "Dropping incomplete last line of spill file {}",
+                            path.display()
+                        );
+                        truncated = true;
+                        break;
+                    };
+                    entries.push_back(
+                        serde_json::from_str(line)
+                            .with_context(|| format!("Corrupted spill file {}", path.display()))?,
+                    );
+                }
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+        let spill = Self {
+            path,
+            max_len,
+            entries,
+        };
+        if truncated {
+            // Otherwise the next entry would be appended to the incomplete line
+            spill.save()?;
+        }
+        Ok(spill)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.entries.len() >= self.max_len
+    }
+
+    pub fn front(&self) -> Option<&T> {
+        self.entries.front()
+    }
+
+    /// Appends to the file immediately
+    pub fn push(&mut self, entry: T) -> Result<(), anyhow::Error> {
+        anyhow::ensure!(!self.is_full(), "Spill {} is full", self.path.display());
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+        self.entries.push_back(entry);
+        Ok(())
+    }
+
+    /// Removes the first entry in memory only, call [`Spill::save`] after removing a
+    /// batch of entries
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.entries.pop_front()
+    }
+
+    /// Rewrites the file with the remaining entries
+    pub fn save(&self) -> Result<(), anyhow::Error> {
+        if self.entries.is_empty() {
+            return match std::fs::remove_file(&self.path) {
+                Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+                _ => Ok(()),
+            };
+        }
+        let temp_path = self.path.with_extension("tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        for entry in &self.entries {
+            serde_json::to_writer(&mut writer, entry)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.into_inner()?.sync_data()?;
+        std::fs::rename(temp_path, &self.path)?;
+        Ok(())
+    }
+}
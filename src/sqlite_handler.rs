@@ -0,0 +1,580 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use rusqlite::{params, params_from_iter, Connection, OpenFlags, OptionalExtension};
+
+use crate::aggregating_handler::{Amount, Totals};
+use crate::storage::{
+    Cursor, DonationFilter, DonationStore, GroupBy, Order, Page, StoredDonation, TopDonor,
+};
+use crate::{
+    metrics, DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEvent,
+    PotlockEventHandler,
+};
+
+/// `total_amount` is also split into 32-bit limbs `amount_0` to `amount_3`, least
+/// significant first, so that SQLite can sum amounts exactly
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS donations (
+    block_height INTEGER NOT NULL,
+    receipt_id TEXT NOT NULL,
+    donation_id INTEGER NOT NULL,
+    event_type TEXT NOT NULL,
+    project_id TEXT,
+    pot_id TEXT,
+    donor_id TEXT NOT NULL,
+    ft_id TEXT NOT NULL,
+    total_amount TEXT NOT NULL,
+    amount_0 INTEGER NOT NULL,
+    amount_1 INTEGER NOT NULL,
+    amount_2 INTEGER NOT NULL,
+    amount_3 INTEGER NOT NULL,
+    event TEXT NOT NULL,
+    context TEXT NOT NULL,
+    PRIMARY KEY (block_height, receipt_id, donation_id)
+);
+CREATE INDEX IF NOT EXISTS donations_project ON donations (project_id, block_height);
+CREATE INDEX IF NOT EXISTS donations_donor ON donations (donor_id, block_height);
+CREATE INDEX IF NOT EXISTS donations_pot ON donations (pot_id, block_height);
+CREATE INDEX IF NOT EXISTS donations_ft ON donations (ft_id, donor_id);
+";
+
+const SUM_AMOUNT: &str = "SUM(amount_0), SUM(amount_1), SUM(amount_2), SUM(amount_3)";
+
+/// Follows a `sums` table with limb sums `sum_0` to `sum_3`. Carries each sum into
+/// the next limb, so that amounts can be compared limb by limb with
+/// [`BY_AMOUNT_DESCENDING`].
+const CARRIED_SUMS: &str = "
+carried_1 AS (SELECT *, sum_1 + (sum_0 >> 32) AS carry_1 FROM sums),
+carried_2 AS (SELECT *, sum_2 + (carry_1 >> 32) AS carry_2 FROM carried_1),
+carried AS (SELECT *, sum_3 + (carry_2 >> 32) AS carry_3 FROM carried_2)
+";
+
+const BY_AMOUNT_DESCENDING: &str = "carry_3 DESC, carry_2 & 4294967295 DESC, carry_1 & 4294967295 DESC, sum_0 & 4294967295 DESC, donor_id";
+
+fn amount_limbs(amount: u128) -> [i64; 4] {
+    [0, 1, 2, 3].map(|limb| (amount >> (32 * limb)) as u32 as i64)
+}
+
+/// Reads the columns of [`SUM_AMOUNT`] starting at `first_column`
+fn summed_amount(row: &rusqlite::Row, first_column: usize) -> Result<Amount, anyhow::Error> {
+    let mut amount = 0u128;
+    for limb in 0..4 {
+        let sum: i64 = row.get(first_column + limb)?;
+        amount = amount.saturating_add((sum as u128).saturating_mul(1 << (32 * limb)));
+    }
+    Ok(Amount(amount))
+}
+
+/// Stores events in an SQLite database file, one row per donation. Writing the same
+/// block again doesn't create duplicates. Use [`StoreInSqlite::store`] to query it.
+///
+/// Donations of a block that failed to be written are kept and written with the
+/// next flush.
+pub struct StoreInSqlite {
+    path: PathBuf,
+    connection: Arc<Mutex<Connection>>,
+    pending: Vec<StoredDonation>,
+}
+
+impl StoreInSqlite {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, anyhow::Error> {
+        let mut connection = Connection::open(&path)?;
+        connection.pragma_update_and_check(None, "journal_mode", "WAL", |_| Ok(()))?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(Self {
+            path: path.as_ref().to_owned(),
+            connection: Arc::new(Mutex::new(connection)),
+            pending: Vec::new(),
+        })
+    }
+
+    /// Queries use their own read-only connections to the database file, so they
+    /// don't wait for writes or for each other
+    pub fn store(&self) -> SqliteDonationStore {
+        SqliteDonationStore {
+            path: Arc::new(self.path.clone()),
+            idle_connections: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+}
+
+#[async_trait]
+impl PotlockEventHandler for StoreInSqlite {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        self.pending.push(StoredDonation {
+            event: PotlockEvent::Donation(event),
+            context,
+        });
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        self.pending.push(StoredDonation {
+            event: PotlockEvent::PotProjectDonation(event),
+            context,
+        });
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        self.pending.push(StoredDonation {
+            event: PotlockEvent::PotDonation(event),
+            context,
+        });
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let _timer = metrics::flush_timer("sqlite");
+        let donations = std::mem::take(&mut self.pending);
+        let connection = Arc::clone(&self.connection);
+        let (donations, result) = tokio::task::spawn_blocking(move || {
+            let result = insert(&mut connection.lock().unwrap(), &donations);
+            (donations, result)
+        })
+        .await?;
+        if result.is_err() {
+            self.pending = donations;
+        }
+        result
+    }
+}
+
+fn insert(connection: &mut Connection, donations: &[StoredDonation]) -> Result<(), anyhow::Error> {
+    let transaction = connection.transaction()?;
+    {
+        let mut statement = transaction.prepare_cached(
+            "INSERT OR IGNORE INTO donations (block_height, receipt_id, donation_id, event_type, project_id, pot_id, donor_id, ft_id, total_amount, amount_0, amount_1, amount_2, amount_3, event, context)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )?;
+        for donation in donations {
+            let [amount_0, amount_1, amount_2, amount_3] =
+                amount_limbs(donation.event.total_amount());
+            statement.execute(params![
+                donation.context.block_height as i64,
+                donation.context.receipt_id.to_string(),
+                donation.event.donation_id() as i64,
+                donation.event.event_type(),
+                donation.event.project_id().map(|id| id.as_str()),
+                donation.event.pot_id().map(|id| id.as_str()),
+                donation.event.donor_id().as_str(),
+                donation.event.ft_id().as_str(),
+                donation.event.total_amount().to_string(),
+                amount_0,
+                amount_1,
+                amount_2,
+                amount_3,
+                serde_json::to_string(&donation.event)?,
+                serde_json::to_string(&donation.context)?,
+            ])?;
+        }
+    }
+    transaction.commit()?;
+    Ok(())
+}
+
+/// Read access to a database written by [`StoreInSqlite`]. Cheap to clone.
+#[derive(Clone)]
+pub struct SqliteDonationStore {
+    path: Arc<PathBuf>,
+    /// Read-only connections not used by a query at the moment
+    idle_connections: Arc<Mutex<Vec<Connection>>>,
+}
+
+impl SqliteDonationStore {
+    async fn query<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&Connection) -> Result<T, anyhow::Error> + Send + 'static,
+    ) -> Result<T, anyhow::Error> {
+        let path = Arc::clone(&self.path);
+        let idle_connections = Arc::clone(&self.idle_connections);
+        tokio::task::spawn_blocking(move || {
+            let idle_connection = idle_connections.lock().unwrap().pop();
+            let connection = match idle_connection {
+                Some(connection) => connection,
+                None => Connection::open_with_flags(
+                    &*path,
+                    OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+                )?,
+            };
+            let result = f(&connection);
+            idle_connections.lock().unwrap().push(connection);
+            result
+        })
+        .await?
+    }
+}
+
+/// Returns SQL conditions and their parameters
+fn filter_clause(filter: &DonationFilter) -> (Vec<String>, Vec<rusqlite::types::Value>) {
+    let mut conditions = Vec::new();
+    let mut values = Vec::new();
+    for (column, value) in [
+        ("project_id", &filter.project_id),
+        ("donor_id", &filter.donor_id),
+        ("pot_id", &filter.pot_id),
+    ] {
+        if let Some(value) = value {
+            conditions.push(format!("{column} = ?"));
+            values.push(value.to_string().into());
+        }
+    }
+    if let Some(event_type) = &filter.event_type {
+        conditions.push("event_type = ?".to_owned());
+        values.push(event_type.clone().into());
+    }
+    if let Some(from_block) = filter.from_block {
+        conditions.push("block_height >= ?".to_owned());
+        values.push((from_block as i64).into());
+    }
+    (conditions, values)
+}
+
+/// Adds the condition for donations strictly after `after` in `order`, and returns
+/// the SQL sort direction
+fn after_clause(
+    order: Order,
+    after: Option<Cursor>,
+    conditions: &mut Vec<String>,
+    values: &mut Vec<rusqlite::types::Value>,
+) -> &'static str {
+    let (comparison, direction) = match order {
+        Order::Ascending => (">", "ASC"),
+        Order::Descending => ("<", "DESC"),
+    };
+    if let Some(after) = after {
+        conditions.push(format!(
+            "(block_height, receipt_id, donation_id) {comparison} (?, ?, ?)"
+        ));
+        values.push((after.block_height as i64).into());
+        values.push(after.receipt_id.to_string().into());
+        values.push((after.donation_id as i64).into());
+    }
+    direction
+}
+
+fn group_column(group_by: GroupBy) -> &'static str {
+    match group_by {
+        GroupBy::Project => "project_id",
+        GroupBy::Pot => "pot_id",
+        GroupBy::Donor => "donor_id",
+    }
+}
+
+/// Adds the condition for `column` being one of `ids`
+fn in_clause(
+    column: &str,
+    ids: &[AccountId],
+    conditions: &mut Vec<String>,
+    values: &mut Vec<rusqlite::types::Value>,
+) {
+    conditions.push(format!("{column} IN ({})", vec!["?"; ids.len()].join(", ")));
+    values.extend(ids.iter().map(|id| id.to_string().into()));
+}
+
+/// Truncates `donations` fetched with one extra row to `limit`
+fn page(mut donations: Vec<StoredDonation>, limit: usize) -> Page {
+    let next_cursor = if donations.len() > limit {
+        donations.truncate(limit);
+        donations.last().map(StoredDonation::cursor)
+    } else {
+        None
+    };
+    Page {
+        donations,
+        next_cursor,
+    }
+}
+
+fn where_clause(conditions: &[String]) -> String {
+    if conditions.is_empty() {
+        String::new()
+    } else {
+        format!("WHERE {}", conditions.join(" AND "))
+    }
+}
+
+fn parse_row(row: &rusqlite::Row) -> Result<StoredDonation, anyhow::Error> {
+    let event: String = row.get("event")?;
+    let context: String = row.get("context")?;
+    Ok(StoredDonation {
+        event: serde_json::from_str(&event)?,
+        context: serde_json::from_str(&context)?,
+    })
+}
+
+#[async_trait]
+impl DonationStore for SqliteDonationStore {
+    async fn donations(
+        &self,
+        filter: &DonationFilter,
+        order: Order,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page, anyhow::Error> {
+        let (mut conditions, mut values) = filter_clause(filter);
+        let direction = after_clause(order, after, &mut conditions, &mut values);
+        // Fetching one more row to know if there's a next page
+        values.push((limit as i64 + 1).into());
+        let sql = format!(
+            "SELECT event, context FROM donations {} ORDER BY block_height {direction}, receipt_id {direction}, donation_id {direction} LIMIT ?",
+            where_clause(&conditions)
+        );
+        self.query(move |connection| {
+            let mut statement = connection.prepare_cached(&sql)?;
+            let mut rows = statement.query(params_from_iter(values))?;
+            let mut donations = Vec::new();
+            while let Some(row) = rows.next()? {
+                donations.push(parse_row(row)?);
+            }
+            Ok(page(donations, limit))
+        })
+        .await
+    }
+
+    async fn donations_by(
+        &self,
+        group_by: GroupBy,
+        ids: &[AccountId],
+        filter: &DonationFilter,
+        order: Order,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<HashMap<AccountId, Page>, anyhow::Error> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let column = group_column(group_by);
+        let mut filter = filter.clone();
+        *filter.group_id_mut(group_by) = None;
+        let (mut conditions, mut values) = filter_clause(&filter);
+        in_clause(column, ids, &mut conditions, &mut values);
+        let direction = after_clause(order, after, &mut conditions, &mut values);
+        // Fetching one more row per account to know if there's a next page
+        values.push((limit as i64 + 1).into());
+        let sql = format!(
+            "SELECT group_id, event, context FROM (
+                SELECT {column} AS group_id, event, context, ROW_NUMBER() OVER (PARTITION BY {column} ORDER BY block_height {direction}, receipt_id {direction}, donation_id {direction}) AS group_rank
+                FROM donations {}
+            ) WHERE group_rank <= ? ORDER BY group_id, group_rank",
+            where_clause(&conditions)
+        );
+        self.query(move |connection| {
+            let mut statement = connection.prepare_cached(&sql)?;
+            let mut rows = statement.query(params_from_iter(values))?;
+            let mut donations = HashMap::<AccountId, Vec<StoredDonation>>::new();
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(0)?;
+                donations
+                    .entry(id.parse()?)
+                    .or_default()
+                    .push(parse_row(row)?);
+            }
+            Ok(donations
+                .into_iter()
+                .map(|(id, donations)| (id, page(donations, limit)))
+                .collect())
+        })
+        .await
+    }
+
+    async fn donation(&self, cursor: Cursor) -> Result<Option<StoredDonation>, anyhow::Error> {
+        self.query(move |connection| {
+            connection
+                .prepare_cached(
+                    "SELECT event, context FROM donations WHERE block_height = ? AND receipt_id = ? AND donation_id = ?",
+                )?
+                .query_row(
+                    params![
+                        cursor.block_height as i64,
+                        cursor.receipt_id.to_string(),
+                        cursor.donation_id as i64
+                    ],
+                    |row| Ok(parse_row(row)),
+                )
+                .optional()?
+                .transpose()
+        })
+        .await
+    }
+
+    async fn project_ids(
+        &self,
+        filter: &DonationFilter,
+        after: Option<AccountId>,
+        limit: usize,
+    ) -> Result<Vec<AccountId>, anyhow::Error> {
+        let (mut conditions, mut values) = filter_clause(filter);
+        conditions.push("project_id IS NOT NULL".to_owned());
+        if let Some(after) = after {
+            conditions.push("project_id > ?".to_owned());
+            values.push(after.to_string().into());
+        }
+        values.push((limit as i64).into());
+        let sql = format!(
+            "SELECT DISTINCT project_id FROM donations {} ORDER BY project_id LIMIT ?",
+            where_clause(&conditions)
+        );
+        self.query(move |connection| {
+            let mut statement = connection.prepare_cached(&sql)?;
+            let mut rows = statement.query(params_from_iter(values))?;
+            let mut project_ids = Vec::new();
+            while let Some(row) = rows.next()? {
+                project_ids.push(row.get::<_, String>(0)?.parse()?);
+            }
+            Ok(project_ids)
+        })
+        .await
+    }
+
+    async fn totals(&self, filter: &DonationFilter) -> Result<Totals, anyhow::Error> {
+        let (conditions, values) = filter_clause(filter);
+        let counts_sql = format!(
+            "SELECT COUNT(*), COUNT(DISTINCT donor_id) FROM donations {}",
+            where_clause(&conditions)
+        );
+        let amounts_sql = format!(
+            "SELECT ft_id, {SUM_AMOUNT} FROM donations {} GROUP BY ft_id",
+            where_clause(&conditions)
+        );
+        self.query(move |connection| {
+            let (donation_count, unique_donors) = connection
+                .prepare_cached(&counts_sql)?
+                .query_row(params_from_iter(&values), |row| {
+                    Ok((row.get::<_, i64>(0)?, row.get::<_, i64>(1)?))
+                })?;
+            let mut statement = connection.prepare_cached(&amounts_sql)?;
+            let mut rows = statement.query(params_from_iter(&values))?;
+            let mut amounts = BTreeMap::<AccountId, Amount>::new();
+            while let Some(row) = rows.next()? {
+                let ft_id: String = row.get(0)?;
+                amounts.insert(ft_id.parse()?, summed_amount(row, 1)?);
+            }
+            Ok(Totals {
+                amounts,
+                donation_count: donation_count as u64,
+                unique_donors: unique_donors as u64,
+            })
+        })
+        .await
+    }
+
+    async fn totals_by(
+        &self,
+        group_by: GroupBy,
+        ids: &[AccountId],
+    ) -> Result<HashMap<AccountId, Totals>, anyhow::Error> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let column = group_column(group_by);
+        let placeholders = vec!["?"; ids.len()].join(", ");
+        let counts_sql = format!(
+            "SELECT {column}, COUNT(*), COUNT(DISTINCT donor_id) FROM donations WHERE {column} IN ({placeholders}) GROUP BY {column}"
+        );
+        let amounts_sql = format!(
+            "SELECT {column}, ft_id, {SUM_AMOUNT} FROM donations WHERE {column} IN ({placeholders}) GROUP BY {column}, ft_id"
+        );
+        let ids = ids.iter().map(AccountId::to_string).collect::<Vec<_>>();
+        self.query(move |connection| {
+            let mut totals = HashMap::<AccountId, Totals>::new();
+            let mut statement = connection.prepare_cached(&counts_sql)?;
+            let mut rows = statement.query(params_from_iter(&ids))?;
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(0)?;
+                let entry = totals.entry(id.parse()?).or_default();
+                entry.donation_count = row.get::<_, i64>(1)? as u64;
+                entry.unique_donors = row.get::<_, i64>(2)? as u64;
+            }
+            let mut statement = connection.prepare_cached(&amounts_sql)?;
+            let mut rows = statement.query(params_from_iter(&ids))?;
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(0)?;
+                let ft_id: String = row.get(1)?;
+                totals
+                    .entry(id.parse()?)
+                    .or_default()
+                    .amounts
+                    .insert(ft_id.parse()?, summed_amount(row, 2)?);
+            }
+            Ok(totals)
+        })
+        .await
+    }
+
+    async fn top_donors(
+        &self,
+        filter: &DonationFilter,
+        ft_id: &AccountId,
+        limit: usize,
+    ) -> Result<Vec<TopDonor>, anyhow::Error> {
+        let (mut conditions, mut values) = filter_clause(filter);
+        conditions.push("ft_id = ?".to_owned());
+        values.push(ft_id.to_string().into());
+        values.push((limit as i64).into());
+        let sql = format!(
+            "WITH sums AS (SELECT donor_id, COUNT(*) AS donation_count, SUM(amount_0) AS sum_0, SUM(amount_1) AS sum_1, SUM(amount_2) AS sum_2, SUM(amount_3) AS sum_3 FROM donations {} GROUP BY donor_id), {CARRIED_SUMS}
+            SELECT donor_id, donation_count, sum_0, sum_1, sum_2, sum_3 FROM carried ORDER BY {BY_AMOUNT_DESCENDING} LIMIT ?",
+            where_clause(&conditions)
+        );
+        self.query(move |connection| {
+            let mut statement = connection.prepare_cached(&sql)?;
+            let mut rows = statement.query(params_from_iter(values))?;
+            let mut donors = Vec::new();
+            while let Some(row) = rows.next()? {
+                donors.push(TopDonor {
+                    donor_id: row.get::<_, String>(0)?.parse()?,
+                    donation_count: row.get::<_, i64>(1)? as u64,
+                    amount: summed_amount(row, 2)?,
+                });
+            }
+            Ok(donors)
+        })
+        .await
+    }
+    async fn top_donors_by(
+        &self,
+        group_by: GroupBy,
+        ids: &[AccountId],
+        ft_id: &AccountId,
+        limit: usize,
+    ) -> Result<HashMap<AccountId, Vec<TopDonor>>, anyhow::Error> {
+        if ids.is_empty() {
+            return Ok(HashMap::new());
+        }
+        let column = group_column(group_by);
+        let mut conditions = vec!["ft_id = ?".to_owned()];
+        let mut values: Vec<rusqlite::types::Value> = vec![ft_id.to_string().into()];
+        in_clause(column, ids, &mut conditions, &mut values);
+        values.push((limit as i64).into());
+        let sql = format!(
+            "WITH sums AS (SELECT {column} AS group_id, donor_id, COUNT(*) AS donation_count, SUM(amount_0) AS sum_0, SUM(amount_1) AS sum_1, SUM(amount_2) AS sum_2, SUM(amount_3) AS sum_3 FROM donations {} GROUP BY {column}, donor_id), {CARRIED_SUMS},
+            ranked AS (SELECT *, ROW_NUMBER() OVER (PARTITION BY group_id ORDER BY {BY_AMOUNT_DESCENDING}) AS group_rank FROM carried)
+            SELECT group_id, donor_id, donation_count, sum_0, sum_1, sum_2, sum_3 FROM ranked WHERE group_rank <= ? ORDER BY group_id, group_rank",
+            where_clause(&conditions)
+        );
+        self.query(move |connection| {
+            let mut statement = connection.prepare_cached(&sql)?;
+            let mut rows = statement.query(params_from_iter(values))?;
+            let mut top_donors = HashMap::<AccountId, Vec<TopDonor>>::new();
+            while let Some(row) = rows.next()? {
+                let id: String = row.get(0)?;
+                top_donors.entry(id.parse()?).or_default().push(TopDonor {
+                    donor_id: row.get::<_, String>(1)?.parse()?,
+                    donation_count: row.get::<_, i64>(2)? as u64,
+                    amount: summed_amount(row, 3)?,
+                });
+            }
+            Ok(top_donors)
+        })
+        .await
+    }
+}
@@ -0,0 +1,99 @@
+use std::io::Write;
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use serde::Deserialize;
+
+use crate::live_feed::LiveEvent;
+use crate::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEvent,
+    PotlockEventHandler,
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PrintFormat {
+    /// A header line with the event type, ids and context, followed by the event
+    /// as indented JSON
+    Pretty,
+    /// One `{"event_type": ..., "event": ..., "context": ...}` object per line
+    Json,
+}
+
+impl FromStr for PrintFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pretty" => Ok(PrintFormat::Pretty),
+            "json" => Ok(PrintFormat::Json),
+            _ => anyhow::bail!("Unknown print format {s:?}, expected `pretty` or `json`"),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for PrintFormat {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// Prints events to stdout as they arrive, for debugging without writing anywhere
+pub struct PrintToStdout {
+    format: PrintFormat,
+}
+
+impl PrintToStdout {
+    pub fn new(format: PrintFormat) -> Self {
+        Self { format }
+    }
+
+    fn print(&self, event: PotlockEvent, context: EventContext) {
+        let output = match self.format {
+            PrintFormat::Pretty => format!(
+                "{} #{} in block {} (transaction {}, receipt {})\n{}\n",
+                event.event_type(),
+                event.donation_id(),
+                context.block_height,
+                context.transaction_id,
+                context.receipt_id,
+                match &event {
+                    PotlockEvent::Donation(event) => serde_json::to_string_pretty(event),
+                    PotlockEvent::PotProjectDonation(event) => serde_json::to_string_pretty(event),
+                    PotlockEvent::PotDonation(event) => serde_json::to_string_pretty(event),
+                }
+                .expect("Failed to serialize event"),
+            ),
+            PrintFormat::Json => serde_json::to_string(&LiveEvent { event, context })
+                .expect("Failed to serialize event"),
+        };
+        let mut stdout = std::io::stdout().lock();
+        // Ignoring errors, e.g. when piped to `head`
+        let _ = writeln!(stdout, "{output}");
+    }
+}
+
+#[async_trait]
+impl PotlockEventHandler for PrintToStdout {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        self.print(PotlockEvent::Donation(event), context);
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        self.print(PotlockEvent::PotProjectDonation(event), context);
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        self.print(PotlockEvent::PotDonation(event), context);
+    }
+
+    async fn flush_events(&mut self, _block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        Ok(())
+    }
+}
@@ -0,0 +1,205 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::{AccountId, BlockHeight};
+use inindexer::near_indexer_primitives::CryptoHash;
+use serde::{Deserialize, Serialize};
+
+use crate::aggregating_handler::{Amount, Totals};
+use crate::{DonationId, EventContext, PotlockEvent};
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct StoredDonation {
+    #[serde(flatten)]
+    pub event: PotlockEvent,
+    pub context: EventContext,
+}
+
+impl StoredDonation {
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            block_height: self.context.block_height,
+            receipt_id: self.context.receipt_id,
+            donation_id: self.event.donation_id(),
+        }
+    }
+}
+
+/// Position of a donation in the `(block_height, receipt_id)` order. `donation_id` is
+/// included because a single receipt can contain multiple donations.
+///
+/// Serialized as `{block_height}:{receipt_id}:{donation_id}`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Cursor {
+    pub block_height: BlockHeight,
+    pub receipt_id: CryptoHash,
+    pub donation_id: DonationId,
+}
+
+impl Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}",
+            self.block_height, self.receipt_id, self.donation_id
+        )
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let (Some(block_height), Some(receipt_id), Some(donation_id), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            anyhow::bail!("Invalid cursor, expected block_height:receipt_id:donation_id");
+        };
+        Ok(Cursor {
+            block_height: block_height.parse()?,
+            receipt_id: receipt_id
+                .parse()
+                .map_err(|err| anyhow::anyhow!("Invalid receipt id in cursor: {err}"))?,
+            donation_id: donation_id.parse()?,
+        })
+    }
+}
+
+impl Serialize for Cursor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Cursor {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+/// All set fields must match
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+pub struct DonationFilter {
+    pub project_id: Option<AccountId>,
+    pub donor_id: Option<AccountId>,
+    pub pot_id: Option<AccountId>,
+    /// `potlock_donation`, `potlock_pot_project_donation`, or `potlock_pot_donation`
+    pub event_type: Option<String>,
+    pub from_block: Option<BlockHeight>,
+}
+
+impl DonationFilter {
+    /// The field of the account that `group_by` groups by
+    pub fn group_id_mut(&mut self, group_by: GroupBy) -> &mut Option<AccountId> {
+        match group_by {
+            GroupBy::Project => &mut self.project_id,
+            GroupBy::Pot => &mut self.pot_id,
+            GroupBy::Donor => &mut self.donor_id,
+        }
+    }
+}
+
+/// Account that results are grouped by in [`DonationStore::totals_by`],
+/// [`DonationStore::donations_by`], and [`DonationStore::top_donors_by`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GroupBy {
+    Project,
+    Pot,
+    Donor,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    Ascending,
+    /// Newest first
+    #[default]
+    Descending,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Page {
+    pub donations: Vec<StoredDonation>,
+    /// Pass as `after` to get the next page. `None` if this is the last page.
+    pub next_cursor: Option<Cursor>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TopDonor {
+    pub donor_id: AccountId,
+    pub amount: Amount,
+    pub donation_count: u64,
+}
+
+/// Read side of a persistent sink
+#[async_trait]
+pub trait DonationStore: Send + Sync {
+    /// Donations matching `filter`, strictly after `after` in `order`
+    async fn donations(
+        &self,
+        filter: &DonationFilter,
+        order: Order,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<Page, anyhow::Error>;
+
+    async fn donation(&self, cursor: Cursor) -> Result<Option<StoredDonation>, anyhow::Error>;
+
+    /// Donations matching `filter` of several accounts in one query, with a page of at
+    /// most `limit` donations per account, e.g. the latest donations of every project
+    /// of a pot. The group column of `filter` is ignored. Accounts without donations
+    /// are missing from the result.
+    async fn donations_by(
+        &self,
+        group_by: GroupBy,
+        ids: &[AccountId],
+        filter: &DonationFilter,
+        order: Order,
+        after: Option<Cursor>,
+        limit: usize,
+    ) -> Result<HashMap<AccountId, Page>, anyhow::Error>;
+
+    /// Distinct projects that received donations matching `filter`, e.g. projects of a
+    /// pot. At most `limit` projects, sorted by id, strictly after `after`.
+    async fn project_ids(
+        &self,
+        filter: &DonationFilter,
+        after: Option<AccountId>,
+        limit: usize,
+    ) -> Result<Vec<AccountId>, anyhow::Error>;
+
+    /// Totals of donations matching `filter`, e.g. everything donated in a pot (round)
+    async fn totals(&self, filter: &DonationFilter) -> Result<Totals, anyhow::Error>;
+
+    /// Totals of several accounts in one query, e.g. of every project of a pot.
+    /// Accounts without donations are missing from the result.
+    async fn totals_by(
+        &self,
+        group_by: GroupBy,
+        ids: &[AccountId],
+    ) -> Result<HashMap<AccountId, Totals>, anyhow::Error>;
+
+    /// Donors with the largest total amount of `ft_id` donated among donations
+    /// matching `filter`
+    async fn top_donors(
+        &self,
+        filter: &DonationFilter,
+        ft_id: &AccountId,
+        limit: usize,
+    ) -> Result<Vec<TopDonor>, anyhow::Error>;
+
+    /// [`DonationStore::top_donors`] of several accounts in one query, e.g. of every
+    /// project of a pot. Accounts without donations are missing from the result.
+    async fn top_donors_by(
+        &self,
+        group_by: GroupBy,
+        ids: &[AccountId],
+        ft_id: &AccountId,
+        limit: usize,
+    ) -> Result<HashMap<AccountId, Vec<TopDonor>>, anyhow::Error>;
+}
@@ -0,0 +1,480 @@
+//! Fabricated blocks for decoding paths that are hard to find on-chain: malformed
+//! logs, failed calls, large batches, results returned through chains of
+//! `SuccessReceiptId`. Enabled with the `testing` feature.
+//!
+//! Blocks are assembled as neardata JSON and deserialized into [`StreamerMessage`],
+//! so they go through the same code as real blocks. Every transaction and all of its
+//! receipts are executed within the block that contains it.
+
+use std::collections::BTreeMap;
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use inindexer::message_provider::MessageProvider;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+use inindexer::near_indexer_primitives::{CryptoHash, StreamerMessage};
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::{DonationEvent, EventContext, PotlockContracts};
+
+const PUBLIC_KEY: &str = "ed25519:11111111111111111111111111111111";
+const SIGNATURE: &str = "ed25519:1111111111111111111111111111111111111111111111111111111111111111";
+
+/// What the receipt created by a transaction ends with
+#[derive(Clone, Debug)]
+pub enum CallResult {
+    Value(Vec<u8>),
+    /// The value is returned after `depth` receipts, each one returning
+    /// `SuccessReceiptId` of the next one
+    ReceiptChain {
+        depth: usize,
+        value: Vec<u8>,
+    },
+    Failure,
+}
+
+impl CallResult {
+    pub fn json(value: &impl Serialize) -> Self {
+        CallResult::Value(serde_json::to_vec(value).unwrap())
+    }
+}
+
+pub struct BlockBuilder {
+    height: BlockHeight,
+    timestamp_nanosec: u64,
+    contracts: PotlockContracts,
+    transactions: Vec<Value>,
+    receipt_outcomes: Vec<Value>,
+    hashes: u64,
+}
+
+impl BlockBuilder {
+    /// Uses the mainnet contracts for [`BlockBuilder::donation`] and
+    /// [`BlockBuilder::pot_donation`]
+    pub fn new(height: BlockHeight) -> Self {
+        Self {
+            height,
+            // One block per second since 2024-01-01
+            timestamp_nanosec: (1_704_067_200 + height) * 1_000_000_000,
+            contracts: PotlockContracts::mainnet(),
+            transactions: Vec::new(),
+            receipt_outcomes: Vec::new(),
+            hashes: 0,
+        }
+    }
+
+    pub fn timestamp_nanosec(mut self, timestamp_nanosec: u64) -> Self {
+        self.timestamp_nanosec = timestamp_nanosec;
+        self
+    }
+
+    pub fn contracts(mut self, contracts: PotlockContracts) -> Self {
+        self.contracts = contracts;
+        self
+    }
+
+    /// Adds a transaction from `signer_id` calling `method_name` on `receiver_id`. Its
+    /// receipt emits `logs` and ends with `result`.
+    pub fn function_call(
+        mut self,
+        signer_id: &str,
+        receiver_id: &str,
+        method_name: &str,
+        logs: Vec<String>,
+        result: CallResult,
+    ) -> Self {
+        let transaction_hash = self.next_hash();
+        let receipt_id = self.next_hash();
+        let action = json!({
+            "FunctionCall": {
+                "method_name": method_name,
+                "args": BASE64_STANDARD.encode("{}"),
+                "gas": 300_000_000_000_000u64,
+                "deposit": "0",
+            }
+        });
+        self.transactions.push(json!({
+            "transaction": {
+                "signer_id": signer_id,
+                "public_key": PUBLIC_KEY,
+                "nonce": self.hashes,
+                "receiver_id": receiver_id,
+                "actions": [action],
+                "priority_fee": 0,
+                "signature": SIGNATURE,
+                "hash": transaction_hash.to_string(),
+            },
+            "outcome": {
+                "execution_outcome": self.outcome(
+                    transaction_hash,
+                    signer_id,
+                    Vec::new(),
+                    json!({ "SuccessReceiptId": receipt_id.to_string() }),
+                ),
+                "receipt": null,
+            },
+        }));
+
+        let (depth, status) = match result {
+            CallResult::Value(value) => (0, success_value(&value)),
+            CallResult::ReceiptChain { depth, value } => (depth, success_value(&value)),
+            CallResult::Failure => (
+                0,
+                json!({
+                    "Failure": {
+                        "ActionError": {
+                            "index": 0,
+                            "kind": {
+                                "FunctionCallError": {
+                                    "ExecutionError": "Smart contract panicked: synthetic failure"
+                                }
+                            }
+                        }
+                    }
+                }),
+            ),
+        };
+        let (mut receipt_id, mut action, mut logs) = (receipt_id, action, logs);
+        let mut predecessor_id = signer_id;
+        for _ in 0..depth {
+            let next = self.next_hash();
+            self.push_receipt(
+                receipt_id,
+                signer_id,
+                predecessor_id,
+                receiver_id,
+                action,
+                logs,
+                json!({ "SuccessReceiptId": next.to_string() }),
+            );
+            receipt_id = next;
+            action = callback_action();
+            logs = Vec::new();
+            predecessor_id = receiver_id;
+        }
+        self.push_receipt(
+            receipt_id,
+            signer_id,
+            predecessor_id,
+            receiver_id,
+            action,
+            logs,
+            status,
+        );
+        self
+    }
+
+    /// `donate` call on the donation contract logging `donations` (see [`donation`])
+    /// in one `donation` event
+    pub fn donation(self, donor_id: &str, donations: &[Value]) -> Self {
+        let donation_contract = self.contracts.donation.to_string();
+        self.function_call(
+            donor_id,
+            &donation_contract,
+            "donate",
+            vec![donation_log(donations)],
+            CallResult::Value(Vec::new()),
+        )
+    }
+
+    /// `donate` call on `{pot}.{pot factory}` returning `result`, normally
+    /// [`CallResult::json`] of [`pot_donation`]
+    pub fn pot_donation(self, donor_id: &str, pot: &str, result: CallResult) -> Self {
+        let pot_id = format!("{pot}.{}", self.contracts.pot_factory);
+        self.function_call(donor_id, &pot_id, "donate", Vec::new(), result)
+    }
+
+    pub fn build(self) -> StreamerMessage {
+        let block_hash = CryptoHash([0xbb; 32]).to_string();
+        let zero_hash = CryptoHash::default().to_string();
+        let chunk_header = json!({
+            "chunk_hash": zero_hash,
+            "prev_block_hash": zero_hash,
+            "outcome_root": zero_hash,
+            "prev_state_root": zero_hash,
+            "encoded_merkle_root": zero_hash,
+            "encoded_length": 0,
+            "height_created": self.height,
+            "height_included": self.height,
+            "shard_id": 0,
+            "gas_used": 0,
+            "gas_limit": 1_000_000_000_000_000u64,
+            "rent_paid": "0",
+            "validator_reward": "0",
+            "balance_burnt": "0",
+            "outgoing_receipts_root": zero_hash,
+            "tx_root": zero_hash,
+            "validator_proposals": [],
+            "signature": SIGNATURE,
+        });
+        let message = json!({
+            "block": {
+                "author": "synthetic.near",
+                "header": {
+                    "height": self.height,
+                    "prev_height": self.height.checked_sub(1),
+                    "epoch_id": zero_hash,
+                    "next_epoch_id": zero_hash,
+                    "hash": block_hash,
+                    "prev_hash": zero_hash,
+                    "prev_state_root": zero_hash,
+                    "chunk_receipts_root": zero_hash,
+                    "chunk_headers_root": zero_hash,
+                    "chunk_tx_root": zero_hash,
+                    "outcome_root": zero_hash,
+                    "chunks_included": 1,
+                    "challenges_root": zero_hash,
+                    "timestamp": self.timestamp_nanosec,
+                    "timestamp_nanosec": self.timestamp_nanosec.to_string(),
+                    "random_value": zero_hash,
+                    "validator_proposals": [],
+                    "chunk_mask": [true],
+                    "gas_price": "100000000",
+                    "block_ordinal": self.height,
+                    "rent_paid": "0",
+                    "validator_reward": "0",
+                    "total_supply": "0",
+                    "challenges_result": [],
+                    "last_final_block": zero_hash,
+                    "last_ds_final_block": zero_hash,
+                    "next_bp_hash": zero_hash,
+                    "block_merkle_root": zero_hash,
+                    "approvals": [],
+                    "signature": SIGNATURE,
+                    "latest_protocol_version": 67,
+                },
+                "chunks": [chunk_header],
+            },
+            "shards": [{
+                "shard_id": 0,
+                "chunk": {
+                    "author": "synthetic.near",
+                    "header": chunk_header,
+                    "transactions": self.transactions,
+                    "receipts": [],
+                },
+                "receipt_execution_outcomes": self.receipt_outcomes,
+                "state_changes": [],
+            }],
+        });
+        serde_json::from_value(message).expect("Synthetic block doesn't match StreamerMessage")
+    }
+
+    fn next_hash(&mut self) -> CryptoHash {
+        self.hashes += 1;
+        let mut hash = [0; 32];
+        hash[..8].copy_from_slice(&self.height.to_le_bytes());
+        hash[8..16].copy_from_slice(&self.hashes.to_le_bytes());
+        CryptoHash(hash)
+    }
+
+    fn outcome(
+        &self,
+        id: CryptoHash,
+        executor_id: &str,
+        logs: Vec<String>,
+        status: Value,
+    ) -> Value {
+        let receipt_ids = match status.get("SuccessReceiptId") {
+            Some(receipt_id) => vec![receipt_id.clone()],
+            None => Vec::new(),
+        };
+        json!({
+            "proof": [],
+            "block_hash": CryptoHash([0xbb; 32]).to_string(),
+            "id": id.to_string(),
+            "outcome": {
+                "logs": logs,
+                "receipt_ids": receipt_ids,
+                "gas_burnt": 0,
+                "tokens_burnt": "0",
+                "executor_id": executor_id,
+                "status": status,
+                "metadata": { "version": 1, "gas_profile": null },
+            },
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn push_receipt(
+        &mut self,
+        receipt_id: CryptoHash,
+        signer_id: &str,
+        predecessor_id: &str,
+        receiver_id: &str,
+        action: Value,
+        logs: Vec<String>,
+        status: Value,
+    ) {
+        let outcome = self.outcome(receipt_id, receiver_id, logs, status);
+        self.receipt_outcomes.push(json!({
+            "execution_outcome": outcome,
+            "receipt": {
+                "predecessor_id": predecessor_id,
+                "receiver_id": receiver_id,
+                "receipt_id": receipt_id.to_string(),
+                "receipt": {
+                    "Action": {
+                        "signer_id": signer_id,
+                        "signer_public_key": PUBLIC_KEY,
+                        "gas_price": "100000000",
+                        "output_data_receivers": [],
+                        "input_data_ids": [],
+                        "actions": [action],
+                        "is_promise_yield": false,
+                    }
+                },
+                "priority": 0,
+            },
+        }));
+    }
+}
+
+fn success_value(value: &[u8]) -> Value {
+    json!({ "SuccessValue": BASE64_STANDARD.encode(value) })
+}
+
+fn callback_action() -> Value {
+    json!({
+        "FunctionCall": {
+            "method_name": "callback",
+            "args": BASE64_STANDARD.encode("{}"),
+            "gas": 10_000_000_000_000u64,
+            "deposit": "0",
+        }
+    })
+}
+
+/// `EVENT_JSON` log of the donation contract with `donations`
+pub fn donation_log(donations: &[Value]) -> String {
+    let data: Vec<Value> = donations
+        .iter()
+        .map(|donation| json!({ "donation": donation }))
+        .collect();
+    format!(
+        "EVENT_JSON:{}",
+        json!({
+            "standard": "potlock",
+            "version": "1.0.0",
+            "event": "donation",
+            "data": data,
+        })
+    )
+}
+
+/// Donation contract donation in NEAR without referrer, edit the fields to vary it
+pub fn donation(id: u64, donor_id: &str, recipient_id: &str, total_amount: u128) -> Value {
+    json!({
+        "id": id,
+        "donor_id": donor_id,
+        "total_amount": total_amount.to_string(),
+        "ft_id": "near",
+        "message": null,
+        "donated_at_ms": 1_704_067_200_000u64 + id,
+        "recipient_id": recipient_id,
+        "protocol_fee": (total_amount / 50).to_string(),
+        "referrer_id": null,
+        "referrer_fee": null,
+    })
+}
+
+/// Decoded direct donation of 1000 yoctoNEAR from `slimedragon.near` to
+/// `yearofchef.near` in `block_height`, for testing sinks without blocks. Use struct
+/// update syntax to vary it.
+pub fn test_donation(id: u64, block_height: BlockHeight) -> (DonationEvent, EventContext) {
+    (
+        DonationEvent {
+            donation_id: id,
+            donor_id: "slimedragon.near".parse().unwrap(),
+            total_amount: 1_000,
+            ft_id: "near".parse().unwrap(),
+            message: None,
+            donated_at: 0,
+            project_id: "yearofchef.near".parse().unwrap(),
+            protocol_fee: 0,
+            referrer_id: None,
+            referrer_fee: None,
+        },
+        EventContext {
+            transaction_id: Default::default(),
+            receipt_id: Default::default(),
+            block_height,
+            block_timestamp_nanosec: 0,
+        },
+    )
+}
+
+/// Pot donation as returned by the pot's `donate`, to the matching pool if
+/// `project_id` is `None`. Edit the fields to vary it.
+pub fn pot_donation(
+    id: u64,
+    donor_id: &str,
+    project_id: Option<&str>,
+    total_amount: u128,
+) -> Value {
+    json!({
+        "id": id,
+        "donor_id": donor_id,
+        "total_amount": total_amount.to_string(),
+        "net_amount": (total_amount - total_amount / 50).to_string(),
+        "message": null,
+        "donated_at": 1_704_067_200_000u64 + id,
+        "project_id": project_id,
+        "referrer_id": null,
+        "referrer_fee": null,
+        "protocol_fee": (total_amount / 50).to_string(),
+        "matching_pool": project_id.is_none(),
+        "chef_id": null,
+        "chef_fee": null,
+    })
+}
+
+/// Serves blocks from memory, heights without a block are skipped. Cheap to clone.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryProvider {
+    blocks: Arc<BTreeMap<BlockHeight, StreamerMessage>>,
+}
+
+impl MemoryProvider {
+    pub fn new(blocks: impl IntoIterator<Item = StreamerMessage>) -> Self {
+        Self {
+            blocks: Arc::new(
+                blocks
+                    .into_iter()
+                    .map(|block| (block.block.header.height, block))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Heights of the blocks, in increasing order
+    pub fn heights(&self) -> impl Iterator<Item = BlockHeight> + '_ {
+        self.blocks.keys().copied()
+    }
+}
+
+#[derive(Debug)]
+pub enum MemoryProviderError {}
+
+impl Display for MemoryProviderError {
+    fn fmt(&self, _f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {}
+    }
+}
+
+impl std::error::Error for MemoryProviderError {}
+
+#[async_trait]
+impl MessageProvider for MemoryProvider {
+    type Error = MemoryProviderError;
+
+    async fn get_message(
+        &self,
+        block_height: BlockHeight,
+    ) -> Result<Option<StreamerMessage>, Self::Error> {
+        Ok(self.blocks.get(&block_height).cloned())
+    }
+}
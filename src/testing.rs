@@ -0,0 +1,148 @@
+//! Helpers for testing code built on [`PotlockIndexer`](crate::PotlockIndexer).
+//! Enabled with the `testing` feature.
+
+use async_trait::async_trait;
+use inindexer::near_indexer_primitives::types::BlockHeight;
+
+use crate::{
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEvent,
+    PotlockEventHandler,
+};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Record {
+    Event(PotlockEvent, EventContext),
+    Flush(BlockHeight),
+}
+
+/// Records every event and flush in the order they were received
+#[derive(Clone, Debug, Default)]
+pub struct RecordingHandler {
+    pub records: Vec<Record>,
+}
+
+impl RecordingHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = (&PotlockEvent, &EventContext)> {
+        self.records.iter().filter_map(|record| match record {
+            Record::Event(event, context) => Some((event, context)),
+            Record::Flush(_) => None,
+        })
+    }
+
+    pub fn donations(&self) -> Vec<(DonationEvent, EventContext)> {
+        self.events()
+            .filter_map(|(event, context)| match event {
+                PotlockEvent::Donation(event) => Some((event.clone(), context.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn pot_project_donations(&self) -> Vec<(PotProjectDonationEvent, EventContext)> {
+        self.events()
+            .filter_map(|(event, context)| match event {
+                PotlockEvent::PotProjectDonation(event) => Some((event.clone(), context.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    pub fn pot_donations(&self) -> Vec<(PotDonationEvent, EventContext)> {
+        self.events()
+            .filter_map(|(event, context)| match event {
+                PotlockEvent::PotDonation(event) => Some((event.clone(), context.clone())),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Events of any type from `donor_id`
+    pub fn events_from(&self, donor_id: &str) -> Vec<(PotlockEvent, EventContext)> {
+        self.events()
+            .filter(|(event, _)| event.donor_id().as_str() == donor_id)
+            .map(|(event, context)| (event.clone(), context.clone()))
+            .collect()
+    }
+
+    pub fn flushed_blocks(&self) -> Vec<BlockHeight> {
+        self.records
+            .iter()
+            .filter_map(|record| match record {
+                Record::Flush(block_height) => Some(*block_height),
+                Record::Event(..) => None,
+            })
+            .collect()
+    }
+
+    /// Panics unless blocks were flushed in increasing order and every event was
+    /// flushed with the block it belongs to, before any later block
+    #[track_caller]
+    pub fn assert_flushed_in_order(&self) {
+        let mut last_flushed = None;
+        let mut pending = Vec::new();
+        for record in &self.records {
+            match record {
+                Record::Event(event, context) => pending.push((event, context.block_height)),
+                Record::Flush(block_height) => {
+                    assert!(
+                        last_flushed.is_none_or(|last| last < *block_height),
+                        "Block {block_height} flushed after block {last_flushed:?}"
+                    );
+                    for (event, event_block) in pending.drain(..) {
+                        assert_eq!(
+                            event_block, *block_height,
+                            "Event from block {event_block} flushed with block {block_height}: {event:?}"
+                        );
+                    }
+                    last_flushed = Some(*block_height);
+                }
+            }
+        }
+        assert!(pending.is_empty(), "Events never flushed: {pending:?}");
+    }
+
+    /// Panics unless exactly one event from `donor_id` was recorded, and returns it
+    #[track_caller]
+    pub fn assert_single_event_from(&self, donor_id: &str) -> (PotlockEvent, EventContext) {
+        let mut events = self.events_from(donor_id);
+        assert_eq!(
+            events.len(),
+            1,
+            "Expected one event from {donor_id}, got {events:?}"
+        );
+        events.remove(0)
+    }
+}
+
+#[async_trait]
+impl PotlockEventHandler for RecordingHandler {
+    async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+        self.records
+            .push(Record::Event(PotlockEvent::Donation(event), context));
+    }
+
+    async fn handle_pot_project_donation(
+        &mut self,
+        event: PotProjectDonationEvent,
+        context: EventContext,
+    ) {
+        self.records.push(Record::Event(
+            PotlockEvent::PotProjectDonation(event),
+            context,
+        ));
+    }
+
+    async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+        self.records
+            .push(Record::Event(PotlockEvent::PotDonation(event), context));
+    }
+
+    async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+        self.records.push(Record::Flush(block_height));
+        Ok(())
+    }
+}
@@ -1,74 +1,73 @@
-use std::collections::HashMap;
-
 use async_trait::async_trait;
 use inindexer::{
     near_indexer_primitives::types::{AccountId, BlockHeight},
-    neardata::NeardataProvider,
     run_indexer, BlockRange, IndexerOptions, PreprocessTransactionsSettings,
 };
 
+use crate::file_provider::FileProvider;
+use crate::synthetic::test_donation;
+use crate::testing::RecordingHandler;
 use crate::{
-    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEventHandler,
-    PotlockIndexer,
+    DonationEvent, EventContext, PotDonationEvent, PotProjectDonationEvent, PotlockEvent,
+    PotlockEventHandler, PotlockIndexer,
 };
 
-#[tokio::test]
-async fn detects_pot_project_donations() {
-    struct TestHandler {
-        events: HashMap<AccountId, Vec<(PotProjectDonationEvent, EventContext)>>,
-    }
+/// Blocks recorded with `cargo run -- download --compress --directory
+/// tests/fixtures/blocks --from <block> --to <block>`
+const FIXTURES_DIRECTORY: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/blocks");
 
-    #[async_trait]
-    impl PotlockEventHandler for TestHandler {
-        async fn handle_pot_project_donation(
-            &mut self,
-            event: PotProjectDonationEvent,
-            context: EventContext,
-        ) {
-            self.events
-                .entry(event.donor_id.clone())
-                .or_default()
-                .push((event, context));
-        }
-
-        async fn handle_pot_donation(&mut self, _event: PotDonationEvent, _context: EventContext) {}
-
-        async fn handle_donation(&mut self, _event: DonationEvent, _context: EventContext) {}
-
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
-    }
-
-    let handler = TestHandler {
-        events: HashMap::new(),
+/// Runs the indexer over the recorded blocks. Fails if any height of `range` wasn't
+/// recorded, tests never fetch blocks from the network.
+async fn run_on_fixtures<T: PotlockEventHandler + 'static>(
+    indexer: &mut PotlockIndexer<T>,
+    range: std::ops::Range<BlockHeight>,
+) {
+    let options = IndexerOptions {
+        preprocess_transactions: Some(PreprocessTransactionsSettings {
+            prefetch_blocks: 0,
+            postfetch_blocks: 0,
+        }),
+        ctrl_c_handler: false,
+        ..IndexerOptions::default_with_range(BlockRange::Range {
+            start_inclusive: range.start,
+            end_exclusive: Some(range.end),
+        })
     };
+    let directory = std::path::Path::new(FIXTURES_DIRECTORY);
+    let missing = range
+        .clone()
+        .filter(|block_height| {
+            ["json", "json.gz", "skipped"].iter().all(|extension| {
+                !directory
+                    .join(format!("{block_height}.{extension}"))
+                    .exists()
+            })
+        })
+        .collect::<Vec<_>>();
+    assert!(
+        missing.is_empty(),
+        "Blocks {missing:?} are not recorded, run `cargo run -- download --compress --directory \
+         tests/fixtures/blocks --from {} --to {}`",
+        range.start,
+        range.end
+    );
+    run_indexer(indexer, FileProvider::new(directory), options)
+        .await
+        .unwrap();
+}
 
-    let mut indexer = PotlockIndexer(handler);
+#[tokio::test]
+#[ignore = "needs blocks recorded in tests/fixtures/blocks"]
+async fn detects_pot_project_donations() {
+    let mut indexer = PotlockIndexer::new(RecordingHandler::new());
 
-    run_indexer(
-        &mut indexer,
-        NeardataProvider::mainnet(),
-        IndexerOptions {
-            preprocess_transactions: Some(PreprocessTransactionsSettings {
-                prefetch_blocks: 0,
-                postfetch_blocks: 0,
-            }),
-            ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 118_091_724,
-                end_exclusive: Some(118_091_730),
-            })
-        },
-    )
-    .await
-    .unwrap();
+    run_on_fixtures(&mut indexer, 118_091_724..118_091_730).await;
 
+    indexer.0.assert_flushed_in_order();
     assert_eq!(
-        *indexer
-            .0
-            .events
-            .get(&"slimedragon.near".parse::<AccountId>().unwrap())
-            .unwrap(),
-        vec![(
-            PotProjectDonationEvent {
+        indexer.0.assert_single_event_from("slimedragon.near"),
+        (
+            PotlockEvent::PotProjectDonation(PotProjectDonationEvent {
                 donation_id: 61,
                 pot_id: "oss.v1.potfactory.potlock.near".parse().unwrap(),
                 donor_id: "slimedragon.near".parse().unwrap(),
@@ -82,7 +81,7 @@ async fn detects_pot_project_donations() {
                 protocol_fee: 2000000000000000000000,
                 chef_id: None,
                 chef_fee: None
-            },
+            }),
             EventContext {
                 transaction_id: "8iTg4kPTnLQneAmrPDF1iURs5UjrrZEHxsXkLbcJA4r3"
                     .parse()
@@ -93,165 +92,1769 @@ async fn detects_pot_project_donations() {
                 block_height: 118091729,
                 block_timestamp_nanosec: 1714655076382528345,
             }
-        )]
+        )
     );
 }
 
 #[tokio::test]
+#[ignore = "needs blocks recorded in tests/fixtures/blocks"]
 async fn detects_pot_donations() {
-    struct TestHandler {
-        events: HashMap<AccountId, Vec<(PotDonationEvent, EventContext)>>,
+    let mut indexer = PotlockIndexer::new(RecordingHandler::new());
+
+    run_on_fixtures(&mut indexer, 118_159_852..118_159_855).await;
+
+    indexer.0.assert_flushed_in_order();
+    assert_eq!(
+        indexer.0.assert_single_event_from("slimedragon.near"),
+        (
+            PotlockEvent::PotDonation(PotDonationEvent {
+                donation_id: 83,
+                pot_id: "oss.v1.potfactory.potlock.near".parse().unwrap(),
+                donor_id: "slimedragon.near".parse().unwrap(),
+                total_amount: 10000000000000000000000,
+                net_amount: 0,
+                message: Some("Testing gh/INTEARnear/potlock-indexer because it's hard to find existing transactions to test on".to_owned()),
+                donated_at: 1714741415342, referrer_id: None, referrer_fee: None, protocol_fee: 0, chef_id: None, chef_fee: None
+            }),
+            EventContext {
+                transaction_id: "mGdDKMFHj7omhumVA8exQEvSZSjZNCDud7eBprVR87c".parse().unwrap(),
+                receipt_id: "3CatRDqy1ZbL3Uo3p1GwVch4STyCooHNGUzzSLH9dQCa".parse().unwrap(),
+                block_height: 118159854,
+                block_timestamp_nanosec: 1714741416164254763,
+            }
+        )
+    );
+}
+
+#[tokio::test]
+#[ignore = "needs blocks recorded in tests/fixtures/blocks"]
+async fn detects_direct_donations() {
+    let mut indexer = PotlockIndexer::new(RecordingHandler::new());
+
+    run_on_fixtures(&mut indexer, 118_100_096..118_100_103).await;
+
+    indexer.0.assert_flushed_in_order();
+    assert_eq!(
+        indexer.0.assert_single_event_from("xslymn.near"),
+        (
+            PotlockEvent::Donation(DonationEvent {
+                donation_id: 2211,
+                donor_id: "xslymn.near".parse().unwrap(),
+                total_amount: 500000000000000000000000,
+                ft_id: "near".parse().unwrap(),
+                message: None,
+                donated_at: 1714665141842,
+                project_id: "indexers.intear.near".parse().unwrap(),
+                protocol_fee: 12500000000000000000000,
+                referrer_id: None,
+                referrer_fee: None
+            }),
+            EventContext {
+                transaction_id: "3VRcmqbc73KKNhPHaSWyaYyNz57c2QjDVTkTDRTXR6L7"
+                    .parse()
+                    .unwrap(),
+                receipt_id: "DcTBNA52876rEw73fFaxZqWzkagatQj8MpWn2cQZU33X"
+                    .parse()
+                    .unwrap(),
+                block_height: 118100099,
+                block_timestamp_nanosec: 1714665143709556816,
+            }
+        )
+    );
+}
+
+#[tokio::test]
+async fn aggregates_totals_per_project_pot_donor_and_token() {
+    use crate::aggregating_handler::{
+        AggregateKind, AggregateSink, AggregateUpdatedEvent, Aggregates, AggregatingHandler,
+        Amount, UniqueDonors,
+    };
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct TestSink {
+        donors: UniqueDonors,
     }
 
     #[async_trait]
-    impl PotlockEventHandler for TestHandler {
-        async fn handle_pot_project_donation(
+    impl<'a> AggregateSink for &'a mut TestSink {
+        async fn publish(
             &mut self,
-            _event: PotProjectDonationEvent,
-            _context: EventContext,
-        ) {
+            _updates: Vec<AggregateUpdatedEvent>,
+        ) -> Result<(), anyhow::Error> {
+            Ok(())
         }
 
-        async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
-            self.events
-                .entry(event.donor_id.clone())
-                .or_default()
-                .push((event, context));
+        async fn add_unique_donors(
+            &mut self,
+            donors: &UniqueDonors,
+        ) -> Result<HashMap<(AggregateKind, AccountId), u64>, anyhow::Error> {
+            Ok(donors
+                .iter()
+                .map(|(key, donors)| {
+                    let known = self.donors.entry(key.clone()).or_default();
+                    known.extend(donors.iter().cloned());
+                    (key.clone(), known.len() as u64)
+                })
+                .collect())
+        }
+
+        async fn save_snapshot(
+            &mut self,
+            _block_height: BlockHeight,
+            _aggregates: &Aggregates,
+        ) -> Result<(), anyhow::Error> {
+            Ok(())
+        }
+    }
+
+    let context = |block_height| EventContext {
+        transaction_id: Default::default(),
+        receipt_id: Default::default(),
+        block_height,
+        block_timestamp_nanosec: 0,
+    };
+    let account = |id: &str| id.parse::<AccountId>().unwrap();
+    let pot_project_donation = |donor_id: &str, total_amount| PotProjectDonationEvent {
+        donation_id: 1,
+        pot_id: account("oss.v1.potfactory.potlock.near"),
+        donor_id: account(donor_id),
+        total_amount,
+        net_amount: 0,
+        message: None,
+        donated_at: 0,
+        project_id: account("nearcatalog.near"),
+        referrer_id: None,
+        referrer_fee: None,
+        protocol_fee: 0,
+        chef_id: None,
+        chef_fee: None,
+    };
+
+    let mut sink = TestSink::default();
+    let mut handler = AggregatingHandler::new(&mut sink, None, 10);
+    handler
+        .handle_pot_project_donation(pot_project_donation("alice.near", 100), context(1))
+        .await;
+    handler
+        .handle_pot_project_donation(pot_project_donation("alice.near", 50), context(1))
+        .await;
+    handler
+        .handle_pot_project_donation(pot_project_donation("bob.near", 1), context(1))
+        .await;
+    handler.flush_events(1).await.unwrap();
+
+    let project = handler
+        .aggregates()
+        .get(AggregateKind::Project, &account("nearcatalog.near"))
+        .unwrap();
+    assert_eq!(project.amounts.get(&account("near")), Some(&Amount(151)));
+    assert_eq!(project.donation_count, 3);
+    assert_eq!(project.unique_donors, 2);
+    let donor = handler
+        .aggregates()
+        .get(AggregateKind::Donor, &account("alice.near"))
+        .unwrap();
+    assert_eq!(donor.amounts.get(&account("near")), Some(&Amount(150)));
+    assert_eq!(donor.donation_count, 2);
+
+    let snapshot = handler.aggregates().clone();
+    drop(handler);
+
+    // Resuming from a snapshot ignores blocks that are already counted, and the sink
+    // keeps the donors it already has
+    let mut handler = AggregatingHandler::new(&mut sink, Some((1, snapshot)), 10);
+    handler
+        .handle_pot_project_donation(pot_project_donation("alice.near", 100), context(1))
+        .await;
+    handler
+        .handle_pot_project_donation(pot_project_donation("carol.near", 1), context(2))
+        .await;
+    handler.flush_events(2).await.unwrap();
+    let project = handler
+        .aggregates()
+        .get(AggregateKind::Project, &account("nearcatalog.near"))
+        .unwrap();
+    assert_eq!(project.amounts.get(&account("near")), Some(&Amount(152)));
+    assert_eq!(project.unique_donors, 3);
+}
+
+#[tokio::test]
+async fn aggregates_keep_updates_until_published_and_snapshot_complete_blocks() {
+    use crate::aggregating_handler::{
+        AggregateKind, AggregateSink, AggregateUpdatedEvent, Aggregates, AggregatingHandler,
+        UniqueDonors,
+    };
+    use std::collections::HashMap;
+
+    #[derive(Default)]
+    struct FlakySink {
+        fail_next_publish: bool,
+        published: Vec<AggregateUpdatedEvent>,
+        snapshots: Vec<BlockHeight>,
+    }
+
+    #[async_trait]
+    impl<'a> AggregateSink for &'a mut FlakySink {
+        async fn publish(
+            &mut self,
+            updates: Vec<AggregateUpdatedEvent>,
+        ) -> Result<(), anyhow::Error> {
+            anyhow::ensure!(
+                !std::mem::take(&mut self.fail_next_publish),
+                "Redis is down"
+            );
+            self.published.extend(updates);
+            Ok(())
+        }
+
+        async fn add_unique_donors(
+            &mut self,
+            _donors: &UniqueDonors,
+        ) -> Result<HashMap<(AggregateKind, AccountId), u64>, anyhow::Error> {
+            Ok(HashMap::new())
+        }
+
+        async fn save_snapshot(
+            &mut self,
+            block_height: BlockHeight,
+            _aggregates: &Aggregates,
+        ) -> Result<(), anyhow::Error> {
+            self.snapshots.push(block_height);
+            Ok(())
+        }
+    }
+
+    let mut sink = FlakySink {
+        fail_next_publish: true,
+        ..Default::default()
+    };
+    let mut handler = AggregatingHandler::new(&mut sink, None, 100);
+    let (event, context) = test_donation(1, 1);
+    handler.handle_donation(event, context).await;
+    assert!(handler.flush_events(1).await.is_err());
+    // Not saved, the updates of block 1 would never be published after a restart
+    handler.save_snapshot().await.unwrap();
+    // Updates of block 1 are published with the next block
+    handler.flush_events(2).await.unwrap();
+    let (event, context) = test_donation(3, 3);
+    handler.handle_donation(event, context).await;
+    handler.flush_events(3).await.unwrap();
+    // Not saved, block 4 is only partially counted
+    let (event, context) = test_donation(4, 4);
+    handler.handle_donation(event, context).await;
+    handler.save_snapshot().await.unwrap();
+    handler.flush_events(4).await.unwrap();
+    handler.save_snapshot().await.unwrap();
+    drop(handler);
+
+    assert!(sink
+        .published
+        .iter()
+        .any(|update| update.kind == AggregateKind::Project
+            && update.id.as_str() == "yearofchef.near"
+            && update.block_height == 2));
+    assert_eq!(sink.snapshots, [2, 4]);
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn sqlite_store_paginates_and_ignores_duplicates() {
+    use crate::sqlite_handler::StoreInSqlite;
+    use crate::storage::{DonationFilter, DonationStore, Order};
+
+    let path = std::env::temp_dir().join(format!("potlock-sqlite-test-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut handler = StoreInSqlite::open(&path).unwrap();
+    let store = handler.store();
+    // The same block twice, as if the indexer was restarted
+    for _ in 0..2 {
+        for (donation_id, block_height) in [(1, 10), (2, 10), (3, 11)] {
+            let (event, context) = test_donation(donation_id, block_height);
+            handler.handle_donation(event, context).await;
+        }
+        handler.flush_events(11).await.unwrap();
+    }
+    let (event, context) = test_donation(4, 12);
+    let event = DonationEvent {
+        donor_id: "bob.near".parse().unwrap(),
+        ..event
+    };
+    handler.handle_donation(event, context).await;
+    handler.flush_events(12).await.unwrap();
+
+    let filter = DonationFilter {
+        project_id: Some("yearofchef.near".parse().unwrap()),
+        ..Default::default()
+    };
+    let first_page = store
+        .donations(&filter, Order::Descending, None, 3)
+        .await
+        .unwrap();
+    assert_eq!(
+        first_page
+            .donations
+            .iter()
+            .map(|donation| donation.event.donation_id())
+            .collect::<Vec<_>>(),
+        vec![4, 3, 2]
+    );
+    let second_page = store
+        .donations(&filter, Order::Descending, first_page.next_cursor, 3)
+        .await
+        .unwrap();
+    assert_eq!(second_page.donations.len(), 1);
+    assert_eq!(second_page.donations[0].event.donation_id(), 1);
+    assert_eq!(second_page.next_cursor, None);
+
+    let totals = store.totals(&filter).await.unwrap();
+    assert_eq!(totals.donation_count, 4);
+    assert_eq!(totals.unique_donors, 2);
+    let top_donors = store
+        .top_donors(&filter, &"near".parse().unwrap(), 1)
+        .await
+        .unwrap();
+    assert_eq!(
+        top_donors[0].donor_id,
+        "slimedragon.near".parse::<AccountId>().unwrap()
+    );
+    assert_eq!(top_donors[0].donation_count, 3);
+    drop((handler, store));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+}
+
+#[cfg(feature = "sqlite")]
+#[tokio::test]
+async fn sqlite_store_sums_and_ranks_amounts_exactly() {
+    use crate::aggregating_handler::Amount;
+    use crate::sqlite_handler::StoreInSqlite;
+    use crate::storage::{DonationFilter, DonationStore};
+
+    let path = std::env::temp_dir().join(format!(
+        "potlock-sqlite-amounts-test-{}.db",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let mut handler = StoreInSqlite::open(&path).unwrap();
+    // Alice's sum overflows i64, and Bob's lowest limbs carry into the next one, so
+    // he is ahead of Carol although her donation has a larger second limb
+    for (donation_id, donor_id, total_amount) in [
+        (1, "alice.near", u128::MAX / 2),
+        (2, "alice.near", u128::MAX / 2),
+        (3, "bob.near", u32::MAX as u128),
+        (4, "bob.near", u32::MAX as u128),
+        (5, "bob.near", u32::MAX as u128),
+        (6, "carol.near", 1 << 32),
+    ] {
+        let (event, context) = test_donation(donation_id, 10);
+        let event = DonationEvent {
+            donor_id: donor_id.parse().unwrap(),
+            total_amount,
+            ..event
+        };
+        handler.handle_donation(event, context).await;
+    }
+    handler.flush_events(10).await.unwrap();
+
+    let store = handler.store();
+    let filter = DonationFilter {
+        donor_id: Some("alice.near".parse().unwrap()),
+        ..Default::default()
+    };
+    let totals = store.totals(&filter).await.unwrap();
+    assert_eq!(totals.donation_count, 2);
+    assert_eq!(totals.unique_donors, 1);
+    assert_eq!(
+        totals.amounts[&"near".parse::<AccountId>().unwrap()],
+        Amount(u128::MAX - 1)
+    );
+    let top_donors = store
+        .top_donors(&DonationFilter::default(), &"near".parse().unwrap(), 10)
+        .await
+        .unwrap();
+    assert_eq!(
+        top_donors
+            .iter()
+            .map(|top_donor| (top_donor.donor_id.as_str(), top_donor.amount))
+            .collect::<Vec<_>>(),
+        [
+            ("alice.near", Amount(u128::MAX - 1)),
+            ("bob.near", Amount(3 * u32::MAX as u128)),
+            ("carol.near", Amount(1 << 32)),
+        ]
+    );
+    drop((handler, store));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+}
+
+/// Records the store methods called and how many accounts each call loaded
+#[cfg(all(feature = "graphql", feature = "sqlite"))]
+struct CountingStore {
+    inner: crate::sqlite_handler::SqliteDonationStore,
+    calls: std::sync::Mutex<Vec<(&'static str, usize)>>,
+}
+
+#[cfg(all(feature = "graphql", feature = "sqlite"))]
+impl CountingStore {
+    fn record(&self, method: &'static str, accounts: usize) {
+        self.calls.lock().unwrap().push((method, accounts));
+    }
+}
+
+#[cfg(all(feature = "graphql", feature = "sqlite"))]
+#[async_trait]
+impl crate::storage::DonationStore for CountingStore {
+    async fn donations(
+        &self,
+        filter: &crate::storage::DonationFilter,
+        order: crate::storage::Order,
+        after: Option<crate::storage::Cursor>,
+        limit: usize,
+    ) -> Result<crate::storage::Page, anyhow::Error> {
+        self.record("donations", 1);
+        self.inner.donations(filter, order, after, limit).await
+    }
+
+    async fn donation(
+        &self,
+        cursor: crate::storage::Cursor,
+    ) -> Result<Option<crate::storage::StoredDonation>, anyhow::Error> {
+        self.record("donation", 1);
+        self.inner.donation(cursor).await
+    }
+
+    async fn donations_by(
+        &self,
+        group_by: crate::storage::GroupBy,
+        ids: &[AccountId],
+        filter: &crate::storage::DonationFilter,
+        order: crate::storage::Order,
+        after: Option<crate::storage::Cursor>,
+        limit: usize,
+    ) -> Result<std::collections::HashMap<AccountId, crate::storage::Page>, anyhow::Error> {
+        self.record("donations_by", ids.len());
+        self.inner
+            .donations_by(group_by, ids, filter, order, after, limit)
+            .await
+    }
+
+    async fn project_ids(
+        &self,
+        filter: &crate::storage::DonationFilter,
+        after: Option<AccountId>,
+        limit: usize,
+    ) -> Result<Vec<AccountId>, anyhow::Error> {
+        self.record("project_ids", 1);
+        self.inner.project_ids(filter, after, limit).await
+    }
+
+    async fn totals(
+        &self,
+        filter: &crate::storage::DonationFilter,
+    ) -> Result<crate::aggregating_handler::Totals, anyhow::Error> {
+        self.record("totals", 1);
+        self.inner.totals(filter).await
+    }
+
+    async fn totals_by(
+        &self,
+        group_by: crate::storage::GroupBy,
+        ids: &[AccountId],
+    ) -> Result<
+        std::collections::HashMap<AccountId, crate::aggregating_handler::Totals>,
+        anyhow::Error,
+    > {
+        self.record("totals_by", ids.len());
+        self.inner.totals_by(group_by, ids).await
+    }
+
+    async fn top_donors(
+        &self,
+        filter: &crate::storage::DonationFilter,
+        ft_id: &AccountId,
+        limit: usize,
+    ) -> Result<Vec<crate::storage::TopDonor>, anyhow::Error> {
+        self.record("top_donors", 1);
+        self.inner.top_donors(filter, ft_id, limit).await
+    }
+
+    async fn top_donors_by(
+        &self,
+        group_by: crate::storage::GroupBy,
+        ids: &[AccountId],
+        ft_id: &AccountId,
+        limit: usize,
+    ) -> Result<std::collections::HashMap<AccountId, Vec<crate::storage::TopDonor>>, anyhow::Error>
+    {
+        self.record("top_donors_by", ids.len());
+        self.inner.top_donors_by(group_by, ids, ft_id, limit).await
+    }
+}
+
+#[cfg(all(feature = "graphql", feature = "sqlite"))]
+#[tokio::test]
+async fn graphql_batches_lookups_and_limits_queries() {
+    use crate::graphql::schema;
+    use crate::live_feed::LiveFeed;
+    use crate::sqlite_handler::StoreInSqlite;
+    use std::sync::Arc;
+
+    let path = std::env::temp_dir().join(format!("potlock-graphql-test-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut handler = StoreInSqlite::open(&path).unwrap();
+    for (donation_id, donor_id, project_id) in [
+        (1, "alice.near", "project-a.near"),
+        (2, "bob.near", "project-a.near"),
+        (3, "alice.near", "project-b.near"),
+    ] {
+        handler
+            .handle_pot_project_donation(
+                PotProjectDonationEvent {
+                    donation_id,
+                    pot_id: "round.v1.potfactory.potlock.near".parse().unwrap(),
+                    donor_id: donor_id.parse().unwrap(),
+                    total_amount: 1_000,
+                    net_amount: 980,
+                    message: None,
+                    donated_at: 0,
+                    project_id: project_id.parse().unwrap(),
+                    referrer_id: None,
+                    referrer_fee: None,
+                    protocol_fee: 20,
+                    chef_id: None,
+                    chef_fee: None,
+                },
+                EventContext {
+                    transaction_id: Default::default(),
+                    receipt_id: Default::default(),
+                    block_height: 10,
+                    block_timestamp_nanosec: 0,
+                },
+            )
+            .await;
+    }
+    handler.flush_events(10).await.unwrap();
+    let store = Arc::new(CountingStore {
+        inner: handler.store(),
+        calls: Default::default(),
+    });
+    let schema = schema(store.clone(), LiveFeed::new(10));
+
+    let response = schema
+        .execute(
+            r#"{
+                pot(id: "round.v1.potfactory.potlock.near") {
+                    totals { donationCount uniqueDonors }
+                    projects(limit: 1) {
+                        projects {
+                            id
+                            totals { donationCount amounts { ftId amount } }
+                        }
+                        nextCursor
+                    }
+                }
+            }"#,
+        )
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(
+        response.data.into_json().unwrap(),
+        serde_json::json!({
+            "pot": {
+                "totals": { "donationCount": 3, "uniqueDonors": 2 },
+                "projects": {
+                    "projects": [
+                        {
+                            "id": "project-a.near",
+                            "totals": {
+                                "donationCount": 2,
+                                "amounts": [{ "ftId": "near", "amount": "2000" }],
+                            },
+                        },
+                    ],
+                    "nextCursor": "project-a.near",
+                },
+            }
+        })
+    );
+
+    store.calls.lock().unwrap().clear();
+    let response = schema
+        .execute(
+            r#"{
+                pot(id: "round.v1.potfactory.potlock.near") {
+                    projects(after: "project-a.near") { projects { id } nextCursor }
+                    allProjects: projects {
+                        projects {
+                            id
+                            totals { donationCount }
+                            donations(limit: 1, order: ASCENDING) { donations { donationId } }
+                            topDonors { donor { id } amount }
+                        }
+                    }
+                }
+            }"#,
+        )
+        .await;
+    assert!(response.errors.is_empty(), "{:?}", response.errors);
+    assert_eq!(
+        response.data.into_json().unwrap(),
+        serde_json::json!({
+            "pot": {
+                "projects": {
+                    "projects": [{ "id": "project-b.near" }],
+                    "nextCursor": null,
+                },
+                "allProjects": {
+                    "projects": [
+                        {
+                            "id": "project-a.near",
+                            "totals": { "donationCount": 2 },
+                            "donations": { "donations": [{ "donationId": 1 }] },
+                            "topDonors": [
+                                { "donor": { "id": "alice.near" }, "amount": "1000" },
+                                { "donor": { "id": "bob.near" }, "amount": "1000" },
+                            ],
+                        },
+                        {
+                            "id": "project-b.near",
+                            "totals": { "donationCount": 1 },
+                            "donations": { "donations": [{ "donationId": 3 }] },
+                            "topDonors": [
+                                { "donor": { "id": "alice.near" }, "amount": "1000" },
+                            ],
+                        },
+                    ],
+                },
+            }
+        })
+    );
+    // Both projects in one query for each kind of data
+    let mut calls = store.calls.lock().unwrap().clone();
+    calls.sort();
+    assert_eq!(
+        calls,
+        [
+            ("donations_by", 2),
+            ("project_ids", 1),
+            ("project_ids", 1),
+            ("top_donors_by", 2),
+            ("totals_by", 2),
+        ]
+    );
+
+    let nested =
+        "projects(limit: 1) { projects { donations(limit: 1) { donations { pot { ".repeat(3);
+    let too_deep = format!(
+        "{{ pot(id: \"round.v1.potfactory.potlock.near\") {{ {nested} id {} }} }}",
+        " } } } } }".repeat(3)
+    );
+    let response = schema.execute(too_deep).await;
+    assert!(response.errors[0].message.contains("nested too deep"));
+
+    let too_complex = r#"{
+        donations(limit: 100) {
+            donations {
+                donor { donations(limit: 100) { donations { donationId totalAmount } } }
+            }
+        }
+    }"#;
+    let response = schema.execute(too_complex).await;
+    assert!(response.errors[0].message.contains("too complex"));
+
+    drop((schema, store, handler));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+}
+
+#[cfg(all(feature = "graphql", feature = "sqlite"))]
+#[tokio::test]
+async fn graphql_subscription_replays_backlog_and_fails_when_lagging() {
+    use crate::graphql::schema;
+    use crate::live_feed::{LiveEvent, LiveFeed};
+    use crate::sqlite_handler::StoreInSqlite;
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    let path = std::env::temp_dir().join(format!(
+        "potlock-graphql-subscription-test-{}.db",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let handler = StoreInSqlite::open(&path).unwrap();
+    let feed = LiveFeed::new(1);
+    let schema = schema(Arc::new(handler.store()), feed.clone());
+    let event = |donation_id| {
+        let (event, context) = test_donation(donation_id, 10);
+        LiveEvent {
+            event: PotlockEvent::Donation(event),
+            context,
+        }
+    };
+
+    let mut stream = schema.execute_stream("subscription { donations { donationId } }");
+    // Subscribes to the feed
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(100), stream.next())
+            .await
+            .is_err()
+    );
+    feed.publish([event(1)]);
+    let response = stream.next().await.unwrap();
+    assert_eq!(
+        response.data.into_json().unwrap(),
+        serde_json::json!({ "donations": { "donationId": 1 } })
+    );
+    // More than the channel holds
+    feed.publish([event(2), event(3), event(4)]);
+    let response = stream.next().await.unwrap();
+    assert!(response.errors[0].message.contains("fell behind"));
+    assert!(stream.next().await.is_none());
+
+    // The backlog holds the last event, which is sent again from its block
+    let mut stream = schema
+        .execute_stream("subscription { donations(filter: { fromBlock: 10 }) { donationId } }");
+    let response = stream.next().await.unwrap();
+    assert_eq!(
+        response.data.into_json().unwrap(),
+        serde_json::json!({ "donations": { "donationId": 4 } })
+    );
+    let mut stream = schema
+        .execute_stream("subscription { donations(filter: { fromBlock: 11 }) { donationId } }");
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(100), stream.next())
+            .await
+            .is_err()
+    );
+
+    drop((schema, handler));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+}
+
+#[cfg(all(feature = "grpc", feature = "sqlite"))]
+#[tokio::test]
+async fn grpc_subscribe_replays_then_tails_without_gaps() {
+    use crate::grpc::proto::potlock_server::Potlock;
+    use crate::grpc::{proto, PotlockService};
+    use crate::live_feed::{LiveEvent, LiveFeed};
+    use crate::sqlite_handler::StoreInSqlite;
+    use futures::StreamExt;
+    use std::sync::Arc;
+
+    let path = std::env::temp_dir().join(format!("potlock-grpc-test-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut handler = StoreInSqlite::open(&path).unwrap();
+    // More than the subscriber buffer, so that the replay waits for the client
+    for block_height in 1..=250 {
+        let (event, context) = test_donation(block_height, block_height);
+        handler.handle_donation(event, context).await;
+    }
+    handler.flush_events(250).await.unwrap();
+    // Holds a single event, overflows unless the replay has caught up before subscribing
+    let feed = LiveFeed::new(1);
+    let service = PotlockService::new(Arc::new(handler.store()), feed.clone());
+    let live_event = |block_height| {
+        let (event, context) = test_donation(block_height, block_height);
+        LiveEvent {
+            event: PotlockEvent::Donation(event),
+            context,
         }
+    };
 
-        async fn handle_donation(&mut self, _event: DonationEvent, _context: EventContext) {}
+    let mut stream = service
+        .subscribe(tonic::Request::new(proto::SubscribeRequest {
+            filter: None,
+            from_block: Some(2),
+        }))
+        .await
+        .unwrap()
+        .into_inner();
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    // Indexed while the client is still reading the replay
+    for block_height in 251..=255 {
+        let (event, context) = test_donation(block_height, block_height);
+        handler.handle_donation(event, context).await;
+        handler.flush_events(block_height).await.unwrap();
+        feed.publish([live_event(block_height)]);
+    }
+    // Published but not stored yet
+    feed.publish([live_event(256)]);
 
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
+    let mut received = Vec::new();
+    while received.len() < 255 {
+        let event = stream.next().await.unwrap().unwrap();
+        let Some(proto::event::Event::Donation(donation)) = event.event else {
+            panic!("Expected a donation, got {event:?}");
+        };
+        received.push((event.context.unwrap().block_height, donation.donation_id));
     }
+    assert_eq!(
+        received,
+        (2..=256)
+            .map(|block_height| (block_height, block_height))
+            .collect::<Vec<_>>()
+    );
+    feed.publish([live_event(257)]);
+    let event = stream.next().await.unwrap().unwrap();
+    assert_eq!(event.context.unwrap().block_height, 257);
+    assert!(
+        tokio::time::timeout(std::time::Duration::from_millis(100), stream.next())
+            .await
+            .is_err()
+    );
+
+    drop((stream, service, handler));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+}
 
-    let handler = TestHandler {
-        events: HashMap::new(),
+#[cfg(all(feature = "grpc", feature = "sqlite"))]
+#[tokio::test]
+async fn grpc_gets_donations() {
+    use crate::grpc::proto::potlock_server::Potlock;
+    use crate::grpc::{proto, PotlockService};
+    use crate::live_feed::LiveFeed;
+    use crate::sqlite_handler::StoreInSqlite;
+    use std::sync::Arc;
+
+    let path = std::env::temp_dir().join(format!(
+        "potlock-grpc-get-donation-test-{}.db",
+        std::process::id()
+    ));
+    let _ = std::fs::remove_file(&path);
+    let mut handler = StoreInSqlite::open(&path).unwrap();
+    let (event, context) = test_donation(10, 10);
+    handler.handle_donation(event, context).await;
+    handler.flush_events(10).await.unwrap();
+    let service = PotlockService::new(Arc::new(handler.store()), LiveFeed::new(1));
+    let request = |block_height, receipt_id: &str, donation_id| {
+        tonic::Request::new(proto::GetDonationRequest {
+            block_height,
+            receipt_id: receipt_id.to_string(),
+            donation_id,
+        })
     };
+    let receipt_id = inindexer::near_indexer_primitives::CryptoHash::default().to_string();
 
-    let mut indexer = PotlockIndexer(handler);
+    let event = service
+        .get_donation(request(10, &receipt_id, 10))
+        .await
+        .unwrap()
+        .into_inner();
+    let (expected_event, expected_context) = test_donation(10, 10);
+    assert_eq!(
+        event,
+        crate::grpc::event_to_proto(&PotlockEvent::Donation(expected_event), &expected_context)
+    );
+    let status = service
+        .get_donation(request(10, &receipt_id, 11))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::NotFound);
+    let status = service
+        .get_donation(request(10, "not a hash", 10))
+        .await
+        .unwrap_err();
+    assert_eq!(status.code(), tonic::Code::InvalidArgument);
+
+    drop((service, handler));
+    for suffix in ["", "-wal", "-shm"] {
+        let _ = std::fs::remove_file(format!("{}{suffix}", path.display()));
+    }
+}
 
+#[cfg(feature = "export")]
+fn export_test_events() -> Vec<(DonationEvent, EventContext)> {
+    let donation = |donation_id, block_height, referrer_id: Option<&str>| {
+        let (event, context) = test_donation(donation_id, block_height);
+        (
+            DonationEvent {
+                total_amount: u128::MAX,
+                message: referrer_id.is_none().then(|| "gm".to_string()),
+                donated_at: 1_700_000_000_000,
+                protocol_fee: 1,
+                referrer_id: referrer_id.map(|id| id.parse().unwrap()),
+                referrer_fee: referrer_id.map(|_| 2),
+                ..event
+            },
+            EventContext {
+                // 2023-11-14
+                block_timestamp_nanosec: 1_700_000_000_000_000_000,
+                ..context
+            },
+        )
+    };
+    vec![
+        donation(1, 10, None),
+        donation(2, 11, Some("referrer.near")),
+    ]
+}
+
+#[cfg(feature = "export")]
+#[tokio::test]
+async fn exported_parquet_reads_back() {
+    use crate::export_handler::{ExportFormat, ExportToFiles};
+    use arrow_array::{Array, StringArray, UInt64Array};
+    use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+    let directory =
+        std::env::temp_dir().join(format!("potlock-export-parquet-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    let mut handler = ExportToFiles::new(&directory, ExportFormat::Parquet, 100, 1_000);
+    for (event, context) in export_test_events() {
+        let block_height = context.block_height;
+        handler.handle_donation(event, context).await;
+        handler.flush_events(block_height).await.unwrap();
+    }
+    handler.finish().await.unwrap();
+
+    let file =
+        std::fs::File::open(directory.join("potlock_donation/date=2023-11-14/10-11.parquet"))
+            .unwrap();
+    let batches = ParquetRecordBatchReaderBuilder::try_new(file)
+        .unwrap()
+        .build()
+        .unwrap()
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+    assert_eq!(batches.len(), 1);
+    let batch = &batches[0];
+    let column = |name: &str| batch.column_by_name(name).unwrap().clone();
+    let strings = |name: &str| {
+        column(name)
+            .as_any()
+            .downcast_ref::<StringArray>()
+            .unwrap()
+            .clone()
+    };
+    let numbers = |name: &str| {
+        column(name)
+            .as_any()
+            .downcast_ref::<UInt64Array>()
+            .unwrap()
+            .values()
+            .to_vec()
+    };
+    assert_eq!(numbers("donation_id"), [1, 2]);
+    assert_eq!(numbers("block_height"), [10, 11]);
+    assert_eq!(strings("total_amount").value(0), u128::MAX.to_string());
+    assert_eq!(strings("message").value(0), "gm");
+    assert!(strings("message").is_null(1));
+    assert!(strings("referrer_id").is_null(0));
+    assert_eq!(strings("referrer_id").value(1), "referrer.near");
+    assert_eq!(strings("referrer_fee").value(1), "2");
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[cfg(feature = "export")]
+#[tokio::test]
+async fn exported_csv_reads_back() {
+    use crate::export_handler::{ExportFormat, ExportToFiles};
+
+    let directory = std::env::temp_dir().join(format!("potlock-export-csv-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    let mut handler = ExportToFiles::new(&directory, ExportFormat::Csv, 100, 1_000);
+    for (event, context) in export_test_events() {
+        let block_height = context.block_height;
+        handler.handle_donation(event, context).await;
+        handler.flush_events(block_height).await.unwrap();
+    }
+    handler.finish().await.unwrap();
+
+    let mut reader =
+        csv::Reader::from_path(directory.join("potlock_donation/date=2023-11-14/10-11.csv"))
+            .unwrap();
+    let headers = reader.headers().unwrap().clone();
+    let rows = reader
+        .records()
+        .map(|record| {
+            let record = record.unwrap();
+            headers
+                .iter()
+                .zip(record.iter())
+                .map(|(header, field)| (header.to_string(), field.to_string()))
+                .collect::<std::collections::HashMap<_, _>>()
+        })
+        .collect::<Vec<_>>();
+    assert_eq!(rows.len(), 2);
+    assert_eq!(rows[0]["donation_id"], "1");
+    assert_eq!(rows[0]["total_amount"], u128::MAX.to_string());
+    assert_eq!(rows[0]["message"], "gm");
+    assert_eq!(rows[0]["referrer_id"], "");
+    assert_eq!(rows[1]["referrer_id"], "referrer.near");
+    assert_eq!(rows[1]["block_timestamp_nanosec"], "1700000000000000000");
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[cfg(feature = "export")]
+#[tokio::test]
+async fn export_writes_rows_after_max_buffered_blocks() {
+    use crate::export_handler::{ExportFormat, ExportToFiles};
+
+    let directory =
+        std::env::temp_dir().join(format!("potlock-export-buffered-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    let mut handler = ExportToFiles::new(&directory, ExportFormat::Csv, 100, 1);
+    let day = directory.join("potlock_donation/date=2023-11-14");
+    let mut events = export_test_events().into_iter();
+    let (event, context) = events.next().unwrap();
+    handler.handle_donation(event, context).await;
+    handler.flush_events(10).await.unwrap();
+    assert!(!day.exists());
+    let (event, context) = events.next().unwrap();
+    handler.handle_donation(event, context).await;
+    handler.flush_events(11).await.unwrap();
+    let files = std::fs::read_dir(&day)
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(files, ["10-11.csv"]);
+    std::fs::remove_dir_all(&directory).unwrap();
+}
+
+#[cfg(feature = "export")]
+#[tokio::test]
+async fn export_fails_flush_on_out_of_range_timestamp() {
+    use crate::export_handler::{ExportFormat, ExportToFiles};
+
+    let directory =
+        std::env::temp_dir().join(format!("potlock-export-invalid-{}", std::process::id()));
+    let mut handler = ExportToFiles::new(&directory, ExportFormat::Csv, 100, 1_000);
+    let (event, mut context) = export_test_events().remove(0);
+    context.block_timestamp_nanosec = u128::MAX;
+    handler.handle_donation(event, context).await;
+    assert!(handler.flush_events(10).await.is_err());
+    handler.finish().await.unwrap();
+    assert!(!directory.exists());
+}
+
+#[test]
+fn parses_redis_retention() {
+    use crate::redis_handler::Retention;
+    use std::time::Duration;
+
+    assert_eq!(
+        "unlimited".parse::<Retention>().unwrap(),
+        Retention::Unlimited
+    );
+    assert_eq!(
+        "10000".parse::<Retention>().unwrap(),
+        Retention::MaxLen(10_000)
+    );
+    assert_eq!(
+        "30m".parse::<Retention>().unwrap(),
+        Retention::MaxAge(Duration::from_secs(30 * 60))
+    );
+    assert_eq!(
+        "7d".parse::<Retention>().unwrap(),
+        Retention::MaxAge(Duration::from_secs(7 * 24 * 60 * 60))
+    );
+    assert!("7w".parse::<Retention>().is_err());
+    assert!("d".parse::<Retention>().is_err());
+}
+
+#[test]
+fn spill_keeps_order_across_reopen() {
+    use crate::spill::Spill;
+
+    let path = std::env::temp_dir().join(format!("potlock-spill-test-{}", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let mut spill = Spill::<u64>::open(&path, 3).unwrap();
+    spill.push(1).unwrap();
+    spill.push(2).unwrap();
+    spill.push(3).unwrap();
+    assert!(spill.is_full());
+    assert!(spill.push(4).is_err());
+
+    let mut spill = Spill::<u64>::open(&path, 3).unwrap();
+    assert_eq!(spill.len(), 3);
+    assert_eq!(spill.pop_front(), Some(1));
+    spill.save().unwrap();
+
+    let mut spill = Spill::<u64>::open(&path, 3).unwrap();
+    assert_eq!(spill.front(), Some(&2));
+    assert_eq!(spill.pop_front(), Some(2));
+    assert_eq!(spill.pop_front(), Some(3));
+    spill.save().unwrap();
+    assert!(!path.exists());
+}
+
+#[test]
+fn spill_drops_incomplete_last_line() {
+    use crate::spill::Spill;
+
+    let path = std::env::temp_dir().join(format!(
+        "potlock-spill-truncated-test-{}",
+        std::process::id()
+    ));
+    std::fs::write(&path, "1\n2\n3").unwrap();
+    let mut spill = Spill::<u64>::open(&path, 3).unwrap();
+    assert_eq!(spill.len(), 2);
+    spill.push(4).unwrap();
+
+    let mut spill = Spill::<u64>::open(&path, 3).unwrap();
+    assert_eq!(spill.pop_front(), Some(1));
+    assert_eq!(spill.pop_front(), Some(2));
+    assert_eq!(spill.pop_front(), Some(4));
+
+    // Only the last line can be incomplete
+    std::fs::write(&path, "1\nx\n2\n").unwrap();
+    assert!(Spill::<u64>::open(&path, 3).is_err());
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn redis_conversions_reject_out_of_range_values() {
+    use crate::redis_handler::{checked_donation_id, checked_timestamp, donation_event};
+    use crate::{DonationEvent, EventContext};
+
+    assert_eq!(checked_donation_id(0).unwrap(), 0);
+    assert_eq!(checked_donation_id(u32::MAX as u64).unwrap(), u32::MAX);
+    assert!(checked_donation_id(u32::MAX as u64 + 1).is_err());
+    assert!(checked_donation_id(u64::MAX).is_err());
+
+    assert_eq!(checked_timestamp(0).unwrap().timestamp_millis(), 0);
+    assert_eq!(
+        checked_timestamp(1_715_000_000_000)
+            .unwrap()
+            .timestamp_millis(),
+        1_715_000_000_000
+    );
+    // The last millisecond of year 262142, the max supported by chrono
+    assert_eq!(
+        checked_timestamp(8_210_266_876_799_999)
+            .unwrap()
+            .timestamp_millis(),
+        8_210_266_876_799_999
+    );
+    assert!(checked_timestamp(8_210_266_876_800_000).is_err());
+    assert!(checked_timestamp(i64::MAX as u64).is_err());
+    assert!(checked_timestamp(i64::MAX as u64 + 1).is_err());
+    assert!(checked_timestamp(u64::MAX).is_err());
+
+    let (event, context) = test_donation(u32::MAX as u64 + 1, 1);
+    let event = DonationEvent {
+        donated_at: 1_715_000_000_000,
+        ..event
+    };
+    let context = EventContext {
+        block_timestamp_nanosec: 1_715_000_000_000_000_000,
+        ..context
+    };
+    let err = donation_event(&event, &context).unwrap_err();
+    assert_eq!(
+        format!("{err:#}"),
+        "Donation id 4294967296 doesn't fit in u32"
+    );
+    let event = DonationEvent {
+        donation_id: 1,
+        donated_at: u64::MAX,
+        ..event
+    };
+    assert!(donation_event(&event, &context).is_err());
+}
+
+#[test]
+fn config_reports_all_problems() {
+    use crate::config::{Config, Network};
+
+    let config: Config = toml::from_str(
+        r#"
+        network = "testnet"
+        sinks = ["redis", "sqlite"]
+
+        [filter]
+        event_types = ["potlock_donation", "potlock_refund"]
+
+        [indexer]
+        start_block = 100
+        end_block = 50
+        "#,
+    )
+    .unwrap();
+    assert_eq!(config.network, Network::Testnet);
+    assert_eq!(
+        config.contracts().donation,
+        "donate.potlock.testnet".parse::<AccountId>().unwrap()
+    );
+    let err = config.validate().unwrap_err().to_string();
+    assert!(err.contains("redis.url"), "{err}");
+    assert!(err.contains("sqlite.path"), "{err}");
+    assert!(err.contains("indexer.end_block"), "{err}");
+    assert!(err.contains("\"potlock_refund\""), "{err}");
+
+    assert!(toml::from_str::<Config>("sink = [\"redis\"]").is_err());
+}
+
+#[tokio::test]
+async fn backfill_merges_chunks_in_block_order() {
+    use crate::backfill::{backfill, BackfillOptions};
+    let directory =
+        std::env::temp_dir().join(format!("potlock-backfill-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    let mut sink = RecordingHandler::new();
+    backfill(
+        &mut sink,
+        BackfillOptions {
+            range: 100..130,
+            chunk_size: 10,
+            concurrency: 3,
+            checkpoint_directory: directory.clone(),
+        },
+        |chunk| async move {
+            // Later chunks finish first
+            tokio::time::sleep(std::time::Duration::from_millis(130 - chunk.end)).await;
+            let (event, context) = test_donation(chunk.start, chunk.start + 5);
+            Ok(vec![(PotlockEvent::Donation(event), context)])
+        },
+    )
+    .await
+    .unwrap();
+    sink.assert_flushed_in_order();
+    assert_eq!(sink.flushed_blocks(), [105, 109, 115, 119, 125, 129]);
+    assert_eq!(
+        sink.donations()
+            .iter()
+            .map(|(event, _)| event.donation_id)
+            .collect::<Vec<_>>(),
+        [100, 110, 120]
+    );
+    // Fully merged, nothing left to resume
+    assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn downloaded_blocks_read_back() {
+    use crate::file_provider::download;
+    use crate::synthetic::{self, BlockBuilder, MemoryProvider};
+    use inindexer::message_provider::MessageProvider;
+
+    let directory =
+        std::env::temp_dir().join(format!("potlock-download-test-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    // No block at 3
+    let source = MemoryProvider::new([1, 2, 4].map(|height| {
+        BlockBuilder::new(height)
+            .donation(
+                "alice.near",
+                &[synthetic::donation(height, "alice.near", "project.near", 1)],
+            )
+            .build()
+    }));
+    assert_eq!(
+        download(&source, 1..4, &directory, false, 2).await.unwrap(),
+        2
+    );
+    assert!(directory.join("3.skipped").exists());
+    assert_eq!(
+        download(&source, 1..5, &directory, true, 2).await.unwrap(),
+        1
+    );
+
+    let provider = FileProvider::new(&directory);
+    for height in [1, 2, 4] {
+        let block = provider.get_message(height).await.unwrap().unwrap();
+        assert_eq!(
+            serde_json::to_value(&block).unwrap(),
+            serde_json::to_value(source.get_message(height).await.unwrap().unwrap()).unwrap()
+        );
+    }
+    assert!(provider.get_message(3).await.unwrap().is_none());
+    // Never downloaded
+    assert!(provider.get_message(5).await.is_err());
+    std::fs::remove_dir_all(directory).unwrap();
+}
+
+#[tokio::test]
+async fn decodes_synthetic_edge_cases() {
+    use crate::synthetic::{self, BlockBuilder, CallResult, MemoryProvider};
+    let block = BlockBuilder::new(1000)
+        .donation(
+            "alice.near",
+            &[
+                synthetic::donation(1, "alice.near", "project-a.near", 1_000),
+                synthetic::donation(2, "alice.near", "project-b.near", 2_000),
+            ],
+        )
+        .function_call(
+            "bob.near",
+            "donate.potlock.near",
+            "donate",
+            vec![synthetic::donation_log(&[
+                serde_json::json!({ "id": "not a number" }),
+            ])],
+            CallResult::Value(Vec::new()),
+        )
+        .pot_donation(
+            "carol.near",
+            "round",
+            CallResult::ReceiptChain {
+                depth: 3,
+                value: serde_json::to_vec(&synthetic::pot_donation(
+                    3,
+                    "carol.near",
+                    Some("project-a.near"),
+                    3_000,
+                ))
+                .unwrap(),
+            },
+        )
+        .pot_donation("dave.near", "round", CallResult::Failure)
+        .pot_donation(
+            "erin.near",
+            "round",
+            CallResult::json(&synthetic::pot_donation(4, "erin.near", None, 4_000)),
+        )
+        .build();
+    let mut indexer = PotlockIndexer::new(RecordingHandler::new());
     run_indexer(
         &mut indexer,
-        NeardataProvider::mainnet(),
+        MemoryProvider::new([block]),
         IndexerOptions {
             preprocess_transactions: Some(PreprocessTransactionsSettings {
                 prefetch_blocks: 0,
                 postfetch_blocks: 0,
             }),
+            ctrl_c_handler: false,
             ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 118_159_852,
-                end_exclusive: Some(118_159_855),
+                start_inclusive: 1000,
+                end_exclusive: Some(1001),
             })
         },
     )
     .await
     .unwrap();
 
+    indexer.0.assert_flushed_in_order();
     assert_eq!(
-        *indexer
+        indexer
             .0
-            .events
-            .get(&"slimedragon.near".parse::<AccountId>().unwrap())
-            .unwrap(),
-        vec![(
-            PotDonationEvent {
-                donation_id: 83,
-                pot_id: "oss.v1.potfactory.potlock.near".parse().unwrap(),
-                donor_id: "slimedragon.near".parse().unwrap(),
-                total_amount: 10000000000000000000000,
-                net_amount: 0,
-                message: Some("Testing gh/INTEARnear/potlock-indexer because it's hard to find existing transactions to test on".to_owned()),
-                donated_at: 1714741415342, referrer_id: None, referrer_fee: None, protocol_fee: 0, chef_id: None, chef_fee: None
+            .donations()
+            .iter()
+            .map(|(event, _)| event.donation_id)
+            .collect::<Vec<_>>(),
+        [1, 2]
+    );
+    assert!(indexer.0.events_from("bob.near").is_empty());
+    assert!(indexer.0.events_from("dave.near").is_empty());
+    let (event, _) = indexer.0.assert_single_event_from("carol.near");
+    assert_eq!(
+        event.pot_id().unwrap().as_str(),
+        "round.v1.potfactory.potlock.near"
+    );
+    assert_eq!(event.project_id().unwrap().as_str(), "project-a.near");
+    let (event, _) = indexer.0.assert_single_event_from("erin.near");
+    assert_eq!(event.event_type(), "potlock_pot_donation");
+    assert_eq!(event.project_id(), None);
+}
+
+#[tokio::test]
+async fn decodes_donation_variants() {
+    use crate::synthetic::{self, BlockBuilder, CallResult, MemoryProvider};
+
+    let mut empty_message = synthetic::donation(1, "alice.near", "project.near", 1_000);
+    empty_message["message"] = "".into();
+    let mut referred = synthetic::donation(2, "alice.near", "project.near", 1_000);
+    referred["referrer_id"] = "referrer.near".into();
+    referred["referrer_fee"] = "10".into();
+    let mut ft_donation = synthetic::donation(3, "bob.near", "project.near", 5_000_000);
+    ft_donation["ft_id"] = "usdt.tether-token.near".into();
+    let mut pot_donation = synthetic::pot_donation(5, "carol.near", Some("project.near"), 1_000);
+    pot_donation["referrer_id"] = "referrer.near".into();
+    pot_donation["referrer_fee"] = "10".into();
+    pot_donation["chef_id"] = "chef.near".into();
+    pot_donation["chef_fee"] = "50".into();
+    let block = BlockBuilder::new(1000)
+        .donation("alice.near", &[empty_message, referred])
+        .function_call(
+            "usdt.tether-token.near",
+            "donate.potlock.near",
+            "ft_on_transfer",
+            vec![synthetic::donation_log(&[ft_donation])],
+            CallResult::json(&"0"),
+        )
+        // Reverted, although the log is kept
+        .function_call(
+            "dave.near",
+            "donate.potlock.near",
+            "donate",
+            vec![synthetic::donation_log(&[synthetic::donation(
+                4,
+                "dave.near",
+                "project.near",
+                1_000,
+            )])],
+            CallResult::Failure,
+        )
+        .pot_donation("carol.near", "round", CallResult::json(&pot_donation))
+        .build();
+    let mut indexer = PotlockIndexer::new(RecordingHandler::new());
+    run_indexer(
+        &mut indexer,
+        MemoryProvider::new([block]),
+        IndexerOptions {
+            preprocess_transactions: Some(PreprocessTransactionsSettings {
+                prefetch_blocks: 0,
+                postfetch_blocks: 0,
+            }),
+            ctrl_c_handler: false,
+            ..IndexerOptions::default_with_range(BlockRange::Range {
+                start_inclusive: 1000,
+                end_exclusive: Some(1001),
+            })
+        },
+    )
+    .await
+    .unwrap();
+
+    let donation = |donation_id, donor_id: &str, total_amount, ft_id: &str| DonationEvent {
+        donation_id,
+        donor_id: donor_id.parse().unwrap(),
+        total_amount,
+        ft_id: ft_id.parse().unwrap(),
+        message: None,
+        donated_at: 1_704_067_200_000 + donation_id,
+        project_id: "project.near".parse().unwrap(),
+        protocol_fee: total_amount / 50,
+        referrer_id: None,
+        referrer_fee: None,
+    };
+    assert_eq!(
+        indexer
+            .0
+            .donations()
+            .into_iter()
+            .map(|(event, _)| event)
+            .collect::<Vec<_>>(),
+        [
+            // An empty message is no message
+            donation(1, "alice.near", 1_000, "near"),
+            DonationEvent {
+                referrer_id: Some("referrer.near".parse().unwrap()),
+                referrer_fee: Some(10),
+                ..donation(2, "alice.near", 1_000, "near")
             },
-            EventContext {
-                transaction_id: "mGdDKMFHj7omhumVA8exQEvSZSjZNCDud7eBprVR87c".parse().unwrap(),
-                receipt_id: "3CatRDqy1ZbL3Uo3p1GwVch4STyCooHNGUzzSLH9dQCa".parse().unwrap(),
-                block_height: 118159854,
-                block_timestamp_nanosec: 1714741416164254763,
-            }
-        )]
+            donation(3, "bob.near", 5_000_000, "usdt.tether-token.near"),
+        ]
+    );
+    assert!(indexer.0.events_from("dave.near").is_empty());
+    assert_eq!(
+        indexer.0.assert_single_event_from("carol.near").0,
+        PotlockEvent::PotProjectDonation(PotProjectDonationEvent {
+            donation_id: 5,
+            pot_id: "round.v1.potfactory.potlock.near".parse().unwrap(),
+            donor_id: "carol.near".parse().unwrap(),
+            total_amount: 1_000,
+            net_amount: 980,
+            message: None,
+            donated_at: 1_704_067_200_005,
+            project_id: "project.near".parse().unwrap(),
+            referrer_id: Some("referrer.near".parse().unwrap()),
+            referrer_fee: Some(10),
+            protocol_fee: 20,
+            chef_id: Some("chef.near".parse().unwrap()),
+            chef_fee: Some(50),
+        })
     );
 }
 
 #[tokio::test]
-async fn detects_direct_donations() {
-    struct TestHandler {
-        events: HashMap<AccountId, Vec<(DonationEvent, EventContext)>>,
-    }
+async fn stops_after_failed_flush() {
+    use crate::synthetic::{BlockBuilder, MemoryProvider};
+
+    struct FailingHandler(RecordingHandler);
 
     #[async_trait]
-    impl PotlockEventHandler for TestHandler {
+    impl PotlockEventHandler for FailingHandler {
+        async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
+            self.0.handle_donation(event, context).await;
+        }
+
         async fn handle_pot_project_donation(
             &mut self,
-            _event: PotProjectDonationEvent,
-            _context: EventContext,
+            event: PotProjectDonationEvent,
+            context: EventContext,
         ) {
+            self.0.handle_pot_project_donation(event, context).await;
         }
 
-        async fn handle_pot_donation(&mut self, _event: PotDonationEvent, _context: EventContext) {}
-
-        async fn handle_donation(&mut self, event: DonationEvent, context: EventContext) {
-            self.events
-                .entry(event.donor_id.clone())
-                .or_default()
-                .push((event, context));
+        async fn handle_pot_donation(&mut self, event: PotDonationEvent, context: EventContext) {
+            self.0.handle_pot_donation(event, context).await;
         }
 
-        async fn flush_events(&mut self, _block_height: BlockHeight) {}
+        async fn flush_events(&mut self, block_height: BlockHeight) -> Result<(), anyhow::Error> {
+            self.0.flush_events(block_height).await?;
+            anyhow::ensure!(block_height != 1001, "Sink is down");
+            Ok(())
+        }
     }
 
-    let handler = TestHandler {
-        events: HashMap::new(),
-    };
-
-    let mut indexer = PotlockIndexer(handler);
-
-    run_indexer(
+    let mut indexer = PotlockIndexer::new(FailingHandler(RecordingHandler::new()));
+    // Even without `stop_on_error`, nothing after the failed block reaches the handler
+    let _ = run_indexer(
         &mut indexer,
-        NeardataProvider::mainnet(),
+        MemoryProvider::new((1000..1004).map(|height| BlockBuilder::new(height).build())),
         IndexerOptions {
             preprocess_transactions: Some(PreprocessTransactionsSettings {
                 prefetch_blocks: 0,
                 postfetch_blocks: 0,
             }),
+            ctrl_c_handler: false,
             ..IndexerOptions::default_with_range(BlockRange::Range {
-                start_inclusive: 118_100_096,
-                end_exclusive: Some(118_100_103),
+                start_inclusive: 1000,
+                end_exclusive: Some(1004),
             })
         },
     )
+    .await;
+    assert_eq!(indexer.0 .0.flushed_blocks(), [1000, 1001]);
+}
+
+/// Redis tests only run if `REDIS_TEST_URL` is set, e.g.
+/// `REDIS_TEST_URL=redis://localhost cargo test redis_`. Each test writes under its
+/// own key prefix and deletes it afterwards.
+async fn redis_test_connection(test_name: &str) -> Option<(redis::aio::ConnectionManager, String)> {
+    let Ok(url) = std::env::var("REDIS_TEST_URL") else {
+        eprintln!("REDIS_TEST_URL is not set, skipping {test_name}");
+        return None;
+    };
+    let client = redis::Client::open(url).unwrap();
+    let mut connection = redis::aio::ConnectionManager::new(client).await.unwrap();
+    let prefix = format!("potlock-test:{test_name}:{}:", std::process::id());
+    delete_redis_keys(&mut connection, &prefix).await;
+    Some((connection, prefix))
+}
+
+async fn delete_redis_keys(connection: &mut redis::aio::ConnectionManager, prefix: &str) {
+    use redis::AsyncCommands;
+    let keys: Vec<String> = connection.keys(format!("{prefix}*")).await.unwrap();
+    if !keys.is_empty() {
+        let _: () = connection.del(keys).await.unwrap();
+    }
+}
+
+fn redis_test_donation(
+    donation_id: u64,
+    block_height: BlockHeight,
+    timestamp_ms: u64,
+) -> (DonationEvent, EventContext) {
+    let (event, context) = test_donation(donation_id, block_height);
+    (
+        DonationEvent {
+            donated_at: timestamp_ms,
+            ..event
+        },
+        EventContext {
+            block_timestamp_nanosec: timestamp_ms as u128 * 1_000_000,
+            ..context
+        },
+    )
+}
+
+#[tokio::test]
+async fn redis_writes_each_block_once() {
+    use crate::redis_handler::{PushToRedisStream, RedisStreamsConfig, Retention};
+    use redis::AsyncCommands;
+
+    let Some((mut connection, prefix)) = redis_test_connection("write_once").await else {
+        return;
+    };
+    let config = RedisStreamsConfig {
+        prefix: prefix.clone(),
+        donation_retention: Retention::Unlimited,
+        secondary_indexes: true,
+        ..Default::default()
+    };
+    let write = |blocks: Vec<(BlockHeight, Vec<u64>)>| {
+        let (connection, config) = (connection.clone(), config.clone());
+        async move {
+            // A new handler each time, like after a restart
+            let mut redis = PushToRedisStream::new(connection, config).await.unwrap();
+            for (block_height, donation_ids) in blocks {
+                for donation_id in donation_ids {
+                    let (event, context) =
+                        redis_test_donation(donation_id, block_height, 1_715_000_000_000);
+                    redis.handle_donation(event, context).await;
+                }
+                redis.flush_events(block_height).await.unwrap();
+            }
+            redis.stream_keys()[0].to_owned()
+        }
+    };
+
+    let stream = write(vec![(100, vec![1, 2])]).await;
+    // Written again, and an older block
+    write(vec![(99, vec![3]), (100, vec![1, 2])]).await;
+
+    let entries: redis::streams::StreamRangeReply = connection.xrange_all(&stream).await.unwrap();
+    assert_eq!(
+        entries
+            .ids
+            .iter()
+            .map(|entry| entry.id.as_str())
+            .collect::<Vec<_>>(),
+        ["100-0", "100-1"]
+    );
+    let checkpoint: BlockHeight = connection
+        .get(format!("{prefix}potlock_last_processed_block"))
+        .await
+        .unwrap();
+    assert_eq!(checkpoint, 100);
+    let total: String = connection
+        .hget(
+            format!("{prefix}potlock_totals:project:yearofchef.near"),
+            "near",
+        )
+        .await
+        .unwrap();
+    assert_eq!(total, "2000");
+    let donor_entries: usize = connection
+        .zcard(format!("{prefix}potlock_index:donor:slimedragon.near"))
+        .await
+        .unwrap();
+    assert_eq!(donor_entries, 2);
+
+    delete_redis_keys(&mut connection, &prefix).await;
+}
+
+#[tokio::test]
+async fn redis_trims_streams_by_age() {
+    use crate::redis_handler::{PushToRedisStream, RedisStreamsConfig, Retention};
+    use redis::AsyncCommands;
+
+    let Some((mut connection, prefix)) = redis_test_connection("trim_by_age").await else {
+        return;
+    };
+    let mut redis = PushToRedisStream::new(
+        connection.clone(),
+        RedisStreamsConfig {
+            prefix: prefix.clone(),
+            donation_retention: Retention::MaxAge(std::time::Duration::from_secs(60 * 60)),
+            ..Default::default()
+        },
+    )
     .await
     .unwrap();
+    let hour_ms = 60 * 60 * 1000;
+    for (donation_id, block_height, timestamp_ms) in [
+        (1, 1, 1_715_000_000_000),
+        (2, 2, 1_715_000_000_000 + hour_ms / 2),
+        (3, 3, 1_715_000_000_000 + 2 * hour_ms),
+    ] {
+        let (event, context) = redis_test_donation(donation_id, block_height, timestamp_ms);
+        redis.handle_donation(event, context).await;
+        redis.flush_events(block_height).await.unwrap();
+    }
 
+    let entries: redis::streams::StreamRangeReply =
+        connection.xrange_all(redis.stream_keys()[0]).await.unwrap();
     assert_eq!(
-        *indexer
-            .0
-            .events
-            .get(&"xslymn.near".parse::<AccountId>().unwrap())
-            .unwrap(),
-        vec![(
-            DonationEvent {
-                donation_id: 2211,
-                donor_id: "xslymn.near".parse().unwrap(),
-                total_amount: 500000000000000000000000,
-                ft_id: "near".parse().unwrap(),
-                message: None,
-                donated_at: 1714665141842,
-                project_id: "indexers.intear.near".parse().unwrap(),
-                protocol_fee: 12500000000000000000000,
-                referrer_id: None,
-                referrer_fee: None
-            },
-            EventContext {
-                transaction_id: "3VRcmqbc73KKNhPHaSWyaYyNz57c2QjDVTkTDRTXR6L7"
-                    .parse()
-                    .unwrap(),
-                receipt_id: "DcTBNA52876rEw73fFaxZqWzkagatQj8MpWn2cQZU33X"
-                    .parse()
-                    .unwrap(),
-                block_height: 118100099,
-                block_timestamp_nanosec: 1714665143709556816,
-            }
-        )]
+        entries
+            .ids
+            .iter()
+            .map(|entry| entry.id.as_str())
+            .collect::<Vec<_>>(),
+        ["3-0"]
+    );
+
+    delete_redis_keys(&mut connection, &prefix).await;
+}
+
+#[tokio::test]
+async fn redis_publishes_only_added_events() {
+    use crate::redis_handler::{PushToRedisStream, RedisStreamsConfig};
+    use futures::StreamExt;
+
+    let Some((mut connection, prefix)) = redis_test_connection("publish").await else {
+        return;
+    };
+    let client = redis::Client::open(std::env::var("REDIS_TEST_URL").unwrap()).unwrap();
+    let mut pubsub = client.get_async_pubsub().await.unwrap();
+    pubsub
+        .subscribe(format!("{prefix}potlock_events"))
+        .await
+        .unwrap();
+    let config = RedisStreamsConfig {
+        prefix: prefix.clone(),
+        publish: true,
+        ..Default::default()
+    };
+    for donation_ids in [vec![1], vec![1, 2]] {
+        let mut redis = PushToRedisStream::new(connection.clone(), config.clone())
+            .await
+            .unwrap();
+        for donation_id in donation_ids {
+            let (event, context) = redis_test_donation(donation_id, 100, 1_715_000_000_000);
+            redis.handle_donation(event, context).await;
+        }
+        redis.flush_events(100).await.unwrap();
+    }
+
+    let mut messages = pubsub.on_message();
+    let mut donation_ids = Vec::new();
+    while let Ok(Some(message)) =
+        tokio::time::timeout(std::time::Duration::from_millis(500), messages.next()).await
+    {
+        let message: serde_json::Value =
+            serde_json::from_str(&message.get_payload::<String>().unwrap()).unwrap();
+        donation_ids.push(message["event"]["donation_id"].as_u64().unwrap());
+    }
+    // Donation 1 was skipped the second time, donation 2 is new
+    assert_eq!(donation_ids, [1, 2]);
+
+    delete_redis_keys(&mut connection, &prefix).await;
+}
+
+#[tokio::test]
+async fn backfill_resumes_after_last_flushed_block() {
+    use crate::backfill::{backfill, BackfillOptions};
+    let directory =
+        std::env::temp_dir().join(format!("potlock-backfill-resume-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&directory);
+    std::fs::create_dir_all(&directory).unwrap();
+    // Interrupted after flushing block 111, in the middle of chunk 110..120
+    std::fs::write(directory.join("progress.json"), r#"{"merged_up_to": 112}"#).unwrap();
+    let mut sink = RecordingHandler::new();
+    backfill(
+        &mut sink,
+        BackfillOptions {
+            range: 100..130,
+            chunk_size: 10,
+            concurrency: 2,
+            checkpoint_directory: directory.clone(),
+        },
+        |chunk| async move {
+            assert_ne!(chunk, 100..110, "Merged chunk indexed again");
+            Ok([1, 5]
+                .into_iter()
+                .map(|offset| {
+                    let block_height = chunk.start + offset;
+                    let (event, context) = test_donation(block_height, block_height);
+                    (PotlockEvent::Donation(event), context)
+                })
+                .collect())
+        },
+    )
+    .await
+    .unwrap();
+    sink.assert_flushed_in_order();
+    assert_eq!(sink.flushed_blocks(), [115, 119, 121, 125, 129]);
+    assert_eq!(
+        sink.donations()
+            .iter()
+            .map(|(event, _)| event.donation_id)
+            .collect::<Vec<_>>(),
+        [115, 121, 125]
     );
+    std::fs::remove_dir_all(directory).unwrap();
 }